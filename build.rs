@@ -0,0 +1,87 @@
+//! Checks that the built-in templates don't reference external origins, so
+//! the default dashboard works without internet access and with its strict
+//! Content-Security-Policy.
+
+use std::{fs, path::Path};
+
+const TEMPLATES_DIR: &str = "src/templates";
+
+/// files that are not embedded or not text
+const SKIPPED_SUFFIXES: &[&str] = &[".rs", ".png", ".ico"];
+
+/// URLs that identify things instead of locating them, like XML namespaces
+const ALLOWED_PREFIXES: &[&str] = &["http://www.w3.org/"];
+
+/// (start, end) of the comments stripped before checking
+const COMMENTS: &[(&str, &str)] = &[("<!--", "-->"), ("{{!--", "--}}"), ("{{!", "}}"), ("/*", "*/")];
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", TEMPLATES_DIR);
+    let mut errors = Vec::new();
+    check_dir(Path::new(TEMPLATES_DIR), &mut errors);
+    if !errors.is_empty() {
+        for e in errors {
+            eprintln!("{}", e);
+        }
+        panic!("built-in templates must not reference external origins");
+    }
+}
+
+fn check_dir(dir: &Path, errors: &mut Vec<String>) {
+    let entries = fs::read_dir(dir).unwrap_or_else(|e| panic!("cannot read '{}': {}", dir.display(), e));
+    for entry in entries {
+        let path = entry.expect("cannot read directory entry").path();
+        let name = path.to_string_lossy().to_string();
+        if path.is_dir() {
+            check_dir(&path, errors);
+        } else if !SKIPPED_SUFFIXES.iter().any(|s| name.ends_with(s)) {
+            let content = fs::read_to_string(&path).unwrap_or_else(|e| panic!("cannot read '{}': {}", name, e));
+            for reference in external_references(&strip_comments(&content)) {
+                errors.push(format!("{}: references external origin '{}'", name, reference));
+            }
+        }
+    }
+}
+
+fn strip_comments(content: &str) -> String {
+    let mut result = String::with_capacity(content.len());
+    let mut rest = content;
+    loop {
+        let next = COMMENTS.iter()
+            .filter_map(|(start, end)| rest.find(start).map(|i| (i, *start, *end)))
+            .min_by_key(|(i, _, _)| *i);
+        match next {
+            Some((i, start, end)) => {
+                result.push_str(&rest[..i]);
+                let after = &rest[i + start.len()..];
+                rest = after.find(end).map_or("", |j| &after[j + end.len()..]);
+            }
+            None => {
+                result.push_str(rest);
+                return result
+            }
+        }
+    }
+}
+
+/// absolute URLs, and protocol-relative ones after a quote, `(` or `=`
+fn external_references(content: &str) -> Vec<String> {
+    let mut references = Vec::new();
+    for (i, _) in content.match_indices("//") {
+        let before = &content[..i];
+        let is_absolute = before.ends_with("http:") || before.ends_with("https:");
+        let is_relative = before.ends_with(['"', '\'', '(', '=']);
+        if !is_absolute && !is_relative {
+            continue
+        }
+        let start = if is_absolute { before.rfind("http").unwrap_or(i) } else { i };
+        let reference = content[start..]
+            .split(|c: char| c.is_whitespace() || matches!(c, '"' | '\'' | ')' | '>'))
+            .next()
+            .unwrap_or_default();
+        if !ALLOWED_PREFIXES.iter().any(|p| reference.starts_with(p)) {
+            references.push(reference.to_string());
+        }
+    }
+    references
+}
@@ -0,0 +1,420 @@
+//! Announcements shown on top of the dashboard, like maintenance notices.
+//! Like sites, they come from the configuration file or are managed at
+//! runtime by admins, and are targeted at users by claim rules.
+
+use std::{path::PathBuf, sync::{Arc, Mutex, RwLock}};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use pulldown_cmark::{html, CowStr, Event, Parser, Tag};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::auth::Claims;
+use crate::site::{self, ClaimRule, Site};
+
+/// URL schemes links and images in announcements may use. Relative URLs
+/// are allowed as well, except protocol-relative ones like `//evil.example`,
+/// which browsers resolve against another host.
+const URL_SCHEMES: &[&str] = &["http", "https", "mailto"];
+
+#[derive(Serialize, Deserialize, JsonSchema, juniper::GraphQLEnum, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[default]
+    Info,
+    Warning,
+    Critical,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct Announcement {
+    /// unique identifier, used to remember which announcements a user dismissed
+    id: String,
+    #[serde(default)]
+    severity: Severity,
+    title: Option<String>,
+    /// the message, in markdown
+    body: String,
+    /// time from which the announcement is shown, like `2021-06-05T08:00:00+02:00`.
+    /// Shown right away if not set
+    #[serde(default, with = "crate::rfc3339")]
+    #[schemars(with = "Option<String>")]
+    starts_at: Option<DateTime<FixedOffset>>,
+    /// time until which the announcement is shown. Shown until removed if not set
+    #[serde(default, with = "crate::rfc3339")]
+    #[schemars(with = "Option<String>")]
+    ends_at: Option<DateTime<FixedOffset>>,
+    /// the announcement is shown if any of these rules match the user's
+    /// access token. Shown to all users if not set
+    claim_rules: Option<Vec<ClaimRule>>,
+    /// names of the sites the announcement is about. If set, it is only shown
+    /// to users who can see at least one of them
+    #[serde(default)]
+    sites: Vec<String>,
+}
+
+impl Announcement {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn severity(&self) -> Severity {
+        self.severity
+    }
+
+    pub fn title(&self) -> Option<&str> {
+        self.title.as_deref()
+    }
+
+    pub fn body(&self) -> &str {
+        &self.body
+    }
+
+    pub fn starts_at(&self) -> Option<&DateTime<FixedOffset>> {
+        self.starts_at.as_ref()
+    }
+
+    pub fn ends_at(&self) -> Option<&DateTime<FixedOffset>> {
+        self.ends_at.as_ref()
+    }
+
+    pub fn claim_rules(&self) -> Option<&[ClaimRule]> {
+        self.claim_rules.as_deref()
+    }
+
+    pub fn sites(&self) -> &[String] {
+        &self.sites
+    }
+
+    /// the body rendered as HTML. Raw HTML in the markdown is escaped, and
+    /// links with schemes other than http, https and mailto are removed.
+    pub fn body_html(&self) -> String {
+        markdown_to_html(&self.body)
+    }
+
+    /// the absolute URLs of the images in the body
+    pub fn image_urls(&self) -> Vec<String> {
+        markdown_image_urls(&self.body)
+    }
+
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        self.starts_at.is_none_or(|t| t <= now) && self.ends_at.is_none_or(|t| now < t)
+    }
+
+    /// Returns true if the announcement is shown to a user with the given
+    /// claims who can see `visible_sites`. As with sites, everything is shown
+    /// if auth is disabled, and nothing to anonymous users otherwise.
+    pub fn is_visible(&self, claims_opt: Option<&Claims>, auth_enabled: bool, visible_sites: &[&Site]) -> bool {
+        let rules_match = match (&self.claim_rules, claims_opt) {
+            (None, _) => claims_opt.is_some() || !auth_enabled,
+            (Some(rules), Some(claims)) => site::any_rule_matches(rules, claims),
+            (Some(_), None) => !auth_enabled,
+        };
+        rules_match && (self.sites.is_empty() || visible_sites.iter().any(|s| self.sites.iter().any(|n| n == s.name())))
+    }
+
+    /// Returns a description of every problem with this announcement
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.id.trim().is_empty() {
+            errors.push("id must not be empty".to_string());
+        }
+        if self.body.trim().is_empty() {
+            errors.push("body must not be empty".to_string());
+        }
+        if let (Some(start), Some(end)) = (self.starts_at, self.ends_at) {
+            if end <= start {
+                errors.push("ends_at must be after starts_at".to_string());
+            }
+        }
+        for (i, r) in self.claim_rules.iter().flatten().enumerate() {
+            if let Some(e) = r.validate() {
+                errors.push(format!("claim rule #{} {}", i + 1, e));
+            }
+        }
+        errors
+    }
+}
+
+/// An announcement as shown to a user, with the body rendered as HTML
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "Announcement")]
+pub struct AnnouncementView<'a> {
+    id: &'a str,
+    severity: Severity,
+    title: Option<&'a str>,
+    /// the message, in markdown
+    body: &'a str,
+    /// the message rendered as HTML
+    body_html: String,
+    /// in RFC 3339 format
+    starts_at: Option<String>,
+    /// in RFC 3339 format
+    ends_at: Option<String>,
+    /// names of the sites the announcement is about
+    sites: &'a [String],
+    /// true if the user can dismiss the announcement, which requires a user
+    dismissible: bool,
+}
+
+impl<'a> AnnouncementView<'a> {
+    pub fn new(announcement: &'a Announcement, dismissible: bool) -> Self {
+        AnnouncementView {
+            id: announcement.id(),
+            severity: announcement.severity(),
+            title: announcement.title(),
+            body: announcement.body(),
+            body_html: announcement.body_html(),
+            starts_at: announcement.starts_at().map(DateTime::to_rfc3339),
+            ends_at: announcement.ends_at().map(DateTime::to_rfc3339),
+            sites: announcement.sites(),
+            dismissible,
+        }
+    }
+}
+
+/// the absolute URLs of the images in the markdown, as far as they are
+/// rendered by [`markdown_to_html`]
+fn markdown_image_urls(markdown: &str) -> Vec<String> {
+    Parser::new(markdown)
+        .filter_map(|event| match event {
+            Event::Start(Tag::Image(_, url, _)) => Some(safe_url(url).to_string()),
+            _ => None,
+        })
+        .filter(|url| url::Url::parse(url).is_ok())
+        .collect()
+}
+
+/// renders untrusted markdown as HTML that is safe to embed in the dashboard
+pub fn markdown_to_html(markdown: &str) -> String {
+    let events = Parser::new(markdown).map(|event| match event {
+        Event::Html(raw) => Event::Text(raw),
+        Event::Start(Tag::Link(kind, url, title)) => Event::Start(Tag::Link(kind, safe_url(url), title)),
+        Event::Start(Tag::Image(kind, url, title)) => Event::Start(Tag::Image(kind, safe_url(url), title)),
+        e => e,
+    });
+    let mut result = String::new();
+    html::push_html(&mut result, events);
+    result
+}
+
+fn safe_url(url: CowStr) -> CowStr {
+    match url::Url::parse(&url) {
+        Ok(u) if URL_SCHEMES.contains(&u.scheme()) => url,
+        Err(url::ParseError::RelativeUrlWithoutBase) if !is_protocol_relative(&url) => url,
+        _ => CowStr::Borrowed(""),
+    }
+}
+
+/// true for URLs starting with two slashes, where browsers accept
+/// backslashes as slashes and skip leading whitespace
+fn is_protocol_relative(url: &str) -> bool {
+    let is_slash = |c: char| c == '/' || c == '\\';
+    let mut chars = url.trim_start_matches(|c: char| c.is_ascii_whitespace() || c.is_ascii_control()).chars();
+    chars.next().is_some_and(is_slash) && chars.next().is_some_and(is_slash)
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct AnnouncementList {
+    announcements: Vec<Announcement>,
+}
+
+/// Holds the announcements: the static ones from the configuration file,
+/// followed by the ones managed at runtime by admins.
+///
+/// Managed announcements are persisted to a JSON file, if one is
+/// configured, so they survive restarts. Static announcements are read-only.
+pub struct AnnouncementStore {
+    static_announcements: Vec<Announcement>,
+    store_path: Option<PathBuf>,
+    /// held while a change is validated and persisted, see
+    /// [`crate::site_store::SiteStore`]
+    writer: Mutex<()>,
+    managed: RwLock<Vec<Announcement>>,
+}
+
+impl AnnouncementStore {
+    /// Creates the store, loading previously managed announcements from
+    /// `store_path` if that file exists
+    pub fn open(static_announcements: Vec<Announcement>, store_path: Option<PathBuf>) -> Result<AnnouncementStore, String> {
+        let managed = match &store_path {
+            Some(p) if p.exists() => {
+                let content = std::fs::read_to_string(p)
+                    .map_err(|e| format!("cannot read announcement store {}: {}", p.to_string_lossy(), e))?;
+                let list: AnnouncementList = serde_json::from_str(&content)
+                    .map_err(|e| format!("cannot parse announcement store {}: {}", p.to_string_lossy(), e))?;
+                list.announcements
+            },
+            _ => Vec::new()
+        };
+        let store = AnnouncementStore {
+            static_announcements,
+            store_path,
+            writer: Mutex::new(()),
+            managed: RwLock::new(Vec::new()),
+        };
+        let mut loaded = Vec::with_capacity(managed.len());
+        for a in managed {
+            store.validate(&loaded, &a, None)?;
+            loaded.push(a);
+        }
+        *store.managed.write().unwrap() = loaded;
+        Ok(store)
+    }
+
+    /// returns all announcements, static ones first
+    pub fn announcements(&self) -> Arc<Vec<Announcement>> {
+        let all = self.static_announcements.iter()
+            .chain(self.managed.read().unwrap().iter())
+            .cloned()
+            .collect();
+        Arc::new(all)
+    }
+
+    pub fn is_managed(&self, id: &str) -> bool {
+        self.managed.read().unwrap()
+            .iter()
+            .any(|a| a.id() == id)
+    }
+
+    pub fn create(&self, announcement: Announcement) -> Result<Announcement, String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed.read().unwrap().clone();
+        self.validate(&managed, &announcement, None)?;
+        managed.push(announcement.clone());
+        self.persist_and_replace(managed)?;
+        Ok(announcement)
+    }
+
+    pub fn update(&self, id: &str, announcement: Announcement) -> Result<Announcement, String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed.read().unwrap().clone();
+        let index = self.managed_index(&managed, id)?;
+        self.validate(&managed, &announcement, Some(id))?;
+        managed[index] = announcement.clone();
+        self.persist_and_replace(managed)?;
+        Ok(announcement)
+    }
+
+    pub fn delete(&self, id: &str) -> Result<(), String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed.read().unwrap().clone();
+        let index = self.managed_index(&managed, id)?;
+        managed.remove(index);
+        self.persist_and_replace(managed)
+    }
+
+    fn managed_index(&self, managed: &[Announcement], id: &str) -> Result<usize, String> {
+        match managed.iter().position(|a| a.id() == id) {
+            Some(i) => Ok(i),
+            None if self.static_announcements.iter().any(|a| a.id() == id) =>
+                Err(format!("announcement '{}' is defined in the configuration file and cannot be changed at runtime", id)),
+            None => Err(format!("there is no announcement with id '{}'", id))
+        }
+    }
+
+    /// Applies the same checks as configuration loading, and makes sure
+    /// that ids stay unique. `replaced` is the id of the announcement that
+    /// `announcement` replaces, if any.
+    fn validate(&self, managed: &[Announcement], announcement: &Announcement, replaced: Option<&str>) -> Result<(), String> {
+        let errors = announcement.validate();
+        if !errors.is_empty() {
+            return Err(format!("announcement '{}': {}", announcement.id(), errors.join("; ")))
+        }
+        let id_taken = self.static_announcements.iter()
+            .chain(managed.iter())
+            .any(|a| a.id() == announcement.id() && Some(a.id()) != replaced);
+        if id_taken {
+            return Err(format!("an announcement with id '{}' already exists", announcement.id()))
+        }
+        Ok(())
+    }
+
+    /// Persists the changed announcements and makes them current. The
+    /// caller must hold the writer lock.
+    fn persist_and_replace(&self, announcements: Vec<Announcement>) -> Result<(), String> {
+        if let Some(p) = &self.store_path {
+            crate::store::persist_json(p, &AnnouncementList { announcements: announcements.clone() }, "announcement store")?;
+        }
+        *self.managed.write().unwrap() = announcements;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn announcement(value: serde_json::Value) -> Announcement {
+        let mut a = serde_json::json!({ "id": "a", "body": "maintenance" });
+        a.as_object_mut().unwrap().extend(value.as_object().unwrap().clone());
+        serde_json::from_value(a).unwrap()
+    }
+
+    fn site(name: &str) -> Site {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "url": "https://example.com",
+            "claim_rules": [],
+        })).unwrap()
+    }
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn is_active_between_start_and_end() {
+        let a = announcement(serde_json::json!({
+            "starts_at": "2021-06-05T08:00:00+02:00",
+            "ends_at": "2021-06-05T12:00:00+02:00",
+        }));
+        assert!(!a.is_active(time("2021-06-05T05:59:59Z")));
+        assert!(a.is_active(time("2021-06-05T06:00:00Z")));
+        assert!(!a.is_active(time("2021-06-05T10:00:00Z")));
+        assert!(announcement(serde_json::json!({})).is_active(Utc::now()));
+
+        let reversed = announcement(serde_json::json!({
+            "starts_at": "2021-06-05T12:00:00Z",
+            "ends_at": "2021-06-05T08:00:00Z",
+        }));
+        assert_eq!(reversed.validate(), vec!["ends_at must be after starts_at"]);
+    }
+
+    #[test]
+    fn is_visible_by_claims_and_sites() {
+        let claims = Claims::from(serde_json::json!({ "groups": ["billing"] }));
+        let billing = site("Billing");
+        let wiki = site("Wiki");
+
+        let everybody = announcement(serde_json::json!({}));
+        assert!(everybody.is_visible(Some(&claims), true, &[]));
+        assert!(!everybody.is_visible(None, true, &[]));
+        assert!(everybody.is_visible(None, false, &[]));
+
+        let targeted = announcement(serde_json::json!({
+            "claim_rules": [{ "path": "groups", "operator": "ContainsMatch", "operand": { "value": "admins" } }],
+        }));
+        assert!(!targeted.is_visible(Some(&claims), true, &[]));
+
+        let about_billing = announcement(serde_json::json!({ "sites": ["Billing"] }));
+        assert!(about_billing.is_visible(Some(&claims), true, &[&wiki, &billing]));
+        assert!(!about_billing.is_visible(Some(&claims), true, &[&wiki]));
+    }
+
+    #[test]
+    fn renders_markdown_safely() {
+        assert_eq!(markdown_to_html("**Billing** is down"), "<p><strong>Billing</strong> is down</p>\n");
+        assert_eq!(markdown_to_html("<script>alert(1)</script>"), "&lt;script&gt;alert(1)&lt;/script&gt;");
+        assert_eq!(markdown_to_html("[status](https://status.example)"), "<p><a href=\"https://status.example\">status</a></p>\n");
+        assert_eq!(markdown_to_html("[x](javascript:alert(1))"), "<p><a href=\"\">x</a></p>\n");
+        assert_eq!(markdown_to_html("[x](//evil.example)"), "<p><a href=\"\">x</a></p>\n");
+        assert_eq!(markdown_to_html(r"[x](\\\\evil.example)"), "<p><a href=\"\">x</a></p>\n");
+        assert_eq!(markdown_to_html("[x](/\\evil.example)"), "<p><a href=\"\">x</a></p>\n");
+        assert_eq!(markdown_to_html("[status](/status)"), "<p><a href=\"/status\">status</a></p>\n");
+        assert_eq!(
+            markdown_image_urls("![map](https://status.example/map.png) ![x](javascript:alert(1)) ![local](map.png)"),
+            vec!["https://status.example/map.png"]
+        );
+    }
+}
@@ -1,4 +1,5 @@
 use std::time::Duration;
+use chrono::{DateTime, TimeZone, Utc};
 use actix_web::client::Client;
 use alcoholic_jwt::{token_kid, validate, Validation, JWK, JWKS};
 use serde::{Deserialize, Serialize};
@@ -22,9 +23,39 @@ impl OidcAuth {
     }
 }
 
+/// Claims that authorization servers only put into access tokens if a
+/// certain scope was requested: the standard OpenID Connect scopes, and
+/// Keycloak's `roles` scope.
+const SCOPED_CLAIMS: &[(&str, &[&str])] = &[
+    ("profile", &["name", "family_name", "given_name", "middle_name", "nickname",
+        "preferred_username", "profile", "picture", "website", "gender",
+        "birthdate", "zoneinfo", "locale", "updated_at"]),
+    ("email", &["email", "email_verified"]),
+    ("address", &["address"]),
+    ("phone", &["phone_number", "phone_number_verified"]),
+    ("roles", &["realm_access", "resource_access"]),
+];
+
+/// Returns the scope that must be requested for the claim at the given
+/// path to be present, if it is known to depend on one.
+pub fn scope_for_claim_path(path: &str) -> Option<&'static str> {
+    let claim = path.split('.').next()?;
+    SCOPED_CLAIMS.iter()
+        .find(|(_, claims)| claims.contains(&claim))
+        .map(|(scope, _)| *scope)
+}
+
+#[derive(Clone, Debug)]
 pub struct Claims(serde_json::Value);
 
 impl Claims {
+    /// the expiration time of the token, from its `exp` claim
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.0.get("exp")
+            .and_then(|v| v.as_i64())
+            .and_then(|secs| Utc.timestamp_opt(secs, 0).single())
+    }
+
     pub fn get_path(&self, path: &str) -> Option<&serde_json::Value> {
         let mut it = path.split(".");
         let mut key_opt = it.next();
@@ -45,6 +76,31 @@ impl Claims {
     pub fn value(&self) -> &serde_json::Value {
         &self.0
     }
+
+    /// the subject identifier, which identifies the user
+    pub fn sub(&self) -> Option<&str> {
+        self.0.get("sub").and_then(|v| v.as_str())
+    }
+
+    /// Extracts the claims from a JWT without verifying its signature or
+    /// expiry. Only use this for diagnostics, never for granting access.
+    pub fn decode_unverified(token: &str) -> Result<Claims, crate::Error> {
+        let payload = match token.trim().split('.').collect::<Vec<_>>()[..] {
+            [_header, payload, _signature] => payload,
+            _ => return Err(crate::Error::TokenDecodeFailure("token does not consist of three dot-separated parts".into()))
+        };
+        let json = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|e| crate::Error::TokenDecodeFailure(e.to_string()))?;
+        serde_json::from_slice(&json)
+            .map(Claims)
+            .map_err(|e| crate::Error::TokenDecodeFailure(e.to_string()))
+    }
+}
+
+impl From<serde_json::Value> for Claims {
+    fn from(v: serde_json::Value) -> Self {
+        Claims(v)
+    }
 }
 
 #[derive(Deserialize, Clone, Debug)]
@@ -55,6 +111,7 @@ pub struct OidcConfig {
 }
 
 #[derive(Deserialize, Debug)]
+#[allow(dead_code)]
 pub struct TokenResponse {
     pub access_token: String,
     pub token_type: String,
@@ -102,7 +159,7 @@ impl OidcAuth {
         log::debug!("fetched server public JSON web keys: {:?}", jwks);
 
         jwks.find(kid)
-            .map(|jwk| jwk.clone())
+            .cloned()
             .ok_or_else(|| crate::Error::CannotFindAuthorizationSigningKey(kid.into()).into())
     }
 
@@ -112,7 +169,7 @@ impl OidcAuth {
             Validation::Issuer(self.authority_uri.clone()),
             Validation::SubjectPresent,
         ];
-        let kid = match token_kid(&token) {
+        let kid = match token_kid(token) {
             Ok(res) => res.expect("failed to decode kid"),
             Err(_) => return Err(crate::Error::JWKSFetchError),
         };
@@ -174,21 +231,38 @@ impl OidcAuth {
         };
 
         let resonse_status = response.status().as_u16();
-        return match resonse_status {
+        match resonse_status {
             200 => match response.json::<TokenResponse>().await {
                 Ok(r) => Ok(r),
-                Err(e) => return Err(crate::Error::TokenExchangeFailure(e.to_string())),
+                Err(e) => Err(crate::Error::TokenExchangeFailure(e.to_string())),
             },
             400 => match response.json::<ErrorResponse>().await {
                 Ok(r) => Err(crate::Error::TokenExchangeResponseError(r)),
-                Err(e) => return Err(crate::Error::TokenExchangeFailure(e.to_string())),
+                Err(e) => Err(crate::Error::TokenExchangeFailure(e.to_string())),
             },
             _ => {
-                return Err(crate::Error::TokenExchangeFailure(
+                Err(crate::Error::TokenExchangeFailure(
                     "invalid auth server token endpoint response status code ".to_owned()
                         + &resonse_status.to_string(),
                 ))
             }
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_scope_of_the_top_level_claim() {
+        assert_eq!(scope_for_claim_path("email"), Some("email"));
+        assert_eq!(scope_for_claim_path("preferred_username"), Some("profile"));
+        assert_eq!(scope_for_claim_path("address.country"), Some("address"));
+        assert_eq!(scope_for_claim_path("realm_access.roles"), Some("roles"));
+        assert_eq!(scope_for_claim_path("groups"), None);
+        // only the first path segment names the claim
+        assert_eq!(scope_for_claim_path("groups.email"), None);
+        assert_eq!(scope_for_claim_path(""), None);
     }
 }
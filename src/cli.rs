@@ -1,74 +1,346 @@
-use std::{error::Error, fs::File, net::IpAddr, path::{PathBuf}, str::FromStr};
-use serde::Deserialize;
+use std::{collections::BTreeMap, error::Error, io::Read, net::IpAddr, path::{PathBuf}, str::FromStr};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 
-use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use clap::{App, AppSettings, Arg, ArgGroup, ArgMatches, SubCommand};
 
-use crate::{AppConfig, CommonConfig, InitTemplatesConfig, error::StringError};
+use crate::{AppConfig, CheckConfigConfig, ClaimsSource, CommonConfig, ExplainConfig, InitTemplatesConfig, PrintConfigConfig, ServeConfig, error::StringError};
 
 
 
-pub const CARGO_PKG_VERSION: &'static str = env!("CARGO_PKG_VERSION");
-pub const CARGO_PKG_NAME: &'static str = env!("CARGO_PKG_NAME");
+pub const CARGO_PKG_VERSION: &str = env!("CARGO_PKG_VERSION");
+pub const CARGO_PKG_NAME: &str = env!("CARGO_PKG_NAME");
 
 pub const SERVE_SCMD_NAME: &str = "serve";
 pub const INIT_TEMPLATES_SCMD_NAME: &str = "init-templates";
+pub const CHECK_CONFIG_SCMD_NAME: &str = "check-config";
+pub const EXPLAIN_SCMD_NAME: &str = "explain";
+pub const CONFIG_SCHEMA_SCMD_NAME: &str = "config-schema";
+pub const CONFIG_FILE_ARG_NAME: &str = "CONFIG_FILE";
+pub const TOKEN_ARG_NAME: &str = "token";
+pub const TOKEN_FILE_ARG_NAME: &str = "token-file";
+pub const CLAIMS_FILE_ARG_NAME: &str = "claims-file";
+pub const VERIFY_ARG_NAME: &str = "verify";
 pub const DEVELOPMENT_ARG_NAME: &str = "development";
 pub const NO_AUTH_ARG_NAME: &str = "no-auth";
 pub const TEMPLATE_DIR_ARG_NAME: &str = "template-dir";
+pub const SET_ARG_NAME: &str = "set";
+pub const PRINT_EFFECTIVE_CONFIG_ARG_NAME: &str = "print-effective-config";
+pub const CONFIG_FORMAT_ARG_NAME: &str = "config-format";
+pub const THEME_ARG_NAME: &str = "theme";
 
-#[derive(Deserialize, Debug)]
+/// passing this as CONFIG_FILE reads the configuration from stdin
+pub const STDIN_CONFIG_FILE: &str = "-";
+
+/// prefix of environment variables overriding configuration keys, e.g.
+/// RESWEB_PORT overrides `port`
+pub const ENV_VAR_PREFIX: &str = "RESWEB_";
+
+/// prefix of environment variables setting deployment variables, e.g.
+/// RESWEB_VAR_ENVIRONMENT sets `variables.environment`
+pub const ENV_VAR_VARIABLE_PREFIX: &str = "RESWEB_VAR_";
+
+/// prefix of keys setting deployment variables with --set
+const VARIABLES_KEY_PREFIX: &str = "variables.";
+
+/// configuration keys that can be overridden by environment variables
+/// and the --set switch
+const OVERRIDABLE_KEYS: &[&str] = &[
+    "port",
+    "authorization_server_url",
+    "client_id",
+    "client_secret",
+    "scope",
+    "session_key",
+    "logging",
+    "development",
+    "disable_auth",
+    "site_store",
+    "user_store",
+    "announcement_store",
+    "default_locale",
+    "content_security_policy",
+    "theme",
+];
+
+const REDACTED: &str = "<redacted>";
+
+/// locale of the dashboard if neither the user nor the configuration choose one
+const DEFAULT_LOCALE: &str = "en";
+
+/// Supported configuration file formats. All of them describe the same
+/// structure, only the syntax differs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Yaml,
+    Json,
+    Toml,
+}
+
+impl ConfigFormat {
+    const NAMES: &'static [&'static str] = &["yaml", "json", "toml"];
+
+    /// determines the format from the file name's extension
+    fn from_path(path: &str) -> Option<ConfigFormat> {
+        let (_, ext) = path.rsplit_once('.')?;
+        match ext.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Some(ConfigFormat::Yaml),
+            "json" => Some(ConfigFormat::Json),
+            "toml" => Some(ConfigFormat::Toml),
+            _ => None
+        }
+    }
+
+    fn parse<T: DeserializeOwned>(&self, content: &str) -> Result<T, Box<dyn Error>> {
+        Ok(match self {
+            ConfigFormat::Yaml => serde_yaml::from_str(content)?,
+            ConfigFormat::Json => serde_json::from_str(content)?,
+            ConfigFormat::Toml => toml::from_str(content)?,
+        })
+    }
+}
+
+impl FromStr for ConfigFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+            "json" => Ok(ConfigFormat::Json),
+            "toml" => Ok(ConfigFormat::Toml),
+            _ => Err(format!("unknown configuration format '{}', supported formats are: {}", s, ConfigFormat::NAMES.join(", ")))
+        }
+    }
+}
+
+/// Configuration of the serve subcommand
+#[derive(Serialize, Deserialize, JsonSchema, Debug)]
+#[schemars(title = "resweb serve configuration")]
 struct ServeConfigContent {
+    /// port on which resweb listens for incoming connections
     port: u16,
+    /// IP addresses of the interfaces to listen on. If omitted, resweb listens on all interfaces
     interface_addresses: Option<Vec<String>>,
+    /// base URI of the OpenID Connect authorization server (IDP)
     authorization_server_url: Option<String>,
+    /// client ID of resweb at the authorization server
     client_id: Option<String>,
+    /// client secret, if resweb is a confidential client
+    client_secret: Option<String>,
+    /// OpenID scopes to request, defaults to 'openid'
     scope: Option<String>,
+    /// secret for encrypting session cookies, at least 32 bytes long
+    session_key: Option<String>,
+    /// log filter in env_logger syntax, like 'info' or 'resweb=debug'
+    logging: Option<String>,
     site_list: crate::site::SiteList,
+    /// users matching any of these rules may manage sites through the GraphQL API
+    #[serde(default)]
+    admin_claim_rules: Vec<crate::site::ClaimRule>,
+    /// every site is hidden from users matching any of these rules,
+    /// whatever the site's own rules say
+    #[serde(default)]
+    deny_claim_rules: Vec<crate::site::ClaimRule>,
+    /// shows sites that have no claim rules to all users. By default, such sites are hidden
+    #[serde(default)]
+    default_visible: bool,
+    /// JSON file in which sites managed through the GraphQL API are stored
+    site_store: Option<String>,
+    /// JSON file in which data of individual users, like their site preferences, is stored
+    user_store: Option<String>,
+    /// deployment variables, like `environment: staging`, that sites can be
+    /// conditioned on. Can be set with RESWEB_VAR_<NAME> environment variables
+    #[serde(default)]
+    variables: crate::site::Variables,
+    /// messages shown on top of the dashboard, like maintenance notices
+    #[serde(default)]
+    announcements: Vec<crate::announcements::Announcement>,
+    /// JSON file in which announcements managed through the GraphQL API are stored
+    announcement_store: Option<String>,
+    /// locale of the dashboard for users whose locale cannot be determined,
+    /// defaults to 'en'
+    default_locale: Option<String>,
+    /// Content-Security-Policy header of the dashboard's pages. By default, a
+    /// policy allowing the built-in templates' own scripts and styles and the
+    /// sites' icons is sent. An empty value sends no header at all
+    content_security_policy: Option<String>,
+    /// built-in look of the dashboard: 'tiles' (the default), 'list' or
+    /// 'dark'. Files in the template directory replace the theme's files
+    #[serde(default)]
+    theme: crate::templates::Theme,
+    /// limits and developer tools of the GraphQL API
+    #[serde(default)]
+    graphql: crate::graphql_limits::GraphQLConfig,
+    /// enables auto-reloading of templates
     #[serde(default)]
     development: bool,
+    /// disables authentication, all sites are displayed
     #[serde(default)]
     disable_auth: bool,
 }
 
 impl ServeConfigContent {
-    fn into_config(&self, force_disable_auth: bool) -> Result<crate::ServeConfig,String> {
+    /// Overrides a single scalar configuration value given as string
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        let parse_bool = |v: &str| match v.to_ascii_lowercase().as_str() {
+            "true" | "1" | "yes" => Ok(true),
+            "false" | "0" | "no" => Ok(false),
+            _ => Err(format!("invalid value '{}' for '{}', expected 'true' or 'false'", v, key))
+        };
+        match key {
+            "port" => self.port = value.parse()
+                .map_err(|e| format!("invalid value '{}' for '{}': {}", value, key, e))?,
+            "authorization_server_url" => self.authorization_server_url = Some(value.into()),
+            "client_id" => self.client_id = Some(value.into()),
+            "client_secret" => self.client_secret = Some(value.into()),
+            "scope" => self.scope = Some(value.into()),
+            "session_key" => self.session_key = Some(value.into()),
+            "logging" => self.logging = Some(value.into()),
+            "development" => self.development = parse_bool(value)?,
+            "disable_auth" => self.disable_auth = parse_bool(value)?,
+            "site_store" => self.site_store = Some(value.into()),
+            "user_store" => self.user_store = Some(value.into()),
+            "announcement_store" => self.announcement_store = Some(value.into()),
+            "default_locale" => self.default_locale = Some(value.into()),
+            "content_security_policy" => self.content_security_policy = Some(value.into()),
+            "theme" => self.theme = value.parse()?,
+            _ => match key.strip_prefix(VARIABLES_KEY_PREFIX) {
+                Some(name) if !name.is_empty() => {
+                    self.variables.insert(name.into(), value.into());
+                },
+                _ => return Err(format!("unknown configuration key '{}', keys that can be set are: {}, {}<name>", key, OVERRIDABLE_KEYS.join(", "), VARIABLES_KEY_PREFIX))
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies overrides on top of the values read from the configuration
+    /// file. The environment variables in `env` are applied first, then the
+    /// `key=value` pairs given with --set, so --set wins over the environment.
+    fn apply_overrides(&mut self, set_args: &[&str], env: impl IntoIterator<Item=(String, String)>) -> Result<(), String> {
+        let env = env.into_iter().collect::<BTreeMap<_, _>>();
+        for key in OVERRIDABLE_KEYS {
+            let var_name = String::from(ENV_VAR_PREFIX) + &key.to_ascii_uppercase();
+            if let Some(value) = env.get(&var_name) {
+                self.set(key, value)
+                    .map_err(|e| format!("environment variable {}: {}", var_name, e))?;
+            }
+        }
+        for (var_name, value) in &env {
+            if let Some(name) = var_name.strip_prefix(ENV_VAR_VARIABLE_PREFIX) {
+                self.set(&(String::from(VARIABLES_KEY_PREFIX) + &name.to_ascii_lowercase()), value)
+                    .map_err(|e| format!("environment variable {}: {}", var_name, e))?;
+            }
+        }
+        for arg in set_args {
+            match arg.split_once('=') {
+                Some((key, value)) => self.set(key.trim(), value)
+                    .map_err(|e| format!("--{} {}: {}", SET_ARG_NAME, arg, e))?,
+                None => return Err(format!("--{} {}: expected key=value", SET_ARG_NAME, arg))
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the configuration as YAML, with secrets replaced
+    fn to_redacted_yaml(&self) -> Result<String, serde_yaml::Error> {
+        let mut value = serde_yaml::to_value(self)?;
+        if let Some(m) = value.as_mapping_mut() {
+            for key in ["client_secret", "session_key"] {
+                if let Some(v) = m.get_mut(&serde_yaml::Value::from(key)) {
+                    if !v.is_null() {
+                        *v = REDACTED.into();
+                    }
+                }
+            }
+        }
+        serde_yaml::to_string(&value)
+    }
+
+    /// Checks the configuration content without touching the network and
+    /// returns all problems found, so they can be reported in one go.
+    fn validate(&self, force_disable_auth: bool) -> Vec<String> {
+        let mut errors = Vec::new();
+
+        if !(self.disable_auth || force_disable_auth) {
+            match (&self.authorization_server_url, &self.client_id) {
+                (Some(u), Some(_)) => if let Err(e) = url::Url::parse(u) {
+                    errors.push(format!("cannot parse authorization_server_url '{}': {}", u, e))
+                },
+                _ => errors.push("'authorization_server_url' and 'client_id' must be configured if 'disable_auth' is not set to 'true'".into())
+            }
+        }
+
+        if let Some(k) = &self.session_key {
+            if k.len() < crate::SESSION_KEY_MIN_LEN {
+                errors.push(format!("'session_key' must be at least {} bytes long", crate::SESSION_KEY_MIN_LEN))
+            }
+        }
+
+        for addr_s in self.interface_addresses.iter().flatten() {
+            if let Err(e) = IpAddr::from_str(addr_s) {
+                errors.push(format!("cannot parse interface_addresses: '{}': {}", addr_s, e))
+            }
+        }
+
+        for (i, r) in self.admin_claim_rules.iter().enumerate() {
+            if let Some(e) = r.validate() {
+                errors.push(format!("admin claim rule #{} {}", i + 1, e))
+            }
+        }
+
+        for (i, r) in self.deny_claim_rules.iter().enumerate() {
+            if let Some(e) = r.validate() {
+                errors.push(format!("deny claim rule #{} {}", i + 1, e))
+            }
+        }
+
+        if let Some(l) = self.default_locale.as_deref().filter(|l| !crate::i18n::is_valid_locale(l)) {
+            errors.push(format!("default_locale '{}' is not a valid language tag like 'de' or 'de-CH'", l))
+        }
+
+        errors.extend(self.graphql.validate());
+
+        errors.extend(self.site_list.validate());
+
+        for (i, a) in self.announcements.iter().enumerate() {
+            errors.extend(a.validate()
+                .into_iter()
+                .map(|e| format!("announcement '{}': {}", a.id(), e)));
+            if self.announcements[..i].iter().any(|other| other.id() == a.id()) {
+                errors.push(format!("announcement id '{}' is used more than once", a.id()))
+            }
+        }
+
+        errors
+    }
+
+    fn into_config(self, force_disable_auth: bool) -> Result<crate::ServeConfig,String> {
+
+        let errors = self.validate(force_disable_auth);
+        if !errors.is_empty() {
+            return Err(errors.join("; "))
+        }
 
         let auth = if self.disable_auth || force_disable_auth {
             None
         } else {
-
-            if self.authorization_server_url.is_none() || self.client_id.is_none() {
-                return Err("'authorization_server_url' and 'client_id' must be configured if 'disable_auth' is not set to 'true'".into());
-            }
-            
-            let authorization_server_url = match url::Url::parse(self.authorization_server_url.as_ref().unwrap()) {
-                Ok(u) => u,
-                Err(e) => return Err(e.to_string())
-            };
-    
+            // both values were checked by validate()
             Some(crate::ServeAuthConfig{
-                authorization_server_url,
-                client_id: self.client_id.as_ref().unwrap().clone()
+                authorization_server_url: url::Url::parse(self.authorization_server_url.as_ref().unwrap()).unwrap(),
+                client_id: self.client_id.as_ref().unwrap().clone(),
+                client_secret: self.client_secret.clone(),
             })
         };
 
         // parse interface addresses - if none are given, attempt to determine
         // all existing interfaces and use all of them for binding
-        let interface_addresses = if let Some(a) = &self.interface_addresses {
-            a.clone()
-        } else {
-            Vec::new()
-        };
-        let interface_addresses = if !interface_addresses.is_empty() {
-            let converted_ifs = interface_addresses.iter()
-            .map(|addr_s|IpAddr::from_str(addr_s))
+        let interface_addresses = self.interface_addresses.iter()
+            .flatten()
+            .map(|addr_s|IpAddr::from_str(addr_s).unwrap())
             .collect::<Vec<_>>();
-            if let Some(err) = converted_ifs.iter().find_map(|r|r.as_ref().err()) {
-                return Err(String::from("cannot parse interface_addresses: ") + &err.to_string())
-            }
-            converted_ifs.iter()
-            .map(|r|r.as_ref().unwrap().clone())
-            .collect::<Vec<_>>()
+        let interface_addresses = if !interface_addresses.is_empty() {
+            interface_addresses
         } else {
             match get_if_addrs::get_if_addrs() {
                 Ok(addrs) => addrs.iter().map(|i|i.ip()).collect(),
@@ -76,14 +348,33 @@ impl ServeConfigContent {
             }
         };
 
+        // the references were checked by validate()
+        let mut site_list = self.site_list.clone();
+        site_list.resolve_rule_sets()?;
         
         Ok(crate::ServeConfig{
-            common: CommonConfig::default(),
+            common: CommonConfig {
+                logging: self.logging.clone(),
+                ..CommonConfig::default()
+            },
             port: self.port,
             interface_addresses,
             scope: self.scope.clone().unwrap_or("openid".into()),
+            session_key: self.session_key.clone(),
             auth,
-            site_list: self.site_list.clone(),
+            site_list,
+            admin_claim_rules: self.admin_claim_rules.clone(),
+            deny_claim_rules: self.deny_claim_rules.clone(),
+            default_visible: self.default_visible,
+            site_store: self.site_store.as_ref().map(PathBuf::from),
+            user_store: self.user_store.as_ref().map(PathBuf::from),
+            announcements: self.announcements.clone(),
+            announcement_store: self.announcement_store.as_ref().map(PathBuf::from),
+            variables: self.variables.clone(),
+            default_locale: self.default_locale.clone().unwrap_or_else(|| DEFAULT_LOCALE.into()),
+            content_security_policy: self.content_security_policy.clone(),
+            theme: self.theme,
+            graphql: self.graphql.clone().into_limits(self.development),
             dev_mode_enabled: self.development,
         })
     }
@@ -96,8 +387,23 @@ impl Default for ServeConfigContent {
             interface_addresses: None,
             authorization_server_url: Some("".into()),
             client_id: Some("".into()),
+            client_secret: None,
             scope: None,
+            session_key: None,
+            logging: None,
             site_list: crate::site::SiteList::new(), 
+            admin_claim_rules: Vec::new(),
+            deny_claim_rules: Vec::new(),
+            default_visible: false,
+            site_store: None,
+            user_store: None,
+            variables: crate::site::Variables::new(),
+            announcements: Vec::new(),
+            announcement_store: None,
+            default_locale: None,
+            content_security_policy: None,
+            theme: crate::templates::Theme::default(),
+            graphql: crate::graphql_limits::GraphQLConfig::default(),
             development: false,
             disable_auth: false,
         }
@@ -105,8 +411,49 @@ impl Default for ServeConfigContent {
 }
 
 
+/// help texts of the arguments shared by the subcommands that read a
+/// configuration file
+struct ConfigFileHelp {
+    config_file: String,
+    config_format: String,
+    set: String,
+}
+
+impl ConfigFileHelp {
+    fn new() -> ConfigFileHelp {
+        ConfigFileHelp {
+            config_file: String::new() + "configuration file in YAML, JSON or TOML format, or '" + STDIN_CONFIG_FILE + "' to read it from stdin",
+            config_format: String::new() + "format of the configuration file. If omitted, it is derived from the file name's extension, and defaults to yaml when reading from '" + STDIN_CONFIG_FILE + "'",
+            set: String::new() + "overrides a configuration key from the configuration file and " + ENV_VAR_PREFIX + "* environment variables. Can be given multiple times.",
+        }
+    }
+}
+
+/// the CONFIG_FILE, --config-format and --set arguments
+fn config_file_args(help: &ConfigFileHelp) -> Vec<Arg<'_, '_>> {
+    vec![
+        Arg::with_name(CONFIG_FILE_ARG_NAME)
+            .required(true)
+            .takes_value(true)
+            .help(&help.config_file),
+        Arg::with_name(CONFIG_FORMAT_ARG_NAME)
+            .long(CONFIG_FORMAT_ARG_NAME)
+            .takes_value(true)
+            .possible_values(ConfigFormat::NAMES)
+            .help(&help.config_format),
+        Arg::with_name(SET_ARG_NAME)
+            .long(SET_ARG_NAME)
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("KEY=VALUE")
+            .help(&help.set),
+    ]
+}
+
 pub fn read_config() -> Result<crate::AppConfig, Box<dyn Error>> {
 
+    let config_file_help = ConfigFileHelp::new();
     let am = App::new(CARGO_PKG_NAME)
     .version(CARGO_PKG_VERSION)
     .setting(AppSettings::SubcommandRequiredElseHelp)
@@ -118,11 +465,8 @@ pub fn read_config() -> Result<crate::AppConfig, Box<dyn Error>> {
     )
     .subcommand(SubCommand::with_name(SERVE_SCMD_NAME)
         .about((String::new() + "Runs " + CARGO_PKG_NAME + " in server mode, which is typically what you want.").as_str())
-        .arg(Arg::with_name("CONFIG_FILE")
-            .required(true)
-            .takes_value(true)
-            .help("configuration file in YAML format")
-        )
+        .after_help((String::new() + "The configuration is validated like '" + CHECK_CONFIG_SCMD_NAME + "' does, and the server is not started if it is invalid.").as_str())
+        .args(&config_file_args(&config_file_help))
         .arg(Arg::with_name(DEVELOPMENT_ARG_NAME)
             .short("d")
             .long(DEVELOPMENT_ARG_NAME)
@@ -132,60 +476,145 @@ pub fn read_config() -> Result<crate::AppConfig, Box<dyn Error>> {
             .long(NO_AUTH_ARG_NAME)
             .help("if specified, disables authentication, and will show all elements in site list, regardless of rules")
         )
+        .arg(Arg::with_name(PRINT_EFFECTIVE_CONFIG_ARG_NAME)
+            .long(PRINT_EFFECTIVE_CONFIG_ARG_NAME)
+            .help("if specified, prints the configuration resulting from the configuration file, environment variables and command line switches (with secrets redacted) and exits")
+        )
     )
     .subcommand(SubCommand::with_name(INIT_TEMPLATES_SCMD_NAME)
         .about("Generates a template directory. Run once before starting development")
         .help((String::new() + "Generate a directory with handlebars templates that can be used as the basis for custom templates. The target directory can be configured using the --" + TEMPLATE_DIR_ARG_NAME + " switch.").as_str())
+        .arg(Arg::with_name(THEME_ARG_NAME)
+            .long(THEME_ARG_NAME)
+            .takes_value(true)
+            .possible_values(crate::templates::Theme::NAMES)
+            .help("built-in theme to export, defaults to 'tiles'. Set the same theme in the configuration, so files missing in the template directory come from it")
+        )
+    )
+    .subcommand(SubCommand::with_name(CHECK_CONFIG_SCMD_NAME)
+        .about("Validates a configuration file without starting the server")
+        .help((String::new() + "Checks the configuration file that would be passed to '" + SERVE_SCMD_NAME + "' and reports all problems found. No network connections are made, so the authorization server does not need to be reachable. Exits with a non-zero status if the configuration is invalid.").as_str())
+        .args(&config_file_args(&config_file_help))
+        .arg(Arg::with_name(NO_AUTH_ARG_NAME)
+            .long(NO_AUTH_ARG_NAME)
+            .help((String::new() + "if specified, the configuration is checked as if '" + SERVE_SCMD_NAME + "' was started with --" + NO_AUTH_ARG_NAME).as_str())
+        )
+    )
+    .subcommand(SubCommand::with_name(EXPLAIN_SCMD_NAME)
+        .about("Explains which sites are visible for a given access token, and why")
+        .help((String::new() + "Evaluates the claim rules of every site in the configuration file against the claims of an access token (or a JSON claims file) and prints which sites are visible, which rule matched and why the other rules did not. The token's signature is only checked if --" + VERIFY_ARG_NAME + " is given, which requires the authorization server to be reachable.").as_str())
+        .args(&config_file_args(&config_file_help))
+        .arg(Arg::with_name(TOKEN_ARG_NAME)
+            .long(TOKEN_ARG_NAME)
+            .takes_value(true)
+            .value_name("JWT")
+            .help("the encoded access token")
+        )
+        .arg(Arg::with_name(TOKEN_FILE_ARG_NAME)
+            .long(TOKEN_FILE_ARG_NAME)
+            .takes_value(true)
+            .value_name("FILE")
+            .help("file containing the encoded access token")
+        )
+        .arg(Arg::with_name(CLAIMS_FILE_ARG_NAME)
+            .long(CLAIMS_FILE_ARG_NAME)
+            .takes_value(true)
+            .value_name("FILE")
+            .help("JSON file containing the claims of a decoded access token")
+        )
+        .group(ArgGroup::with_name("claims")
+            .args(&[TOKEN_ARG_NAME, TOKEN_FILE_ARG_NAME, CLAIMS_FILE_ARG_NAME])
+            .required(true)
+        )
+        .arg(Arg::with_name(VERIFY_ARG_NAME)
+            .long(VERIFY_ARG_NAME)
+            .help("if specified, the token is validated with the authorization server before its claims are used")
+        )
+    )
+    .subcommand(SubCommand::with_name(CONFIG_SCHEMA_SCMD_NAME)
+        .about("Prints the JSON Schema of the configuration file")
+        .help((String::new() + "Prints a JSON Schema describing the configuration file read by '" + SERVE_SCMD_NAME + "'. Editors can use it for auto-completion and validation of the configuration.").as_str())
     )
     .get_matches();
 
     if let Some(m) = am.subcommand_matches(SERVE_SCMD_NAME) {
-        let config_file_path = m.value_of("CONFIG_FILE").unwrap();
-
-        let config_file = match File::open(config_file_path) {
-            Ok(f) => f,
-            Err(e) => {
-                let msg = format!("cannot open configuration file {}: {}", config_file_path, e.to_string());
-                return Err(Box::new(StringError::from_source(Box::new(e), &msg)))
+        let cfg_content = read_serve_config_content(m)?;
+        if m.is_present(PRINT_EFFECTIVE_CONFIG_ARG_NAME) {
+            let mut cfg = PrintConfigConfig {
+                common: CommonConfig::default(),
+                content: cfg_content.to_redacted_yaml()?,
+            };
+            return match init_common_config(&am, &mut cfg.common, false) {
+                Ok(_) => Ok(AppConfig::PrintConfig(cfg)),
+                Err(e) => Err(e)
             }
-        };
+        }
 
-        let r: serde_yaml::Result<ServeConfigContent> = if config_file_path.ends_with(".yaml") || config_file_path.ends_with(".yml") {
-            serde_yaml::from_reader(config_file)
-        } else {
-            let msg = std::fmt::format(format_args!("config file name {} must end in .yml or .yaml", config_file_path));
-            return Err(Box::new(StringError::from(msg)));
-        };
+        let mut cfg = into_serve_config(cfg_content, false)?;
+        match init_common_config(&am, &mut cfg.common, true) {
+            Ok(_) => Ok(AppConfig::Serve(cfg)),
+            Err(e) => Err(e)
+        }
         
-        let cfg_content = match r {
-            Ok(cfg) => cfg,
-            Err(e) => {
-                let msg = format!("error parsing configuration file {}: {}", config_file_path, e.to_string());
-                return Err(Box::new(StringError::from_source(Box::new(e), &msg)))
-            }
+    } else if let Some(m) = am.subcommand_matches(INIT_TEMPLATES_SCMD_NAME){
+        let mut cfg = InitTemplatesConfig {
+            common: CommonConfig::default(),
+            theme: m.value_of(THEME_ARG_NAME).map(str::parse).transpose()?.unwrap_or_default(),
         };
-
-        let no_auth = m.is_present(NO_AUTH_ARG_NAME);
-        let mut cfg = match cfg_content.into_config(no_auth) {
-            Ok(v) => v,
-            Err(msg) => {
-                let msg = format!("configuration validation failed ({})", msg.to_string());
+        match init_common_config(&am, &mut cfg.common, false) {
+            Ok(_) => Ok(AppConfig::InitTemplates(cfg)),
+            Err(e) => Err(e)
+        }
+    } else if let Some(m) = am.subcommand_matches(CHECK_CONFIG_SCMD_NAME) {
+        let mut cfg = CheckConfigConfig {
+            common: CommonConfig::default(),
+            config_file: m.value_of(CONFIG_FILE_ARG_NAME).unwrap().into(),
+            config_format: config_format_arg(m)?,
+            no_auth: m.is_present(NO_AUTH_ARG_NAME),
+            overrides: m.values_of(SET_ARG_NAME)
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect(),
+        };
+        // a missing template directory is reported by check_config() along
+        // with all other errors, so we don't require it here
+        match init_common_config(&am, &mut cfg.common, false) {
+            Ok(_) => Ok(AppConfig::CheckConfig(cfg)),
+            Err(e) => Err(e)
+        }
+    } else if let Some(m) = am.subcommand_matches(EXPLAIN_SCMD_NAME) {
+        let verify = m.is_present(VERIFY_ARG_NAME);
+        let claims_source = if let Some(t) = m.value_of(TOKEN_ARG_NAME) {
+            ClaimsSource::Token(t.into())
+        } else if let Some(f) = m.value_of(TOKEN_FILE_ARG_NAME) {
+            ClaimsSource::TokenFile(f.into())
+        } else {
+            ClaimsSource::ClaimsFile(m.value_of(CLAIMS_FILE_ARG_NAME).unwrap().into())
+        };
+        if verify {
+            if let ClaimsSource::ClaimsFile(_) = claims_source {
+                let msg = format!("--{} requires a token, but a claims file was given", VERIFY_ARG_NAME);
                 return Err(Box::new(StringError::from(msg)))
             }
-        };
-
-        if m.is_present(DEVELOPMENT_ARG_NAME) {
-            cfg.dev_mode_enabled = true;
         }
-        match init_common_config(&am, &mut cfg.common, true) {
-            Ok(_) => Ok(AppConfig::Serve(cfg)),
+        let mut cfg = ExplainConfig {
+            common: CommonConfig::default(),
+            serve: into_serve_config(read_serve_config_content(m)?, !verify)?,
+            claims_source,
+            verify,
+        };
+        match init_common_config(&am, &mut cfg.common, false) {
+            Ok(_) => Ok(AppConfig::Explain(cfg)),
             Err(e) => Err(e)
         }
-        
-    } else if let Some(_m) = am.subcommand_matches(INIT_TEMPLATES_SCMD_NAME){
-        let mut cfg = InitTemplatesConfig { common: CommonConfig::default() };
+    } else if let Some(_m) = am.subcommand_matches(CONFIG_SCHEMA_SCMD_NAME) {
+        let mut cfg = PrintConfigConfig {
+            common: CommonConfig::default(),
+            content: config_schema(),
+        };
         match init_common_config(&am, &mut cfg.common, false) {
-            Ok(_) => Ok(AppConfig::InitTemplates(cfg)),
+            Ok(_) => Ok(AppConfig::PrintConfig(cfg)),
             Err(e) => Err(e)
         }
     } else {
@@ -193,6 +622,56 @@ pub fn read_config() -> Result<crate::AppConfig, Box<dyn Error>> {
     }
 }
 
+/// reads the configuration file given as CONFIG_FILE argument of a subcommand
+/// and applies all overrides. The order of precedence, from lowest to highest, is:
+/// configuration file, RESWEB_* environment variables, --set switches,
+/// --development and --no-auth switches
+fn read_serve_config_content(m: &ArgMatches) -> Result<ServeConfigContent, Box<dyn Error>> {
+    let config_file_path = m.value_of(CONFIG_FILE_ARG_NAME).unwrap();
+
+    let content = read_config_file(config_file_path)?;
+    let format = resolve_config_format(config_file_path, config_format_arg(m)?)?;
+    
+    let mut cfg_content: ServeConfigContent = match format.parse(&content) {
+        Ok(cfg) => cfg,
+        Err(e) => {
+            let msg = format!("error parsing configuration file {}: {}", config_file_path, e);
+            return Err(Box::new(StringError::from_source(e, &msg)))
+        }
+    };
+
+    let set_args = m.values_of(SET_ARG_NAME).into_iter().flatten().collect::<Vec<_>>();
+    if let Err(msg) = cfg_content.apply_overrides(&set_args, std::env::vars()) {
+        return Err(Box::new(StringError::from(msg)))
+    }
+    if m.is_present(DEVELOPMENT_ARG_NAME) {
+        cfg_content.development = true;
+    }
+    if m.is_present(NO_AUTH_ARG_NAME) {
+        cfg_content.disable_auth = true;
+    }
+
+    Ok(cfg_content)
+}
+
+fn into_serve_config(cfg_content: ServeConfigContent, no_auth: bool) -> Result<ServeConfig, Box<dyn Error>> {
+    match cfg_content.into_config(no_auth) {
+        Ok(v) => Ok(v),
+        Err(msg) => {
+            let msg = format!("configuration validation failed ({})", msg);
+            Err(Box::new(StringError::from(msg)))
+        }
+    }
+}
+
+/// reads a serve configuration with authentication disabled, for tests of
+/// the request handlers
+#[cfg(test)]
+pub fn serve_config_from_yaml(yaml: &str) -> ServeConfig {
+    let content: ServeConfigContent = serde_yaml::from_str(yaml).unwrap();
+    into_serve_config(content, true).unwrap()
+}
+
 fn init_common_config(m: &ArgMatches, common: &mut CommonConfig, require_templatedir_exists: bool) -> Result<(), Box<dyn Error>> {
     if let Some(v) = m.value_of(TEMPLATE_DIR_ARG_NAME) {
         let p = PathBuf::from(v);
@@ -204,4 +683,271 @@ fn init_common_config(m: &ArgMatches, common: &mut CommonConfig, require_templat
         }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn config_format_arg(m: &ArgMatches) -> Result<Option<ConfigFormat>, Box<dyn Error>> {
+    match m.value_of(CONFIG_FORMAT_ARG_NAME) {
+        Some(f) => match ConfigFormat::from_str(f) {
+            Ok(format) => Ok(Some(format)),
+            Err(msg) => Err(Box::new(StringError::from(msg)))
+        },
+        None => Ok(None)
+    }
+}
+
+fn resolve_config_format(config_file_path: &str, config_format: Option<ConfigFormat>) -> Result<ConfigFormat, Box<dyn Error>> {
+    if let Some(format) = config_format {
+        return Ok(format)
+    }
+    if config_file_path == STDIN_CONFIG_FILE {
+        return Ok(ConfigFormat::Yaml)
+    }
+    match ConfigFormat::from_path(config_file_path) {
+        Some(format) => Ok(format),
+        None => {
+            let msg = format!("cannot determine format of config file {}, its name must end in .yml, .yaml, .json or .toml, or the format must be given with --{}", config_file_path, CONFIG_FORMAT_ARG_NAME);
+            Err(Box::new(StringError::from(msg)))
+        }
+    }
+}
+
+fn read_config_file(config_file_path: &str) -> Result<String, Box<dyn Error>> {
+    let r = if config_file_path == STDIN_CONFIG_FILE {
+        let mut content = String::new();
+        std::io::stdin().read_to_string(&mut content).map(|_| content)
+    } else {
+        std::fs::read_to_string(config_file_path)
+    };
+
+    match r {
+        Ok(content) => Ok(content),
+        Err(e) => {
+            let msg = format!("cannot open configuration file {}: {}", config_file_path, e);
+            Err(Box::new(StringError::from_source(Box::new(e), &msg)))
+        }
+    }
+}
+
+/// Returns the JSON Schema of the configuration file
+pub fn config_schema() -> String {
+    let schema = schemars::schema_for!(ServeConfigContent);
+    serde_json::to_string_pretty(&schema).unwrap() + "\n"
+}
+
+/// Validates the configuration for the `check-config` subcommand and
+/// returns all errors found. An empty result means that the configuration
+/// is valid.
+pub fn check_config(cfg: &CheckConfigConfig) -> Vec<String> {
+    let content = match read_config_file(&cfg.config_file) {
+        Ok(c) => c,
+        Err(e) => return vec![e.to_string()]
+    };
+    let format = match resolve_config_format(&cfg.config_file, cfg.config_format) {
+        Ok(f) => f,
+        Err(e) => return vec![e.to_string()]
+    };
+    let mut value: serde_json::Value = match format.parse(&content) {
+        Ok(v) => v,
+        Err(e) => return vec![format!("error parsing configuration file {}: {}", cfg.config_file, e)]
+    };
+
+    let mut errors = Vec::new();
+
+    // rules with an invalid regex would fail the whole configuration, so
+    // they are reported and removed first
+    for (key, what) in [("admin_claim_rules", "admin claim rule"), ("deny_claim_rules", "deny rule")] {
+        errors.extend(take_invalid_regexes(value.get_mut(key), "/operand/regex", what));
+    }
+    if let Some(rule_sets) = value.pointer_mut("/site_list/rule_sets").and_then(|v| v.as_array_mut()) {
+        for (i, set) in rule_sets.iter_mut().enumerate() {
+            let name = set.get("name").and_then(|n| n.as_str()).map(String::from).unwrap_or_else(|| format!("#{}", i + 1));
+            errors.extend(take_invalid_regexes(set.get_mut("claim_rules"), "/operand/regex", "claim rule")
+                .into_iter()
+                .map(|e| format!("rule set '{}': {}", name, e)));
+        }
+    }
+    if let Some(announcements) = value.get_mut("announcements").and_then(|v| v.as_array_mut()) {
+        for (i, a) in announcements.iter_mut().enumerate() {
+            let id = a.get("id").and_then(|n| n.as_str()).map(String::from).unwrap_or_else(|| format!("#{}", i + 1));
+            errors.extend(take_invalid_regexes(a.get_mut("claim_rules"), "/operand/regex", "claim rule")
+                .into_iter()
+                .map(|e| format!("announcement '{}': {}", id, e)));
+        }
+    }
+
+    // sites are deserialized one by one first, so that a broken site
+    // (e.g. one with an invalid regex) does not hide errors in the others.
+    // Broken sites are removed so they are not reported twice below.
+    if let Some(sites) = value.pointer_mut("/site_list/sites").and_then(|v| v.as_array_mut()) {
+        let mut index = 0;
+        sites.retain_mut(|site| {
+            index += 1;
+            let name = site.get("name")
+                .and_then(|n| n.as_str())
+                .map(String::from)
+                .unwrap_or_else(|| format!("#{}", index));
+
+            let regex_errors = [("claim_rules", "/operand/regex", "claim rule"), ("deny_claim_rules", "/operand/regex", "deny rule"), ("conditions", "/regex", "condition")]
+                .iter()
+                .flat_map(|&(key, pointer, what)| take_invalid_regexes(site.get_mut(key), pointer, what))
+                .map(|e| format!("site '{}': {}", name, e))
+                .collect::<Vec<_>>();
+            if !regex_errors.is_empty() {
+                errors.extend(regex_errors);
+                return false
+            }
+
+            match serde_json::from_value::<crate::site::Site>(site.clone()) {
+                Ok(_) => true,
+                Err(e) => {
+                    errors.push(format!("site '{}': {}", name, e));
+                    false
+                }
+            }
+        });
+    }
+
+    match serde_json::from_value::<ServeConfigContent>(value) {
+        Ok(mut content) => {
+            let set_args = cfg.overrides.iter().map(String::as_str).collect::<Vec<_>>();
+            match content.apply_overrides(&set_args, std::env::vars()) {
+                Ok(_) => errors.extend(content.validate(cfg.no_auth)),
+                Err(e) => errors.push(e)
+            }
+        },
+        Err(e) => errors.push(e.to_string())
+    }
+
+    if let Some(template_dir) = &cfg.common.template_dir {
+        if !template_dir.is_dir() {
+            errors.push(format!("specified template directory '{}' does not exist", template_dir.to_string_lossy()));
+        }
+    }
+
+    errors
+}
+
+/// Compiles the regexes of a list of rules or conditions, at the given
+/// pointer in each entry, and removes the entries whose regex is invalid.
+/// serde can't tell us why a regex operand failed to parse, because
+/// Operand is untagged, so we compile regexes here to get the details.
+fn take_invalid_regexes(list: Option<&mut serde_json::Value>, regex_pointer: &str, what: &str) -> Vec<String> {
+    let mut errors = Vec::new();
+    if let Some(entries) = list.and_then(|l| l.as_array_mut()) {
+        let mut index = 0;
+        entries.retain(|entry| {
+            index += 1;
+            let error = entry.pointer(regex_pointer)
+                .and_then(|r| r.as_str())
+                .and_then(|r| regex::Regex::new(r).err());
+            match error {
+                Some(e) => {
+                    errors.push(format!("{} #{} has an invalid regex: {}", what, index, e));
+                    false
+                },
+                None => true
+            }
+        });
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The schema file is published for use in editors and CI, so it must be
+    /// regenerated whenever the configuration structure changes, using:
+    ///
+    /// resweb config-schema > resweb-config.schema.json
+    #[test]
+    fn published_schema_is_up_to_date() {
+        let published = include_str!("../resweb-config.schema.json");
+        assert_eq!(published, config_schema(), "resweb-config.schema.json is outdated, regenerate it with 'resweb config-schema'");
+    }
+
+    #[test]
+    fn overrides_apply_in_order() {
+        let mut content: ServeConfigContent = serde_yaml::from_str(r#"
+port: 8080
+scope: openid
+site_list: { sites: [] }
+variables:
+  environment: file
+  region: file
+"#).unwrap();
+        let env = [
+            ("RESWEB_PORT", "8081"),
+            ("RESWEB_SCOPE", "openid env"),
+            ("RESWEB_VAR_ENVIRONMENT", "env"),
+            ("RESWEB_VAR_REGION", "env"),
+            ("UNRELATED", "ignored"),
+        ].iter().map(|(k, v)| (k.to_string(), v.to_string()));
+        content.apply_overrides(&["scope=openid set", "variables.region=set"], env).unwrap();
+        assert_eq!(content.port, 8081);
+        assert_eq!(content.scope.as_deref(), Some("openid set"));
+        assert_eq!(content.variables.get("environment").map(String::as_str), Some("env"));
+        assert_eq!(content.variables.get("region").map(String::as_str), Some("set"));
+    }
+
+    /// serve used to start with these problems, now it refuses to
+    #[test]
+    fn serve_rejects_invalid_sites_it_used_to_accept() {
+        let content: ServeConfigContent = serde_yaml::from_str(r#"
+port: 8080
+interface_addresses: [127.0.0.1]
+site_list:
+  sites:
+    - name: wiki
+      url: not a url
+      claim_rules:
+        - { path: "groups..name", operator: Matches, operand: { value: it } }
+    - { name: wiki, url: "https://wiki.example", claim_rules: [] }
+"#).unwrap();
+        let err = into_serve_config(content, true).unwrap_err().to_string();
+        assert_eq!(err, "configuration validation failed (\
+            site 'wiki': cannot parse url 'not a url': relative URL without a base; \
+            site 'wiki': claim rule #1 has an invalid path 'groups..name'; \
+            site name 'wiki' is used more than once)");
+    }
+
+    #[test]
+    fn check_config_reports_invalid_regexes_in_all_rule_lists() {
+        let path = std::env::temp_dir().join(format!("resweb-check-config-{}.yaml", std::process::id()));
+        std::fs::write(&path, r#"
+port: 8080
+disable_auth: true
+admin_claim_rules:
+  - { path: groups, operator: Matches, operand: { regex: "(" } }
+deny_claim_rules:
+  - { path: groups, operator: Matches, operand: { regex: "[" } }
+site_list:
+  sites:
+    - name: Wiki
+      url: https://wiki.example
+      deny_claim_rules:
+        - { path: groups, operator: Matches, operand: { regex: "(" } }
+    - name: Billing
+      url: https://billing.example
+      conditions:
+        - { variable: environment, regex: "(" }
+    - name: Broken
+"#).unwrap();
+        let errors = check_config(&CheckConfigConfig {
+            common: CommonConfig::default(),
+            config_file: path.to_string_lossy().to_string(),
+            config_format: None,
+            no_auth: false,
+            overrides: Vec::new(),
+        });
+        std::fs::remove_file(&path).unwrap();
+        let has = |prefix: &str| errors.iter().any(|e| e.starts_with(prefix));
+        assert!(has("admin claim rule #1 has an invalid regex"), "{:?}", errors);
+        assert!(has("deny rule #1 has an invalid regex"), "{:?}", errors);
+        assert!(has("site 'Wiki': deny rule #1 has an invalid regex"), "{:?}", errors);
+        assert!(has("site 'Billing': condition #1 has an invalid regex"), "{:?}", errors);
+        // the other errors are still found
+        assert!(has("site 'Broken': missing field `url`"), "{:?}", errors);
+        assert_eq!(errors.len(), 5, "{:?}", errors);
+    }
+}
@@ -1,13 +1,66 @@
 
 use std::{cell::RefCell, future::{Future, Ready}, pin::Pin, sync::Arc, task::{Context, Poll}};
 
-use actix_session::UserSession;
-use actix_web::{Error, HttpMessage, HttpResponse, dev::{Body, Service, ServiceRequest, ServiceResponse, Transform}, error::{ErrorBadRequest, ErrorInternalServerError}, web};
+use actix_session::{Session, UserSession};
+use actix_web::{Error, HttpMessage, HttpResponse, dev::{Body, Service, ServiceRequest, ServiceResponse, Transform}, error::{ErrorBadRequest, ErrorInternalServerError}, http::{header, Method}, web};
+use rand::Rng;
 use url::{Url};
 
 use crate::auth::OidcAuth;
 
 const SESSION_AUTH_KEY: &str = "auth_r";
+const SESSION_CSRF_KEY: &str = "csrf_token";
+
+/// header carrying the CSRF token on requests authenticated by the session cookie
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+/// query parameter carrying the CSRF token where no header can be set, which
+/// is only the WebSocket upgrade. Anywhere else, it would put the token into
+/// URLs, access logs and Referer headers.
+pub const CSRF_QUERY_PARAM: &str = "csrf_token";
+
+/// returns the access token stored in the session of the request, if any
+pub fn session_access_token(req: &ServiceRequest) -> Result<Option<String>, Error> {
+    req.get_session().get::<String>(SESSION_AUTH_KEY)
+}
+
+/// returns the CSRF token of the session, creating one if the session has none yet
+pub fn csrf_token(session: &Session) -> Result<String, Error> {
+    if let Some(t) = session.get::<String>(SESSION_CSRF_KEY)? {
+        return Ok(t)
+    }
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    let token = base64::encode_config(bytes, base64::URL_SAFE_NO_PAD);
+    session.set(SESSION_CSRF_KEY, &token)?;
+    Ok(token)
+}
+
+/// checks that the request carries the CSRF token of its session in the
+/// CSRF_HEADER, or in the CSRF_QUERY_PARAM if `query_param_allowed`
+pub fn has_valid_csrf_token(req: &ServiceRequest, query_param_allowed: bool) -> bool {
+    let expected = match req.get_session().get::<String>(SESSION_CSRF_KEY) {
+        Ok(Some(t)) => t,
+        _ => return false,
+    };
+    let presented = req.headers().get(CSRF_HEADER)
+        .and_then(|h| h.to_str().ok())
+        .map(String::from)
+        .or_else(|| url::form_urlencoded::parse(req.query_string().as_bytes())
+            .find(|(k, _)| query_param_allowed && k == CSRF_QUERY_PARAM)
+            .map(|(_, v)| v.into_owned())
+        );
+    presented.is_some_and(|p| constant_time_eq(p.as_bytes(), expected.as_bytes()))
+}
+
+/// true if the request opens a WebSocket connection
+pub fn is_websocket_upgrade(req: &ServiceRequest) -> bool {
+    req.method() == Method::GET && req.headers().get(header::UPGRADE)
+        .and_then(|h| h.to_str().ok())
+        .is_some_and(|u| u.eq_ignore_ascii_case("websocket"))
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
 
 pub trait CookieAuthHandler : Clone {
     const DEFAULT_SCOPES: &'static str = "openid";
@@ -140,7 +193,7 @@ where
 
     async fn redirect_to_auth_server(handler: H, hreq: &ServiceRequest) -> Result<HttpResponse, Error> {
         
-        let mut auth_request_uri = match Url::parse(&handler.auth_uri()) {
+        let mut auth_request_uri = match Url::parse(handler.auth_uri()) {
             Err(e) => return Err(ErrorInternalServerError(e)),
             Ok(u) => u
         };
@@ -194,7 +247,8 @@ where
                     }
                 }
 
-                service.borrow_mut().call(req).await
+                let fut = service.borrow_mut().call(req);
+                fut.await
             }
         )
     }
@@ -216,7 +270,7 @@ fn token_exchange_url(token_exchange_path: &str, request_url: &str) -> Result<St
 
 async fn handle_web_token_exchange(auth: Arc<OidcAuth>, token_exchange_url: &str, req: &ServiceRequest, q: &WebTokenExcechangeQuery) -> HttpResponse<Body> {
     let redirect_uri = token_exchange_url;
-    let token_response = match auth.exchange_code_for_token(&q.code, Some(&redirect_uri), q.state.as_deref()).await {
+    let token_response = match auth.exchange_code_for_token(&q.code, Some(redirect_uri), q.state.as_deref()).await {
         Ok(r) => r,
         Err(e) => return HttpResponse::BadRequest().body(e.to_string()),
     };
@@ -235,3 +289,54 @@ async fn handle_web_token_exchange(auth: Arc<OidcAuth>, token_exchange_url: &str
     .set_header("location", location)
     .finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+
+    use super::*;
+
+    const TOKEN: &str = "vU2lzdGVudC1jc3JmLXRva2VuLWZvci10ZXN0aW5nLTEyMw";
+
+    /// a request whose session holds TOKEN as CSRF token
+    fn with_session(req: TestRequest) -> ServiceRequest {
+        let mut req = req.to_srv_request();
+        Session::set_session(vec![(SESSION_CSRF_KEY.to_string(), serde_json::to_string(TOKEN).unwrap())], &mut req);
+        req
+    }
+
+    #[test]
+    fn accepts_the_session_token_in_the_header() {
+        let req = with_session(TestRequest::post().header(CSRF_HEADER, TOKEN));
+        assert!(has_valid_csrf_token(&req, false));
+    }
+
+    #[test]
+    fn rejects_missing_wrong_and_truncated_tokens() {
+        assert!(!has_valid_csrf_token(&with_session(TestRequest::post()), false));
+        let wrong = TOKEN.replace('v', "w");
+        assert!(!has_valid_csrf_token(&with_session(TestRequest::post().header(CSRF_HEADER, wrong)), false));
+        let truncated = &TOKEN[..TOKEN.len() - 1];
+        assert!(!has_valid_csrf_token(&with_session(TestRequest::post().header(CSRF_HEADER, truncated)), false));
+    }
+
+    #[test]
+    fn rejects_a_token_without_session_token() {
+        let req = TestRequest::post().header(CSRF_HEADER, TOKEN).to_srv_request();
+        assert!(!has_valid_csrf_token(&req, false));
+    }
+
+    #[test]
+    fn query_param_is_only_accepted_where_allowed() {
+        let uri = format!("/gql/subscriptions?{}={}", CSRF_QUERY_PARAM, TOKEN);
+        assert!(!has_valid_csrf_token(&with_session(TestRequest::get().uri(&uri)), false));
+        assert!(has_valid_csrf_token(&with_session(TestRequest::get().uri(&uri)), true));
+    }
+
+    #[test]
+    fn detects_websocket_upgrades() {
+        assert!(is_websocket_upgrade(&TestRequest::get().header(header::UPGRADE, "WebSocket").to_srv_request()));
+        assert!(!is_websocket_upgrade(&TestRequest::get().to_srv_request()));
+        assert!(!is_websocket_upgrade(&TestRequest::post().header(header::UPGRADE, "websocket").to_srv_request()));
+    }
+}
@@ -0,0 +1,162 @@
+use std::{error::Error, fs::File};
+
+use chrono::{DateTime, Utc};
+
+use crate::{ClaimsSource, ExplainConfig, ServeConfig, auth::{Claims, OidcAuth}, error::StringError};
+
+/// Prints, for every site in the site list, whether it is visible with the
+/// configured claims and what each of its claim rules and its `when`
+/// expression evaluated to.
+pub fn explain(cfg: &ExplainConfig) -> Result<(), Box<dyn Error>> {
+    let claims = load_claims(cfg)?;
+    for line in explanation(&cfg.serve, &claims, chrono::Utc::now()) {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+/// the lines printed by [`explain`]
+fn explanation(serve: &ServeConfig, claims: &Claims, now: DateTime<Utc>) -> Vec<String> {
+    let mut lines = Vec::new();
+    let policy = serve.visibility_policy();
+    for (i, r) in policy.deny_claim_rules.iter().enumerate() {
+        lines.push(format!("global deny rule #{} ({}): {}", i + 1, r, r.evaluate(claims)));
+    }
+    for site in serve.site_list.sites() {
+        lines.push(format!("{}: {}", site.name(), site.visibility(claims, &policy, now)));
+
+        if !site.is_within_window(now) {
+            let from = site.visible_from().map(|t| t.to_rfc3339()).unwrap_or_else(|| "-".into());
+            let until = site.visible_until().map(|t| t.to_rfc3339()).unwrap_or_else(|| "-".into());
+            lines.push(format!("  outside of its visibility window ({} to {})", from, until));
+        }
+        for (i, c) in site.conditions().iter().enumerate() {
+            let outcome = match serve.variables.get(&c.variable) {
+                None => "variable not set",
+                Some(_) if c.holds(&serve.variables) => "holds",
+                Some(_) => "does not hold",
+            };
+            lines.push(format!("  condition #{} ({}): {}", i + 1, c, outcome));
+        }
+
+        for (i, r) in site.deny_claim_rules.iter().enumerate() {
+            lines.push(format!("  deny rule #{} ({}): {}", i + 1, r, r.evaluate(claims)));
+        }
+        if site.claim_rules.is_empty() && site.rule_sets().is_empty() && site.when().is_none() {
+            lines.push("  no claim rules".to_string());
+        }
+        for (i, r) in site.claim_rules.iter().enumerate() {
+            lines.push(format!("  rule #{} ({}): {}", i + 1, r, r.evaluate(claims)));
+        }
+        for (i, set) in site.resolved_rule_sets().iter().enumerate() {
+            lines.push(format!("  rule set #{} '{}':", i + 1, set.name()));
+            for (j, r) in set.claim_rules().iter().enumerate() {
+                lines.push(format!("    rule #{} ({}): {}", j + 1, r, r.evaluate(claims)));
+            }
+        }
+        if let Some(w) = site.when() {
+            match w.evaluate(claims) {
+                Ok(result) => lines.push(format!("  when ({}): {}", w, result)),
+                Err(e) => lines.push(format!("  when ({}): {}", w, e)),
+            }
+        }
+    }
+    lines
+}
+
+fn load_claims(cfg: &ExplainConfig) -> Result<Claims, Box<dyn Error>> {
+    let token = match &cfg.claims_source {
+        ClaimsSource::Token(t) => t.clone(),
+        ClaimsSource::TokenFile(p) => match std::fs::read_to_string(p) {
+            Ok(t) => t.trim().to_string(),
+            Err(e) => {
+                let msg = format!("cannot read token file {}: {}", p.to_string_lossy(), e);
+                return Err(Box::new(StringError::from_source(Box::new(e), &msg)))
+            }
+        },
+        ClaimsSource::ClaimsFile(p) => {
+            let r = File::open(p)
+                .map_err(|e| Box::new(e) as Box<dyn Error>)
+                .and_then(|f| serde_json::from_reader::<_, serde_json::Value>(f).map_err(|e| Box::new(e) as Box<dyn Error>));
+            return match r {
+                Ok(v) => Ok(Claims::from(v)),
+                Err(e) => {
+                    let msg = format!("cannot read claims file {}: {}", p.to_string_lossy(), e);
+                    Err(Box::new(StringError::from_source(e, &msg)))
+                }
+            }
+        }
+    };
+
+    if !cfg.verify {
+        return Ok(Claims::decode_unverified(&token)?)
+    }
+
+    // into_config() ensures that auth is configured when verification was requested
+    let auth_config = cfg.serve.auth.as_ref().unwrap();
+    let auth = OidcAuth::new(auth_config.authorization_server_url.to_string(), &auth_config.client_id, auth_config.client_secret.as_deref());
+    let claims = actix_web::rt::System::new("explain")
+        .block_on(async move { auth.validate_token(&token).await })?;
+    Ok(claims)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn explain_for(claims: serde_json::Value) -> Vec<String> {
+        let serve = crate::cli::serve_config_from_yaml(r#"
+port: 8080
+interface_addresses: [127.0.0.1]
+deny_claim_rules:
+  - { path: blocked, operator: Matches, operand: { value: true } }
+site_list:
+  sites:
+    - name: wiki
+      url: https://wiki.example
+      claim_rules:
+        - { path: department, operator: Matches, operand: { value: IT } }
+        - { path: realm_access.roles, operator: ContainsMatch, operand: { value: wiki } }
+    - name: payroll
+      url: https://payroll.example
+      claim_rules:
+        - { path: department, operator: Matches, operand: { value: IT } }
+      deny_claim_rules:
+        - { path: contractor, operator: Matches, operand: { value: true } }
+    - name: admin
+      url: https://admin.example
+      when: 'level >= 3'
+"#);
+        let now = DateTime::parse_from_rfc3339("2021-06-07T06:00:00Z").unwrap().with_timezone(&Utc);
+        explanation(&serve, &Claims::from(claims), now)
+    }
+
+    #[test]
+    fn explains_matching_and_missing_claims() {
+        let lines = explain_for(serde_json::json!({ "department": "IT", "level": 1 }));
+        assert_eq!(lines[..4], [
+            "global deny rule #1 (blocked Matches true): path not found in claims",
+            "wiki: visible because of claim rule #1",
+            "  rule #1 (department Matches \"IT\"): matched",
+            "  rule #2 (realm_access.roles ContainsMatch \"wiki\"): path not found in claims",
+        ]);
+    }
+
+    #[test]
+    fn explains_that_a_deny_rule_wins() {
+        let lines = explain_for(serde_json::json!({ "department": "IT", "contractor": true }));
+        assert!(lines.contains(&"payroll: hidden by deny claim rule #1".to_string()), "{:#?}", lines);
+        assert!(lines.contains(&"  deny rule #1 (contractor Matches true): matched".to_string()), "{:#?}", lines);
+        assert!(lines.contains(&"  rule #1 (department Matches \"IT\"): matched".to_string()), "{:#?}", lines);
+    }
+
+    #[test]
+    fn explains_a_false_when_expression() {
+        let lines = explain_for(serde_json::json!({ "level": 1 }));
+        let admin = lines.iter().position(|l| l.starts_with("admin: ")).unwrap();
+        assert_eq!(lines[admin..], [
+            "admin: hidden, because no claim rule matched and no when expression is true",
+            "  when (level >= 3): false",
+        ]);
+    }
+}
@@ -0,0 +1,766 @@
+//! A small expression language for deciding about site visibility, like
+//!
+//! ```text
+//! 'wiki_admin' in realm_access.roles || (dept == "IT" && level >= 3)
+//! ```
+//!
+//! Expressions are compiled when the configuration is loaded, so syntax and
+//! type errors are reported with their position right away. The language
+//! has no loops or function calls, and evaluation is additionally limited
+//! to a number of steps, so a rule cannot hang a worker.
+
+use std::fmt;
+
+use regex::{Regex, RegexBuilder};
+use schemars::JsonSchema;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::auth::Claims;
+
+/// maximum length of an expression's source
+const MAX_SOURCE_LEN: usize = 4096;
+/// maximum nesting depth of an expression
+const MAX_DEPTH: usize = 32;
+/// maximum number of steps an evaluation may take. Every node evaluated and
+/// every array element compared takes a step.
+const MAX_STEPS: usize = 10_000;
+/// maximum compiled size of a regular expression, in bytes
+const MAX_REGEX_SIZE: usize = 1 << 20;
+
+/// An error in an expression, with the position it was found at
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExprError {
+    /// 1-based line
+    pub line: usize,
+    /// 1-based column, in characters
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ExprError {}
+
+/// A compiled expression
+#[derive(Clone)]
+pub struct Expression {
+    source: String,
+    root: Node,
+}
+
+impl Expression {
+    pub fn compile(source: &str) -> Result<Expression, ExprError> {
+        let error_at = |offset: usize, message: String| {
+            let before = &source[..offset];
+            ExprError {
+                line: before.matches('\n').count() + 1,
+                column: before.rsplit('\n').next().unwrap_or_default().chars().count() + 1,
+                message,
+            }
+        };
+        if source.len() > MAX_SOURCE_LEN {
+            return Err(error_at(0, format!("expression is longer than {} bytes", MAX_SOURCE_LEN)))
+        }
+        let tokens = tokenize(source).map_err(|(offset, m)| error_at(offset, m))?;
+        let mut parser = Parser { tokens, index: 0, depth: 0 };
+        let root = parser.parse().map_err(|(offset, m)| error_at(offset, m))?;
+        let root_type = check(&root).map_err(|(offset, m)| error_at(offset, m))?;
+        if !matches!(root_type, Type::Bool | Type::Dynamic) {
+            return Err(error_at(root.offset, format!("expected a boolean expression, found {}", root_type)))
+        }
+        Ok(Expression { source: source.to_string(), root })
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// the dot-separated paths of all claims the expression checks
+    pub fn claim_paths(&self) -> Vec<String> {
+        fn collect(node: &Node, paths: &mut Vec<String>) {
+            match &node.kind {
+                Kind::Literal(_) => (),
+                Kind::Path(keys) => paths.push(keys.join(".")),
+                Kind::Array(elements) => elements.iter().for_each(|e| collect(e, paths)),
+                Kind::Not(n) | Kind::Match(n, _) => collect(n, paths),
+                Kind::And(l, r) | Kind::Or(l, r) | Kind::Compare(_, l, r) | Kind::In(l, r) => {
+                    collect(l, paths);
+                    collect(r, paths);
+                },
+            }
+        }
+        let mut paths = Vec::new();
+        collect(&self.root, &mut paths);
+        paths
+    }
+
+    /// Evaluates the expression against the claims. Only `true` counts as
+    /// true; missing claims are `null`. Returns an error if the evaluation
+    /// takes too many steps.
+    pub fn evaluate(&self, claims: &Claims) -> Result<bool, String> {
+        let mut evaluator = Evaluator { claims: claims.value(), steps: 0 };
+        evaluator.eval(&self.root).map(|v| v == Value::Bool(true))
+    }
+}
+
+impl fmt::Debug for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expression({:?})", self.source)
+    }
+}
+
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+impl Serialize for Expression {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Expression {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Expression::compile(&source)
+            .map_err(|e| de::Error::custom(format!("invalid expression at {}", e)))
+    }
+}
+
+impl JsonSchema for Expression {
+    fn schema_name() -> String {
+        "Expression".into()
+    }
+
+    fn json_schema(gen: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        String::json_schema(gen)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Tok {
+    Ident(String),
+    Str(String),
+    Num(f64),
+    True,
+    False,
+    Null,
+    In,
+    And,
+    Or,
+    Not,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Match,
+    Dot,
+    Comma,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    End,
+}
+
+impl fmt::Display for Tok {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Tok::Ident(i) => write!(f, "'{}'", i),
+            Tok::Str(s) => write!(f, "string {:?}", s),
+            Tok::Num(n) => write!(f, "number {}", n),
+            Tok::True => f.write_str("'true'"),
+            Tok::False => f.write_str("'false'"),
+            Tok::Null => f.write_str("'null'"),
+            Tok::In => f.write_str("'in'"),
+            Tok::And => f.write_str("'&&'"),
+            Tok::Or => f.write_str("'||'"),
+            Tok::Not => f.write_str("'!'"),
+            Tok::Eq => f.write_str("'=='"),
+            Tok::Ne => f.write_str("'!='"),
+            Tok::Lt => f.write_str("'<'"),
+            Tok::Le => f.write_str("'<='"),
+            Tok::Gt => f.write_str("'>'"),
+            Tok::Ge => f.write_str("'>='"),
+            Tok::Match => f.write_str("'=~'"),
+            Tok::Dot => f.write_str("'.'"),
+            Tok::Comma => f.write_str("','"),
+            Tok::LParen => f.write_str("'('"),
+            Tok::RParen => f.write_str("')'"),
+            Tok::LBracket => f.write_str("'['"),
+            Tok::RBracket => f.write_str("']'"),
+            Tok::End => f.write_str("end of expression"),
+        }
+    }
+}
+
+/// an error message and the byte offset it refers to
+type PosError = (usize, String);
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Tok)>, PosError> {
+    let mut tokens = Vec::new();
+    let mut chars = source.char_indices().peekable();
+    while let Some(&(offset, c)) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+            continue
+        }
+        let two = source[offset..].chars().take(2).collect::<String>();
+        let op = match two.as_str() {
+            "&&" => Some(Tok::And),
+            "||" => Some(Tok::Or),
+            "==" => Some(Tok::Eq),
+            "!=" => Some(Tok::Ne),
+            "<=" => Some(Tok::Le),
+            ">=" => Some(Tok::Ge),
+            "=~" => Some(Tok::Match),
+            _ => None,
+        };
+        if let Some(op) = op {
+            chars.next();
+            chars.next();
+            tokens.push((offset, op));
+            continue
+        }
+        let single = match c {
+            '!' => Some(Tok::Not),
+            '<' => Some(Tok::Lt),
+            '>' => Some(Tok::Gt),
+            '.' => Some(Tok::Dot),
+            ',' => Some(Tok::Comma),
+            '(' => Some(Tok::LParen),
+            ')' => Some(Tok::RParen),
+            '[' => Some(Tok::LBracket),
+            ']' => Some(Tok::RBracket),
+            _ => None,
+        };
+        if let Some(t) = single {
+            chars.next();
+            tokens.push((offset, t));
+        } else if c == '"' || c == '\'' {
+            chars.next();
+            let mut s = String::new();
+            loop {
+                match chars.next() {
+                    Some((_, q)) if q == c => break,
+                    Some((esc, '\\')) => match chars.next() {
+                        Some((_, 'n')) => s.push('\n'),
+                        Some((_, 't')) => s.push('\t'),
+                        Some((_, e @ ('\\' | '"' | '\''))) => s.push(e),
+                        _ => return Err((esc, "invalid escape sequence".into())),
+                    },
+                    Some((_, ch)) => s.push(ch),
+                    None => return Err((offset, "unterminated string".into())),
+                }
+            }
+            tokens.push((offset, Tok::Str(s)));
+        } else if c.is_ascii_digit() || (c == '-' && source[offset + 1..].starts_with(|d: char| d.is_ascii_digit())) {
+            let mut end = offset + c.len_utf8();
+            chars.next();
+            while let Some(&(i, d)) = chars.peek() {
+                if d.is_ascii_digit() || d == '.' {
+                    end = i + 1;
+                    chars.next();
+                } else {
+                    break
+                }
+            }
+            let n = source[offset..end].parse::<f64>()
+                .map_err(|_| (offset, format!("invalid number '{}'", &source[offset..end])))?;
+            tokens.push((offset, Tok::Num(n)));
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            // other claim names are written in brackets, so that `a-b` is
+            // not mistaken for a claim
+            let mut end = offset;
+            while let Some(&(i, d)) = chars.peek() {
+                if d.is_ascii_alphanumeric() || d == '_' {
+                    end = i + 1;
+                    chars.next();
+                } else {
+                    break
+                }
+            }
+            tokens.push((offset, match &source[offset..end] {
+                "true" => Tok::True,
+                "false" => Tok::False,
+                "null" => Tok::Null,
+                "in" => Tok::In,
+                ident => Tok::Ident(ident.to_string()),
+            }));
+        } else {
+            return Err((offset, format!("unexpected character '{}'", c)))
+        }
+    }
+    tokens.push((source.len(), Tok::End));
+    Ok(tokens)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone)]
+enum Kind {
+    Literal(Value),
+    /// a claim, by the keys leading to it
+    Path(Vec<String>),
+    Array(Vec<Node>),
+    Not(Box<Node>),
+    And(Box<Node>, Box<Node>),
+    Or(Box<Node>, Box<Node>),
+    Compare(CmpOp, Box<Node>, Box<Node>),
+    In(Box<Node>, Box<Node>),
+    Match(Box<Node>, Regex),
+}
+
+#[derive(Debug, Clone)]
+struct Node {
+    /// byte offset in the source
+    offset: usize,
+    kind: Kind,
+}
+
+struct Parser {
+    tokens: Vec<(usize, Tok)>,
+    index: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn parse(&mut self) -> Result<Node, PosError> {
+        let node = self.or()?;
+        match self.peek() {
+            (_, Tok::End) => Ok(node),
+            (offset, t) => Err((offset, format!("unexpected {}", t))),
+        }
+    }
+
+    fn peek(&self) -> (usize, Tok) {
+        self.tokens[self.index].clone()
+    }
+
+    fn next(&mut self) -> (usize, Tok) {
+        let t = self.peek();
+        if t.1 != Tok::End {
+            self.index += 1;
+        }
+        t
+    }
+
+    fn expect(&mut self, expected: Tok) -> Result<(), PosError> {
+        match self.next() {
+            (_, t) if t == expected => Ok(()),
+            (offset, t) => Err((offset, format!("expected {}, found {}", expected, t))),
+        }
+    }
+
+    /// guards the recursion, so deeply nested expressions cannot overflow the stack
+    fn nested<T>(&mut self, offset: usize, f: impl FnOnce(&mut Self) -> Result<T, PosError>) -> Result<T, PosError> {
+        self.descend(offset)?;
+        let result = f(self);
+        self.depth -= 1;
+        result
+    }
+
+    /// counts one more level of the tree towards MAX_DEPTH
+    fn descend(&mut self, offset: usize) -> Result<(), PosError> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return Err((offset, format!("expression is nested deeper than {} levels", MAX_DEPTH)))
+        }
+        Ok(())
+    }
+
+    /// `a || b || c` is parsed into `(a || b) || c`, so every operator adds a
+    /// level to the tree that evaluation recurses into, like parentheses do
+    fn or(&mut self) -> Result<Node, PosError> {
+        let depth = self.depth;
+        let mut left = self.and()?;
+        while let (offset, Tok::Or) = self.peek() {
+            self.next();
+            self.descend(offset)?;
+            let right = self.and()?;
+            left = Node { offset, kind: Kind::Or(Box::new(left), Box::new(right)) };
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    /// like [`Parser::or`]
+    fn and(&mut self) -> Result<Node, PosError> {
+        let depth = self.depth;
+        let mut left = self.not()?;
+        while let (offset, Tok::And) = self.peek() {
+            self.next();
+            self.descend(offset)?;
+            let right = self.not()?;
+            left = Node { offset, kind: Kind::And(Box::new(left), Box::new(right)) };
+        }
+        self.depth = depth;
+        Ok(left)
+    }
+
+    fn not(&mut self) -> Result<Node, PosError> {
+        match self.peek() {
+            (offset, Tok::Not) => {
+                self.next();
+                let operand = self.nested(offset, Self::not)?;
+                Ok(Node { offset, kind: Kind::Not(Box::new(operand)) })
+            },
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Node, PosError> {
+        let left = self.primary()?;
+        let (offset, op) = self.peek();
+        let op = match op {
+            Tok::Eq => CmpOp::Eq,
+            Tok::Ne => CmpOp::Ne,
+            Tok::Lt => CmpOp::Lt,
+            Tok::Le => CmpOp::Le,
+            Tok::Gt => CmpOp::Gt,
+            Tok::Ge => CmpOp::Ge,
+            Tok::In => {
+                self.next();
+                let right = self.primary()?;
+                return self.unchained(Node { offset, kind: Kind::In(Box::new(left), Box::new(right)) })
+            },
+            Tok::Match => {
+                self.next();
+                let regex = match self.next() {
+                    (regex_offset, Tok::Str(s)) => RegexBuilder::new(&s)
+                        .size_limit(MAX_REGEX_SIZE)
+                        .build()
+                        .map_err(|e| (regex_offset, format!("invalid regular expression: {}", e)))?,
+                    (o, t) => return Err((o, format!("expected a regular expression string, found {}", t))),
+                };
+                return self.unchained(Node { offset, kind: Kind::Match(Box::new(left), regex) })
+            },
+            _ => return Ok(left),
+        };
+        self.next();
+        let right = self.primary()?;
+        self.unchained(Node { offset, kind: Kind::Compare(op, Box::new(left), Box::new(right)) })
+    }
+
+    /// rejects `a == b == c`, which is rarely what was meant
+    fn unchained(&mut self, node: Node) -> Result<Node, PosError> {
+        match self.peek() {
+            (offset, Tok::Eq | Tok::Ne | Tok::Lt | Tok::Le | Tok::Gt | Tok::Ge | Tok::In | Tok::Match) =>
+                Err((offset, "comparisons cannot be chained, use parentheses".into())),
+            _ => Ok(node),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Node, PosError> {
+        let (offset, t) = self.next();
+        let kind = match t {
+            Tok::Str(s) => Kind::Literal(Value::String(s)),
+            Tok::Num(n) => Kind::Literal(serde_json::json!(n)),
+            Tok::True => Kind::Literal(Value::Bool(true)),
+            Tok::False => Kind::Literal(Value::Bool(false)),
+            Tok::Null => Kind::Literal(Value::Null),
+            Tok::LParen => {
+                let inner = self.nested(offset, Self::or)?;
+                self.expect(Tok::RParen)?;
+                return Ok(inner)
+            },
+            Tok::LBracket => {
+                let mut elements = Vec::new();
+                if self.peek().1 != Tok::RBracket {
+                    loop {
+                        elements.push(self.nested(offset, Self::primary)?);
+                        if self.peek().1 != Tok::Comma {
+                            break
+                        }
+                        self.next();
+                    }
+                }
+                self.expect(Tok::RBracket)?;
+                Kind::Array(elements)
+            },
+            Tok::Ident(first) => {
+                let mut path = vec![first];
+                loop {
+                    match self.peek().1 {
+                        Tok::Dot => {
+                            self.next();
+                            match self.next() {
+                                (_, Tok::Ident(key)) => path.push(key),
+                                (o, t) => return Err((o, format!("expected a claim name, found {}", t))),
+                            }
+                        },
+                        // for claim names that are not identifiers, like realm_access["my-roles"]
+                        Tok::LBracket => {
+                            self.next();
+                            match self.next() {
+                                (_, Tok::Str(key)) => path.push(key),
+                                (o, t) => return Err((o, format!("expected a claim name string, found {}", t))),
+                            }
+                            self.expect(Tok::RBracket)?;
+                        },
+                        _ => break,
+                    }
+                }
+                Kind::Path(path)
+            },
+            t => return Err((offset, format!("unexpected {}", t))),
+        };
+        Ok(Node { offset, kind })
+    }
+}
+
+/// the type of a node, as far as it is known before evaluation
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Type {
+    Bool,
+    Number,
+    String,
+    Null,
+    Array,
+    /// a claim, which can be of any type
+    Dynamic,
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Type::Bool => "a boolean",
+            Type::Number => "a number",
+            Type::String => "a string",
+            Type::Null => "null",
+            Type::Array => "an array",
+            Type::Dynamic => "a claim",
+        })
+    }
+}
+
+/// determines the type of the node, rejecting operations that can never succeed
+fn check(node: &Node) -> Result<Type, PosError> {
+    let expect = |n: &Node, allowed: &[Type]| -> Result<Type, PosError> {
+        let t = check(n)?;
+        if t == Type::Dynamic || allowed.contains(&t) {
+            Ok(t)
+        } else {
+            let names = allowed.iter().map(Type::to_string).collect::<Vec<_>>();
+            Err((n.offset, format!("expected {}, found {}", names.join(" or "), t)))
+        }
+    };
+    Ok(match &node.kind {
+        Kind::Literal(Value::Bool(_)) => Type::Bool,
+        Kind::Literal(Value::Number(_)) => Type::Number,
+        Kind::Literal(Value::String(_)) => Type::String,
+        Kind::Literal(_) => Type::Null,
+        Kind::Path(_) => Type::Dynamic,
+        Kind::Array(elements) => {
+            for e in elements {
+                check(e)?;
+            }
+            Type::Array
+        },
+        Kind::Not(operand) => {
+            expect(operand, &[Type::Bool])?;
+            Type::Bool
+        },
+        Kind::And(left, right) | Kind::Or(left, right) => {
+            expect(left, &[Type::Bool])?;
+            expect(right, &[Type::Bool])?;
+            Type::Bool
+        },
+        Kind::Compare(op, left, right) => {
+            let allowed: &[Type] = match op {
+                CmpOp::Eq | CmpOp::Ne => &[Type::Bool, Type::Number, Type::String, Type::Null, Type::Array],
+                _ => &[Type::Number, Type::String],
+            };
+            let l = expect(left, allowed)?;
+            let r = expect(right, allowed)?;
+            if l != r && l != Type::Dynamic && r != Type::Dynamic && l != Type::Null && r != Type::Null {
+                return Err((node.offset, format!("cannot compare {} with {}", l, r)))
+            }
+            Type::Bool
+        },
+        Kind::In(left, right) => {
+            let r = expect(right, &[Type::Array, Type::String])?;
+            if r == Type::String {
+                expect(left, &[Type::String])?;
+            } else {
+                check(left)?;
+            }
+            Type::Bool
+        },
+        Kind::Match(left, _) => {
+            expect(left, &[Type::String])?;
+            Type::Bool
+        },
+    })
+}
+
+struct Evaluator<'a> {
+    claims: &'a Value,
+    steps: usize,
+}
+
+impl Evaluator<'_> {
+    fn step(&mut self) -> Result<(), String> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            Err(format!("evaluation took more than {} steps", MAX_STEPS))
+        } else {
+            Ok(())
+        }
+    }
+
+    fn eval(&mut self, node: &Node) -> Result<Value, String> {
+        self.step()?;
+        let is_true = |v: Value| v == Value::Bool(true);
+        Ok(match &node.kind {
+            Kind::Literal(v) => v.clone(),
+            Kind::Path(keys) => keys.iter()
+                .try_fold(self.claims, |v, k| v.get(k))
+                .cloned()
+                .unwrap_or(Value::Null),
+            Kind::Array(elements) => Value::Array(elements.iter()
+                .map(|e| self.eval(e))
+                .collect::<Result<_, _>>()?),
+            Kind::Not(operand) => Value::Bool(!is_true(self.eval(operand)?)),
+            Kind::And(left, right) => Value::Bool(is_true(self.eval(left)?) && is_true(self.eval(right)?)),
+            Kind::Or(left, right) => Value::Bool(is_true(self.eval(left)?) || is_true(self.eval(right)?)),
+            Kind::Compare(op, left, right) => {
+                let (l, r) = (self.eval(left)?, self.eval(right)?);
+                Value::Bool(match op {
+                    CmpOp::Eq => equal(&l, &r),
+                    CmpOp::Ne => !equal(&l, &r),
+                    _ => match ordering(&l, &r) {
+                        Some(o) => match op {
+                            CmpOp::Lt => o.is_lt(),
+                            CmpOp::Le => o.is_le(),
+                            CmpOp::Gt => o.is_gt(),
+                            _ => o.is_ge(),
+                        },
+                        None => false,
+                    },
+                })
+            },
+            Kind::In(left, right) => {
+                let (l, r) = (self.eval(left)?, self.eval(right)?);
+                Value::Bool(match (&l, &r) {
+                    (_, Value::Array(elements)) => {
+                        let mut found = false;
+                        for e in elements {
+                            self.step()?;
+                            if equal(&l, e) {
+                                found = true;
+                                break
+                            }
+                        }
+                        found
+                    },
+                    (Value::String(needle), Value::String(haystack)) => haystack.contains(needle.as_str()),
+                    _ => false,
+                })
+            },
+            Kind::Match(left, regex) => match self.eval(left)? {
+                Value::String(s) => Value::Bool(regex.is_match(&s)),
+                _ => Value::Bool(false),
+            },
+        })
+    }
+}
+
+/// JSON equality, except that numbers are compared by value, so 3 == 3.0
+fn equal(l: &Value, r: &Value) -> bool {
+    match (l.as_f64(), r.as_f64()) {
+        (Some(a), Some(b)) => a == b,
+        _ => l == r,
+    }
+}
+
+fn ordering(l: &Value, r: &Value) -> Option<std::cmp::Ordering> {
+    match (l, r) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, claims: Value) -> bool {
+        Expression::compile(source).unwrap().evaluate(&Claims::from(claims)).unwrap()
+    }
+
+    fn error(source: &str) -> String {
+        Expression::compile(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn evaluates_against_claims() {
+        let claims = serde_json::json!({
+            "realm_access": { "roles": ["wiki_admin", "user"] },
+            "dept": "IT",
+            "level": 3,
+            "email": "jane@inraweb.local",
+        });
+        let policy = r#"'wiki_admin' in realm_access.roles || (dept == "IT" && level >= 3)"#;
+        assert!(eval(policy, claims.clone()));
+        assert!(eval(policy, serde_json::json!({ "dept": "IT", "level": 4 })));
+        assert!(!eval(policy, serde_json::json!({ "dept": "IT", "level": 2 })));
+        assert!(eval(r#"email =~ "@inraweb\\.local$" && !(dept in ["HR", "Sales"])"#, claims.clone()));
+        assert!(eval(r#"realm_access["roles"] != null && level == 3.0"#, claims.clone()));
+        // missing claims are null and never true
+        assert!(!eval("missing.claim", claims.clone()));
+        assert!(!eval("missing < 3", claims.clone()));
+        assert!(eval("!missing", claims));
+    }
+
+    #[test]
+    fn reports_errors_with_positions() {
+        assert_eq!(error("dept == \"IT\" &&"), "1:16: unexpected end of expression");
+        assert_eq!(error("(level > 3"), "1:11: expected ')', found end of expression");
+        assert_eq!(error("level >= true"), "1:10: expected a number or a string, found a boolean");
+        assert_eq!(error("1 == \"1\""), "1:3: cannot compare a number with a string");
+        assert_eq!(error("dept == \"IT\" &&\n  \"yes\""), "2:3: expected a boolean, found a string");
+        assert_eq!(error("a == b == c"), "1:8: comparisons cannot be chained, use parentheses");
+        assert_eq!(error("name =~ \"(\""), "1:9: invalid regular expression: regex parse error:\n    (\n    ^\nerror: unclosed group");
+        assert_eq!(error("3"), "1:1: expected a boolean expression, found a number");
+        assert_eq!(error("a # b"), "1:3: unexpected character '#'");
+        assert_eq!(error("a-b == 1"), "1:2: unexpected character '-'");
+        assert_eq!(error("größe > 1"), "1:3: unexpected character 'ö'");
+    }
+
+    #[test]
+    fn bounds_nesting_and_evaluation() {
+        let deep = "(".repeat(MAX_DEPTH + 1) + "a" + &")".repeat(MAX_DEPTH + 1);
+        assert!(error(&deep).contains("nested deeper than"));
+        // chained operators nest just as deeply
+        let chain = |op: &str, n: usize| vec!["a"; n + 1].join(op);
+        assert!(Expression::compile(&chain(" || ", MAX_DEPTH)).is_ok());
+        assert!(error(&chain(" || ", MAX_DEPTH + 1)).contains("nested deeper than"));
+        assert!(error(&chain(" && ", MAX_DEPTH + 1)).contains("nested deeper than"));
+        // mixed, parentheses and operators count together
+        let mixed = "(".repeat(MAX_DEPTH / 2) + &chain(" && ", MAX_DEPTH / 2 + 1) + &")".repeat(MAX_DEPTH / 2);
+        assert!(error(&mixed).contains("nested deeper than"));
+
+        let claims = Claims::from(serde_json::json!({ "roles": vec!["x"; MAX_STEPS] }));
+        let e = Expression::compile("'y' in roles").unwrap();
+        assert!(e.evaluate(&claims).is_err());
+    }
+}
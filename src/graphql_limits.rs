@@ -0,0 +1,325 @@
+//! Limits for GraphQL requests. Requests are analysed before they are handed
+//! to juniper, which has no notion of query depth or complexity.
+
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use graphql_parser::query::{parse_query, Definition, OperationDefinition, Selection, SelectionSet};
+use juniper::{
+    http::{GraphQLBatchRequest, GraphQLRequest},
+    InputValue,
+};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// fields that are only used for introspecting the schema
+const INTROSPECTION_FIELDS: &[&str] = &["__schema", "__type"];
+/// operations that may run at the same time on a WebSocket connection, unless configured
+const DEFAULT_MAX_OPERATIONS_PER_CONNECTION: usize = 25;
+
+/// GraphQL settings in the serve configuration
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct GraphQLConfig {
+    /// maximum nesting depth of fields in a request. Unlimited if not set
+    pub max_depth: Option<usize>,
+    /// maximum number of fields in a request, fields in fragments counted
+    /// every time the fragment is used. For batches, the fields of all
+    /// requests in the batch are added up. Unlimited if not set
+    pub max_complexity: Option<usize>,
+    /// maximum number of requests in a batch. Unlimited if not set
+    pub max_batch_size: Option<usize>,
+    /// maximum number of operations running at the same time on one
+    /// WebSocket connection. Defaults to 25
+    pub max_operations_per_connection: Option<usize>,
+    /// allows schema introspection (__schema and __type queries).
+    /// Defaults to true in development mode, false otherwise
+    pub introspection: Option<bool>,
+    /// serves GraphiQL at /gql/graphiql. Defaults to true in development mode, false otherwise
+    pub graphiql: Option<bool>,
+    /// serves GraphQL Playground at /gql/playground. Defaults to true in development mode, false otherwise
+    pub playground: Option<bool>,
+    /// seconds after which the execution of a query or mutation is aborted. Unlimited if not set
+    pub timeout_secs: Option<u64>,
+}
+
+impl GraphQLConfig {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        let positive = [
+            ("max_depth", self.max_depth.map(|v| v as u64)),
+            ("max_complexity", self.max_complexity.map(|v| v as u64)),
+            ("max_batch_size", self.max_batch_size.map(|v| v as u64)),
+            ("max_operations_per_connection", self.max_operations_per_connection.map(|v| v as u64)),
+            ("timeout_secs", self.timeout_secs),
+        ];
+        for (key, value) in positive.iter() {
+            if *value == Some(0) {
+                errors.push(format!("'graphql.{}' must be greater than 0", key))
+            }
+        }
+        errors
+    }
+
+    /// resolves the settings, using the mode resweb runs in for defaults
+    pub fn into_limits(self, dev_mode_enabled: bool) -> GraphQLLimits {
+        GraphQLLimits {
+            max_depth: self.max_depth,
+            max_complexity: self.max_complexity,
+            max_batch_size: self.max_batch_size,
+            max_operations_per_connection: self.max_operations_per_connection.unwrap_or(DEFAULT_MAX_OPERATIONS_PER_CONNECTION),
+            introspection: self.introspection.unwrap_or(dev_mode_enabled),
+            graphiql: self.graphiql.unwrap_or(dev_mode_enabled),
+            playground: self.playground.unwrap_or(dev_mode_enabled),
+            timeout: self.timeout_secs.map(Duration::from_secs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GraphQLLimits {
+    pub max_depth: Option<usize>,
+    pub max_complexity: Option<usize>,
+    pub max_batch_size: Option<usize>,
+    pub max_operations_per_connection: usize,
+    pub introspection: bool,
+    pub graphiql: bool,
+    pub playground: bool,
+    pub timeout: Option<Duration>,
+}
+
+impl GraphQLLimits {
+    /// Checks all operations in the query against the limits. Returns a
+    /// message for the client if the query exceeds any of them.
+    pub fn check(&self, query: &str) -> Result<(), String> {
+        self.analyse(query).map(|_| ())
+    }
+
+    /// like [`GraphQLLimits::check`], returning the complexity of the most
+    /// complex operation in the query
+    fn analyse(&self, query: &str) -> Result<usize, String> {
+        let document = parse_query::<&str>(query)
+            .map_err(|e| format!("cannot parse query: {}", e))?;
+
+        let mut analysis = Analysis {
+            fragments: HashMap::new(),
+            costs: HashMap::new(),
+            visiting: HashSet::new(),
+        };
+        for definition in &document.definitions {
+            if let Definition::Fragment(f) = definition {
+                analysis.fragments.insert(f.name, &f.selection_set);
+            }
+        }
+
+        let mut complexity = 0;
+        for definition in &document.definitions {
+            let selection_set = match definition {
+                Definition::Operation(OperationDefinition::SelectionSet(s)) => s,
+                Definition::Operation(OperationDefinition::Query(q)) => &q.selection_set,
+                Definition::Operation(OperationDefinition::Mutation(m)) => &m.selection_set,
+                Definition::Operation(OperationDefinition::Subscription(s)) => &s.selection_set,
+                Definition::Fragment(_) => continue,
+            };
+            let cost = analysis.selection_set(selection_set);
+            if let Some(max) = self.max_depth.filter(|max| cost.depth > *max) {
+                return Err(format!("query depth {} exceeds the maximum of {}", cost.depth, max))
+            }
+            if let Some(max) = self.max_complexity.filter(|max| cost.complexity > *max) {
+                return Err(format!("query complexity {} exceeds the maximum of {}", cost.complexity, max))
+            }
+            if cost.introspection && !self.introspection {
+                return Err("introspection is disabled".into())
+            }
+            complexity = complexity.max(cost.complexity);
+        }
+        Ok(complexity)
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct Cost {
+    depth: usize,
+    complexity: usize,
+    introspection: bool,
+}
+
+impl Cost {
+    /// combines the costs of two selections on the same level
+    fn add(self, other: Cost) -> Cost {
+        Cost {
+            depth: self.depth.max(other.depth),
+            complexity: self.complexity.saturating_add(other.complexity),
+            introspection: self.introspection || other.introspection,
+        }
+    }
+}
+
+struct Analysis<'d, 'a> {
+    fragments: HashMap<&'a str, &'d SelectionSet<'a, &'a str>>,
+    /// fragment costs are computed once, so that fragments spread many
+    /// times do not make the analysis itself expensive
+    costs: HashMap<&'a str, Cost>,
+    /// fragments currently being analysed, to stop on cycles (which juniper
+    /// rejects later anyway)
+    visiting: HashSet<&'a str>,
+}
+
+impl<'d, 'a> Analysis<'d, 'a> {
+    fn selection_set(&mut self, selection_set: &'d SelectionSet<'a, &'a str>) -> Cost {
+        selection_set.items.iter().fold(Cost::default(), |cost, selection| {
+            let selection_cost = match selection {
+                Selection::Field(f) => {
+                    let inner = self.selection_set(&f.selection_set);
+                    Cost {
+                        depth: inner.depth + 1,
+                        complexity: inner.complexity.saturating_add(1),
+                        introspection: inner.introspection || INTROSPECTION_FIELDS.contains(&f.name),
+                    }
+                }
+                Selection::FragmentSpread(s) => self.fragment(s.fragment_name),
+                Selection::InlineFragment(i) => self.selection_set(&i.selection_set),
+            };
+            cost.add(selection_cost)
+        })
+    }
+
+    fn fragment(&mut self, name: &'a str) -> Cost {
+        if let Some(cost) = self.costs.get(name) {
+            return *cost
+        }
+        let selection_set = match self.fragments.get(name) {
+            Some(s) => *s,
+            // unknown fragments are reported by juniper
+            None => return Cost::default(),
+        };
+        if !self.visiting.insert(name) {
+            return Cost::default()
+        }
+        let cost = self.selection_set(selection_set);
+        self.visiting.remove(name);
+        self.costs.insert(name, cost);
+        cost
+    }
+}
+
+/// A GraphQL request as sent by clients. Unlike juniper's request type, the
+/// query can be inspected, so that it can be checked against the limits.
+#[derive(Deserialize, Debug)]
+pub struct Request {
+    pub query: String,
+    #[serde(rename = "operationName")]
+    pub operation_name: Option<String>,
+    pub variables: Option<InputValue>,
+}
+
+impl Request {
+    /// checks the request against the limits and converts it for execution
+    pub fn check(self, limits: &GraphQLLimits) -> Result<GraphQLRequest, String> {
+        limits.check(&self.query)?;
+        Ok(GraphQLRequest::new(self.query, self.operation_name, self.variables))
+    }
+}
+
+/// one or several requests, as sent in the body of POST requests
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+pub enum BatchRequest {
+    Single(Request),
+    Batch(Vec<Request>),
+}
+
+impl BatchRequest {
+    /// checks all requests against the limits and converts them for execution
+    pub fn check(self, limits: &GraphQLLimits) -> Result<GraphQLBatchRequest, String> {
+        Ok(match self {
+            BatchRequest::Single(r) => GraphQLBatchRequest::Single(r.check(limits)?),
+            BatchRequest::Batch(rs) => {
+                if let Some(max) = limits.max_batch_size.filter(|max| rs.len() > *max) {
+                    return Err(format!("batch of {} requests exceeds the maximum of {}", rs.len(), max))
+                }
+                let mut complexity = 0usize;
+                for r in &rs {
+                    complexity = complexity.saturating_add(limits.analyse(&r.query)?);
+                }
+                if let Some(max) = limits.max_complexity.filter(|max| complexity > *max) {
+                    return Err(format!("batch complexity {} exceeds the maximum of {}", complexity, max))
+                }
+                GraphQLBatchRequest::Batch(rs.into_iter()
+                    .map(|r| GraphQLRequest::new(r.query, r.operation_name, r.variables))
+                    .collect()
+                )
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits() -> GraphQLLimits {
+        GraphQLConfig {
+            max_depth: Some(3),
+            max_complexity: Some(5),
+            max_batch_size: Some(3),
+            ..GraphQLConfig::default()
+        }.into_limits(false)
+    }
+
+    #[test]
+    fn accepts_queries_within_limits() {
+        assert_eq!(limits().check("{ sites { name claimRules { path } } }"), Ok(()));
+    }
+
+    #[test]
+    fn rejects_deep_queries() {
+        let r = limits().check("{ a { b { c { d } } } }");
+        assert_eq!(r, Err("query depth 4 exceeds the maximum of 3".into()));
+    }
+
+    #[test]
+    fn counts_fragments_each_time_they_are_spread() {
+        let q = "{ a { ...F } b { ...F } } fragment F on T { x y }";
+        assert_eq!(limits().check(q), Err("query complexity 6 exceeds the maximum of 5".into()));
+    }
+
+    #[test]
+    fn fragment_depth_adds_to_spread_depth() {
+        let q = "query { a { ... on T { ...F } } } fragment F on T { b { c } }";
+        assert_eq!(limits().check(q), Ok(()));
+        let q = "query { a { x { ...F } } } fragment F on T { b { c } }";
+        assert_eq!(limits().check(q), Err("query depth 4 exceeds the maximum of 3".into()));
+    }
+
+    fn batch(queries: &[&str]) -> BatchRequest {
+        BatchRequest::Batch(queries.iter()
+            .map(|q| Request { query: q.to_string(), operation_name: None, variables: None })
+            .collect())
+    }
+
+    #[test]
+    fn limits_batches() {
+        assert!(batch(&["{ a }", "{ b }", "{ c d }"]).check(&limits()).is_ok());
+        let r = batch(&["{ a }", "{ b }", "{ c }", "{ d }"]).check(&limits());
+        assert_eq!(r.err(), Some("batch of 4 requests exceeds the maximum of 3".into()));
+        // every request is within the limit, but together they exceed it
+        let r = batch(&["{ a b c }", "{ d e f }"]).check(&limits());
+        assert_eq!(r.err(), Some("batch complexity 6 exceeds the maximum of 5".into()));
+        let r = batch(&["{ a }", "{ a { b { c { d } } } }"]).check(&limits());
+        assert_eq!(r.err(), Some("query depth 4 exceeds the maximum of 3".into()));
+    }
+
+    #[test]
+    fn survives_fragment_cycles() {
+        let q = "{ ...A } fragment A on Q { a ...B } fragment B on Q { b ...A }";
+        assert_eq!(limits().check(q), Ok(()));
+    }
+
+    #[test]
+    fn introspection_depends_on_mode() {
+        let q = "{ __schema { types { name } } }";
+        assert_eq!(limits().check(q), Err("introspection is disabled".into()));
+        assert_eq!(GraphQLConfig::default().into_limits(true).check(q), Ok(()));
+        assert_eq!(limits().check("{ __typename }"), Ok(()));
+    }
+}
@@ -1,33 +1,820 @@
+
+use std::{pin::Pin, sync::Arc};
+
+use actix_web::web;
+use futures_util::stream::Stream;
 use juniper::{
-    graphql_object, EmptySubscription, 
-    GraphQLObject, EmptyMutation, RootNode
+    graphql_object, graphql_subscription, FieldResult,
+    GraphQLInputObject, GraphQLObject, RootNode
 };
 
+use crate::announcements::{Announcement, AnnouncementStore, Severity};
+use crate::auth::Claims;
+use crate::expr::Expression;
+use crate::health::{HealthMonitor, SiteHealth};
+use crate::site::{ClaimRule, HealthCheck, Operand, Operator, RuleSet, Site, VariableCondition};
+use crate::site_store::SiteStore;
+use crate::user_store::UserStore;
+use crate::ServeConfig;
+
 #[derive(GraphQLObject)]
-#[graphql(description = "A resource that we manage")]
-struct Resource {
-    id: i32,
-    name: String,
+#[graphql(description = "The user the access token was issued for")]
+struct User {
+    /// subject identifier
+    sub: Option<String>,
+    name: Option<String>,
+    preferred_username: Option<String>,
+    email: Option<String>,
+    /// all claims of the access token, JSON encoded
+    claims: String,
+}
+
+#[derive(Clone)]
+pub struct Context {
+    /// claims of the validated access token, None if auth is disabled
+    pub claims: Option<Claims>,
+    /// the sites visible to the calling user
+    pub sites: Vec<Site>,
+    /// true if the calling user matches the admin claim rules
+    pub is_admin: bool,
+    pub site_store: Arc<SiteStore>,
+    pub health_monitor: Arc<HealthMonitor>,
+    pub announcement_store: Arc<AnnouncementStore>,
+    pub user_store: Arc<UserStore>,
+    pub serve_config: web::Data<ServeConfig>,
 }
 
-pub struct Context {}
+impl Context {
+    /// a context of an anonymous user, with in-memory stores
+    #[cfg(test)]
+    pub fn for_tests(serve_config: ServeConfig) -> Self {
+        Context {
+            claims: None,
+            sites: Vec::new(),
+            is_admin: false,
+            site_store: Arc::new(SiteStore::open(serve_config.site_list.clone(), None).unwrap()),
+            health_monitor: Arc::new(HealthMonitor::new()),
+            announcement_store: Arc::new(AnnouncementStore::open(Vec::new(), None).unwrap()),
+            user_store: Arc::new(UserStore::open(None).unwrap()),
+            serve_config: web::Data::new(serve_config),
+        }
+    }
+
+    /// determines the sites visible to the calling user from the current
+    /// contents of the site store
+    fn current_sites(&self) -> Vec<Site> {
+        let all_sites = self.site_store.sites();
+        crate::visible_sites(&self.serve_config, &all_sites, self.claims.as_ref())
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    /// the active announcements for the calling user, without the ones
+    /// they dismissed unless `include_dismissed` is set
+    fn current_announcements(&self, include_dismissed: bool) -> Vec<Announcement> {
+        let dismissed = match self.claims.as_ref().and_then(Claims::sub) {
+            Some(sub) if !include_dismissed => self.user_store.dismissed_announcements(sub),
+            _ => Vec::new(),
+        };
+        let sites = self.sites.iter().collect::<Vec<_>>();
+        let all = self.announcement_store.announcements();
+        crate::visible_announcements(&self.serve_config, &all, &sites, self.claims.as_ref(), &dismissed)
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    fn require_admin(&self) -> FieldResult<()> {
+        if self.is_admin {
+            Ok(())
+        } else {
+            Err("managing sites, rule sets and announcements requires admin permissions".into())
+        }
+    }
+}
 
 impl juniper::Context for Context{
 }
 
+#[graphql_object(context=Context, description="A site linked from the dashboard")]
+impl Site {
+    fn name(&self) -> &str {
+        Site::name(self)
+    }
+
+    fn description(&self) -> Option<&str> {
+        Site::description(self)
+    }
+
+    fn url(&self) -> &str {
+        Site::url(self)
+    }
+
+    fn category(&self) -> Option<&str> {
+        Site::category(self)
+    }
+
+    fn tags(&self) -> &[String] {
+        Site::tags(self)
+    }
+
+    /// alternative names and terms the site can be found by
+    fn keywords(&self) -> &[String] {
+        Site::keywords(self)
+    }
+
+    /// name and description in other languages
+    fn translations(&self) -> Vec<SiteTranslation> {
+        Site::translations(self).iter()
+            .map(|(locale, t)| SiteTranslation {
+                locale: locale.clone(),
+                name: t.name.clone(),
+                description: t.description.clone(),
+            })
+            .collect()
+    }
+
+    /// true if the site is managed through the API, false if it is defined
+    /// in the configuration file
+    fn managed(&self, context: &Context) -> bool {
+        context.site_store.is_managed(self.name())
+    }
+
+    /// the site's claim rules, only visible to admins
+    fn claim_rules(&self, context: &Context) -> Option<&[ClaimRule]> {
+        if context.is_admin {
+            Some(self.claim_rules.as_slice())
+        } else {
+            None
+        }
+    }
+
+    /// names of the rule sets whose rules show the site in addition to its
+    /// claim rules, only visible to admins
+    fn rule_sets(&self, context: &Context) -> Option<&[String]> {
+        if context.is_admin {
+            Some(Site::rule_sets(self))
+        } else {
+            None
+        }
+    }
+
+    /// the expression that shows the site in addition to the claim rules,
+    /// only visible to admins
+    fn when(&self, context: &Context) -> Option<&str> {
+        if context.is_admin {
+            Site::when(self).map(Expression::source)
+        } else {
+            None
+        }
+    }
+
+    /// the site's deny claim rules, only visible to admins
+    fn deny_claim_rules(&self, context: &Context) -> Option<&[ClaimRule]> {
+        if context.is_admin {
+            Some(self.deny_claim_rules.as_slice())
+        } else {
+            None
+        }
+    }
+
+    fn required_scopes(&self) -> &[String] {
+        Site::required_scopes(self)
+    }
+
+    /// the result of the latest health check, null if the site has none
+    fn health(&self, context: &Context) -> Option<SiteHealth> {
+        context.health_monitor.health(self.name())
+    }
+
+    /// RFC 3339 time from which the site is shown
+    fn visible_from(&self) -> Option<String> {
+        Site::visible_from(self).map(|t| t.to_rfc3339())
+    }
+
+    /// RFC 3339 time until which the site is shown
+    fn visible_until(&self) -> Option<String> {
+        Site::visible_until(self).map(|t| t.to_rfc3339())
+    }
+
+    /// the conditions on deployment variables, only visible to admins
+    fn conditions(&self, context: &Context) -> Option<&[VariableCondition]> {
+        if context.is_admin {
+            Some(Site::conditions(self))
+        } else {
+            None
+        }
+    }
+
+    /// how the site's health is checked, only visible to admins
+    fn health_check(&self, context: &Context) -> Option<&HealthCheck> {
+        if context.is_admin {
+            Site::health_check(self)
+        } else {
+            None
+        }
+    }
+}
+
+#[graphql_object(context=Context, description="A named list of claim rules that sites can refer to")]
+impl RuleSet {
+    fn name(&self) -> &str {
+        RuleSet::name(self)
+    }
+
+    fn claim_rules(&self) -> &[ClaimRule] {
+        RuleSet::claim_rules(self)
+    }
+
+    /// true if the rule set is managed through the API, false if it is
+    /// defined in the configuration file
+    fn managed(&self, context: &Context) -> bool {
+        context.site_store.is_managed_rule_set(self.name())
+    }
+}
+
+#[derive(GraphQLObject)]
+#[graphql(description = "The name and description of a site in another language")]
+struct SiteTranslation {
+    /// language tag, like 'de' or 'de-CH'
+    locale: String,
+    /// null if the site's name is used
+    name: Option<String>,
+    /// null if the site's description is used
+    description: Option<String>,
+}
+
+#[graphql_object(context=Context, description="A condition on a deployment variable")]
+impl VariableCondition {
+    fn variable(&self) -> &str {
+        &self.variable
+    }
+
+    /// the value the variable must be equal to, if this condition compares values
+    fn value(&self) -> Option<String> {
+        match &self.operand {
+            Operand::Value{value} => Some(value.as_str().map(String::from).unwrap_or_else(|| value.to_string())),
+            Operand::Regex{..} => None
+        }
+    }
+
+    /// the regular expression the variable must match, if this condition matches regexes
+    fn regex(&self) -> Option<String> {
+        match &self.operand {
+            Operand::Value{..} => None,
+            Operand::Regex{regex} => Some(regex.to_string())
+        }
+    }
+}
+
+#[graphql_object(context=Context, description="How the health of a site is checked")]
+impl HealthCheck {
+    /// the URL requested, null if the site's url is used
+    fn url(&self) -> Option<&str> {
+        self.url.as_deref()
+    }
+
+    /// the HTTP status the site must respond with, null if any status
+    /// below 400 is accepted
+    fn expected_status(&self) -> Option<i32> {
+        self.expected_status.map(i32::from)
+    }
+
+    fn interval_secs(&self) -> i32 {
+        self.interval().as_secs() as i32
+    }
+
+    fn timeout_secs(&self) -> i32 {
+        self.timeout().as_secs() as i32
+    }
+}
+
+#[graphql_object(context=Context, description="A rule checking a claim of the user's access token")]
+impl ClaimRule {
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    fn operator(&self) -> Operator {
+        self.operator.clone()
+    }
+
+    /// the JSON encoded value to compare with, if this rule compares values
+    fn json_value(&self) -> Option<String> {
+        match &self.operand {
+            Operand::Value{value} => Some(value.to_string()),
+            Operand::Regex{..} => None
+        }
+    }
+
+    /// the regular expression to match with, if this rule matches regexes
+    fn regex(&self) -> Option<String> {
+        match &self.operand {
+            Operand::Value{..} => None,
+            Operand::Regex{regex} => Some(regex.to_string())
+        }
+    }
+}
+
+#[graphql_object(context=Context, description="A message shown on top of the dashboard")]
+impl Announcement {
+    fn id(&self) -> &str {
+        Announcement::id(self)
+    }
+
+    fn severity(&self) -> Severity {
+        Announcement::severity(self)
+    }
+
+    fn title(&self) -> Option<&str> {
+        Announcement::title(self)
+    }
+
+    /// the message, in markdown
+    fn body(&self) -> &str {
+        Announcement::body(self)
+    }
+
+    /// the message rendered as HTML
+    fn body_html(&self) -> String {
+        Announcement::body_html(self)
+    }
+
+    /// RFC 3339 time from which the announcement is shown
+    fn starts_at(&self) -> Option<String> {
+        Announcement::starts_at(self).map(|t| t.to_rfc3339())
+    }
+
+    /// RFC 3339 time until which the announcement is shown
+    fn ends_at(&self) -> Option<String> {
+        Announcement::ends_at(self).map(|t| t.to_rfc3339())
+    }
+
+    /// names of the sites the announcement is about
+    fn sites(&self) -> &[String] {
+        Announcement::sites(self)
+    }
+
+    /// true if the announcement is managed through the API, false if it is
+    /// defined in the configuration file
+    fn managed(&self, context: &Context) -> bool {
+        context.announcement_store.is_managed(self.id())
+    }
+
+    /// the announcement's claim rules, only visible to admins. Null if the
+    /// announcement is for all users
+    fn claim_rules(&self, context: &Context) -> Option<&[ClaimRule]> {
+        if context.is_admin {
+            Announcement::claim_rules(self)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A claim rule. Exactly one of value, jsonValue and regex must be given")]
+struct ClaimRuleInput {
+    path: String,
+    operator: Operator,
+    /// a string to compare with
+    value: Option<String>,
+    /// a JSON encoded value to compare with, for comparing non-string values
+    json_value: Option<String>,
+    /// a regular expression to match with
+    regex: Option<String>,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "The name and description of a site in another language")]
+struct SiteTranslationInput {
+    /// language tag, like 'de' or 'de-CH'
+    locale: String,
+    name: Option<String>,
+    description: Option<String>,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A condition on a deployment variable. Exactly one of value and regex must be given")]
+struct VariableConditionInput {
+    variable: String,
+    value: Option<String>,
+    regex: Option<String>,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "How to check whether a site is up")]
+struct HealthCheckInput {
+    /// URL to request, defaults to the site's url
+    url: Option<String>,
+    /// status the site must respond with, defaults to any status below 400
+    expected_status: Option<i32>,
+    /// defaults to 60
+    interval_secs: Option<i32>,
+    /// defaults to 10
+    timeout_secs: Option<i32>,
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A site to create or update")]
+struct SiteInput {
+    name: String,
+    description: Option<String>,
+    url: String,
+    category: Option<String>,
+    tags: Option<Vec<String>>,
+    keywords: Option<Vec<String>>,
+    /// name and description in other languages
+    translations: Option<Vec<SiteTranslationInput>>,
+    required_scopes: Option<Vec<String>>,
+    claim_rules: Option<Vec<ClaimRuleInput>>,
+    /// names of rule sets whose rules show the site in addition to the
+    /// claim rules
+    rule_sets: Option<Vec<String>>,
+    /// expression that shows the site to the users it is true for, in
+    /// addition to the claim rules
+    when: Option<String>,
+    /// rules hiding the site from the users they match, even if claim rules match
+    deny_claim_rules: Option<Vec<ClaimRuleInput>>,
+    health_check: Option<HealthCheckInput>,
+    /// RFC 3339 time from which the site is shown, right away if not set
+    visible_from: Option<String>,
+    /// RFC 3339 time until which the site is shown, until removed if not set
+    visible_until: Option<String>,
+    /// conditions on deployment variables that must all hold
+    conditions: Option<Vec<VariableConditionInput>>,
+}
+
+/// converts claim rule inputs into the JSON the configuration file would contain
+fn claim_rules_json(rules: Vec<ClaimRuleInput>) -> FieldResult<Vec<serde_json::Value>> {
+    let mut claim_rules = Vec::new();
+    for (i, r) in rules.into_iter().enumerate() {
+        let operand = match (r.value, r.json_value, r.regex) {
+            (Some(v), None, None) => serde_json::json!({"value": v}),
+            (None, Some(v), None) => match serde_json::from_str::<serde_json::Value>(&v) {
+                Ok(v) => serde_json::json!({"value": v}),
+                Err(e) => return Err(format!("claim rule #{}: invalid jsonValue: {}", i + 1, e).into())
+            },
+            (None, None, Some(regex)) => match regex::Regex::new(&regex) {
+                Ok(_) => serde_json::json!({"regex": regex}),
+                Err(e) => return Err(format!("claim rule #{}: invalid regex: {}", i + 1, e).into())
+            },
+            _ => return Err(format!("claim rule #{}: exactly one of value, jsonValue and regex must be given", i + 1).into())
+        };
+        claim_rules.push(serde_json::json!({
+            "path": r.path,
+            "operator": r.operator,
+            "operand": operand,
+        }));
+    }
+    Ok(claim_rules)
+}
+
+impl SiteInput {
+    /// converts the input into a site the same way the configuration file
+    /// is read, so that the same rules apply
+    fn into_site(self) -> FieldResult<Site> {
+        let claim_rules = claim_rules_json(self.claim_rules.unwrap_or_default())?;
+        if let Some(w) = &self.when {
+            if let Err(e) = Expression::compile(w) {
+                return Err(format!("invalid when expression at {}", e).into())
+            }
+        }
+        let deny_claim_rules = claim_rules_json(self.deny_claim_rules.unwrap_or_default())?;
+        let mut conditions = Vec::new();
+        for (i, c) in self.conditions.unwrap_or_default().into_iter().enumerate() {
+            conditions.push(match (c.value, c.regex) {
+                (Some(v), None) => serde_json::json!({"variable": c.variable, "value": v}),
+                (None, Some(regex)) => match regex::Regex::new(&regex) {
+                    Ok(_) => serde_json::json!({"variable": c.variable, "regex": regex}),
+                    Err(e) => return Err(format!("condition #{}: invalid regex: {}", i + 1, e).into())
+                },
+                _ => return Err(format!("condition #{}: exactly one of value and regex must be given", i + 1).into())
+            });
+        }
+        let mut translations = serde_json::Map::new();
+        for t in self.translations.unwrap_or_default() {
+            if translations.contains_key(&t.locale) {
+                return Err(format!("translation locale '{}' is given more than once", t.locale).into())
+            }
+            translations.insert(t.locale, serde_json::json!({"name": t.name, "description": t.description}));
+        }
+        let health_check = self.health_check.map(|h| serde_json::json!({
+            "url": h.url,
+            "expected_status": h.expected_status,
+            "interval_secs": h.interval_secs,
+            "timeout_secs": h.timeout_secs,
+        }));
+        let site = serde_json::json!({
+            "name": self.name,
+            "description": self.description,
+            "url": self.url,
+            "category": self.category,
+            "tags": self.tags.unwrap_or_default(),
+            "keywords": self.keywords.unwrap_or_default(),
+            "translations": translations,
+            "required_scopes": self.required_scopes.unwrap_or_default(),
+            "claim_rules": claim_rules,
+            "rule_sets": self.rule_sets.unwrap_or_default(),
+            "when": self.when,
+            "deny_claim_rules": deny_claim_rules,
+            "health_check": health_check,
+            "visible_from": self.visible_from,
+            "visible_until": self.visible_until,
+            "conditions": conditions,
+        });
+        Ok(serde_json::from_value(site)?)
+    }
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "A rule set to create or update")]
+struct RuleSetInput {
+    name: String,
+    claim_rules: Vec<ClaimRuleInput>,
+}
+
+impl RuleSetInput {
+    /// converts the input the same way the configuration file is read
+    fn into_rule_set(self) -> FieldResult<RuleSet> {
+        let rule_set = serde_json::json!({
+            "name": self.name,
+            "claim_rules": claim_rules_json(self.claim_rules)?,
+        });
+        Ok(serde_json::from_value(rule_set)?)
+    }
+}
+
+#[derive(GraphQLInputObject)]
+#[graphql(description = "An announcement to create or update")]
+struct AnnouncementInput {
+    id: String,
+    /// defaults to INFO
+    severity: Option<Severity>,
+    title: Option<String>,
+    /// the message, in markdown
+    body: String,
+    /// RFC 3339 time from which the announcement is shown, right away if not set
+    starts_at: Option<String>,
+    /// RFC 3339 time until which the announcement is shown, until removed if not set
+    ends_at: Option<String>,
+    /// if not set, the announcement is shown to all users
+    claim_rules: Option<Vec<ClaimRuleInput>>,
+    /// names of the sites the announcement is about
+    sites: Option<Vec<String>>,
+}
+
+impl AnnouncementInput {
+    /// converts the input the same way the configuration file is read
+    fn into_announcement(self) -> FieldResult<Announcement> {
+        let claim_rules = match self.claim_rules {
+            Some(rules) => Some(claim_rules_json(rules)?),
+            None => None,
+        };
+        let announcement = serde_json::json!({
+            "id": self.id,
+            "severity": self.severity.unwrap_or_default(),
+            "title": self.title,
+            "body": self.body,
+            "starts_at": self.starts_at,
+            "ends_at": self.ends_at,
+            "claim_rules": claim_rules,
+            "sites": self.sites.unwrap_or_default(),
+        });
+        Ok(serde_json::from_value(announcement)?)
+    }
+}
+
 pub struct Query;
 
 #[graphql_object(context=Context)]
 impl Query {
-   fn resources() -> Vec<Resource> {
-        vec![
-            Resource {
-                id: 1,
-                name: "Uwe".into()
-            }
-        ]
+    /// the calling user, null if authentication is disabled
+    fn me(context: &Context) -> Option<User> {
+        let claims = context.claims.as_ref()?;
+        let get = |path: &str| claims.get_path(path)
+            .and_then(|v| v.as_str())
+            .map(String::from);
+        Some(User {
+            sub: get("sub"),
+            name: get("name"),
+            preferred_username: get("preferred_username"),
+            email: get("email"),
+            claims: claims.value().to_string(),
+        })
+    }
+
+    /// the sites visible to the calling user, optionally filtered. `search`
+    /// matches case-insensitively on name and description
+    fn sites(context: &Context, category: Option<String>, tag: Option<String>, search: Option<String>) -> Vec<Site> {
+        let search = search.map(|s| s.to_lowercase());
+        context.sites.iter()
+            .filter(|site| category.is_none() || site.category() == category.as_deref())
+            .filter(|site| tag.as_ref().is_none_or(|t| site.tags().contains(t)))
+            .filter(|site| search.as_ref().is_none_or(|s| 
+                site.name().to_lowercase().contains(s)
+                || site.description().is_some_and(|d| d.to_lowercase().contains(s))
+            ))
+            .cloned()
+            .collect()
+    }
+
+    /// the visible sites matching the query, best matches first. Matches
+    /// name, keywords, category and description, tolerating small typos
+    fn search(context: &Context, query: String) -> Vec<Site> {
+        let sites = context.sites.iter().collect::<Vec<_>>();
+        crate::search::search(&sites, &query)
+            .into_iter()
+            .map(|hit| hit.site.clone())
+            .collect()
+    }
+
+    /// the site with the given name, if it is visible to the calling user
+    fn site(context: &Context, name: String) -> Option<Site> {
+        context.sites.iter()
+            .find(|site| site.name() == name)
+            .cloned()
+    }
+
+    /// all sites regardless of their claim rules, only available to admins
+    fn all_sites(context: &Context) -> FieldResult<Vec<Site>> {
+        context.require_admin()?;
+        Ok(context.site_store.sites().to_vec())
+    }
+
+    /// all rule sets, only available to admins
+    fn all_rule_sets(context: &Context) -> FieldResult<Vec<RuleSet>> {
+        context.require_admin()?;
+        Ok(context.site_store.rule_sets())
+    }
+
+    /// the active announcements for the calling user, most severe first.
+    /// Dismissed announcements are left out unless `includeDismissed` is set
+    fn announcements(context: &Context, include_dismissed: Option<bool>) -> Vec<Announcement> {
+        context.current_announcements(include_dismissed.unwrap_or(false))
+    }
+
+    /// all announcements regardless of their time and claim rules, only
+    /// available to admins
+    fn all_announcements(context: &Context) -> FieldResult<Vec<Announcement>> {
+        context.require_admin()?;
+        Ok(context.announcement_store.announcements().to_vec())
     }
 }
 
-pub type Schema = RootNode<'static, Query, EmptyMutation<Context>, EmptySubscription<Context>>;
+pub struct Mutation;
+
+/// Mutations for managing sites, rule sets and announcements. Only those created
+/// through the API can be changed, the ones from the configuration file
+/// are read-only.
+#[graphql_object(context=Context)]
+impl Mutation {
+    fn create_site(context: &Context, site: SiteInput) -> FieldResult<Site> {
+        context.require_admin()?;
+        Ok(context.site_store.create(site.into_site()?)?)
+    }
+
+    /// replaces the site with the given name
+    fn update_site(context: &Context, name: String, site: SiteInput) -> FieldResult<Site> {
+        context.require_admin()?;
+        Ok(context.site_store.update(&name, site.into_site()?)?)
+    }
+
+    fn delete_site(context: &Context, name: String) -> FieldResult<bool> {
+        context.require_admin()?;
+        context.site_store.delete(&name)?;
+        Ok(true)
+    }
+
+    /// puts the managed sites into the given order, which must contain the
+    /// names of all managed sites. Managed sites are always listed after the
+    /// sites from the configuration file.
+    fn reorder_sites(context: &Context, names: Vec<String>) -> FieldResult<Vec<Site>> {
+        context.require_admin()?;
+        context.site_store.reorder(&names)?;
+        Ok(context.site_store.sites().to_vec())
+    }
+
+    fn create_rule_set(context: &Context, rule_set: RuleSetInput) -> FieldResult<RuleSet> {
+        context.require_admin()?;
+        Ok(context.site_store.create_rule_set(rule_set.into_rule_set()?)?)
+    }
+
+    /// replaces the rule set with the given name. Sites referring to it use
+    /// the new rules right away; it can only be renamed if no site refers to it
+    fn update_rule_set(context: &Context, name: String, rule_set: RuleSetInput) -> FieldResult<RuleSet> {
+        context.require_admin()?;
+        Ok(context.site_store.update_rule_set(&name, rule_set.into_rule_set()?)?)
+    }
+
+    /// deletes the rule set with the given name, which no site may refer to
+    fn delete_rule_set(context: &Context, name: String) -> FieldResult<bool> {
+        context.require_admin()?;
+        context.site_store.delete_rule_set(&name)?;
+        Ok(true)
+    }
 
+    fn create_announcement(context: &Context, announcement: AnnouncementInput) -> FieldResult<Announcement> {
+        context.require_admin()?;
+        Ok(context.announcement_store.create(announcement.into_announcement()?)?)
+    }
+
+    /// replaces the announcement with the given id
+    fn update_announcement(context: &Context, id: String, announcement: AnnouncementInput) -> FieldResult<Announcement> {
+        context.require_admin()?;
+        Ok(context.announcement_store.update(&id, announcement.into_announcement()?)?)
+    }
+
+    fn delete_announcement(context: &Context, id: String) -> FieldResult<bool> {
+        context.require_admin()?;
+        context.announcement_store.delete(&id)?;
+        Ok(true)
+    }
+
+    /// hides the announcement from the calling user. Requires a user, so it
+    /// is not available with authentication disabled
+    async fn dismiss_announcement(context: &Context, id: String) -> FieldResult<bool> {
+        let sub = match context.claims.as_ref().and_then(Claims::sub) {
+            Some(sub) => sub,
+            None => return Err("dismissing announcements requires a user".into()),
+        };
+        let visible = context.current_announcements(true);
+        if !visible.iter().any(|a| a.id() == id) {
+            return Err(format!("there is no announcement with id '{}'", id).into())
+        }
+        let existing = visible.iter().map(Announcement::id).collect::<Vec<_>>();
+        context.user_store.dismiss_announcement(sub, &id, &existing).await?;
+        Ok(true)
+    }
+}
+
+pub struct Subscription;
+
+type SitesStream = Pin<Box<dyn Stream<Item = Vec<Site>> + Send>>;
+
+#[graphql_subscription(context=Context)]
+impl Subscription {
+    /// the sites visible to the calling user, sent every time the site list
+    /// is changed
+    async fn sites_changed(context: &Context) -> SitesStream {
+        let context = context.clone();
+        // a lagging receiver has only missed notifications, not data, so
+        // any item (including a lag error) means: send the current sites
+        let changes = context.site_store.subscribe();
+        Box::pin(changes.map(move |_| context.current_sites()))
+    }
+}
+
+pub type Schema = RootNode<'static, Query, Mutation, Subscription>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use juniper::{EmptySubscription, Variables};
+
+    fn context(is_admin: bool) -> Context {
+        let serve_config = crate::cli::serve_config_from_yaml(r#"
+port: 8081
+interface_addresses: [127.0.0.1]
+site_list:
+  sites:
+  - name: Wiki
+    url: https://wiki.example
+"#);
+        Context { is_admin, ..Context::for_tests(serve_config) }
+    }
+
+    /// runs the operation, returning the error messages
+    fn execute(operation: &str, context: &Context) -> Vec<String> {
+        let schema = RootNode::new(Query, Mutation, EmptySubscription::<Context>::new());
+        let (_, errors) = juniper::execute_sync(operation, None, &schema, &Variables::new(), context).unwrap();
+        errors.iter().map(|e| e.error().message().to_string()).collect()
+    }
+
+    #[test]
+    fn only_admins_manage_sites_rule_sets_and_announcements() {
+        let mutations = [
+            r#"mutation { createSite(site: { name: "Mail", url: "https://mail.example" }) { name } }"#,
+            r#"mutation { updateSite(name: "Mail", site: { name: "Mail", url: "https://mail.example" }) { name } }"#,
+            r#"mutation { reorderSites(names: ["Mail"]) { name } }"#,
+            r#"mutation { deleteSite(name: "Mail") }"#,
+            r#"mutation { createRuleSet(ruleSet: { name: "ops", claimRules: [] }) { name } }"#,
+            r#"mutation { updateRuleSet(name: "ops", ruleSet: { name: "ops", claimRules: [] }) { name } }"#,
+            r#"mutation { deleteRuleSet(name: "ops") }"#,
+            r#"mutation { deleteAnnouncement(id: "x") }"#,
+            r#"{ allSites { name } }"#,
+            r#"{ allRuleSets { name } }"#,
+        ];
+        let user = context(false);
+        for m in mutations.iter() {
+            assert_eq!(execute(m, &user), vec!["managing sites, rule sets and announcements requires admin permissions"], "{}", m);
+        }
+        assert_eq!(user.site_store.sites().len(), 1);
+
+        let admin = context(true);
+        for m in mutations[..7].iter().chain(&mutations[8..]) {
+            assert_eq!(execute(m, &admin), Vec::<String>::new(), "{}", m);
+        }
+    }
+}
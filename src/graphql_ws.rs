@@ -0,0 +1,375 @@
+//! GraphQL over WebSocket, speaking the `graphql-ws` sub-protocol as defined by
+//! [subscriptions-transport-ws](https://github.com/apollographql/subscriptions-transport-ws/blob/master/PROTOCOL.md).
+//! Each connection is handled by a session actor, which runs every started
+//! operation as a future of its own, so operations can be stopped independently.
+//! Connections are authorized by the access token they were opened with, so
+//! they are closed when that token expires.
+
+use std::{collections::HashMap, sync::Arc};
+
+use actix::{
+    Actor, ActorContext, AsyncContext, Handler, Message, SpawnHandle,
+    StreamHandler, WrapFuture,
+};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web_actors::ws;
+use chrono::Utc;
+use futures_util::stream::{self, StreamExt};
+use juniper::{GraphQLError, Object, Value};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::graphql_limits;
+use crate::graphql_schema::{Context, Schema};
+
+/// the WebSocket sub-protocol clients must request
+pub const PROTOCOL: &str = "graphql-ws";
+
+/// messages sent by the client
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ClientMessage {
+    ConnectionInit {},
+    Start { id: String, payload: graphql_limits::Request },
+    Stop { id: String },
+    ConnectionTerminate {},
+}
+
+/// a JSON encoded protocol message to be sent to the client
+#[derive(Message)]
+#[rtype(result = "()")]
+struct ServerMessage(String);
+
+/// sent to the session by an operation's future when it has run to completion
+#[derive(Message)]
+#[rtype(result = "()")]
+struct OperationFinished(String);
+
+pub struct GraphQLWsSession {
+    schema: web::Data<Schema>,
+    context: Arc<Context>,
+    /// the running operations, by the id the client assigned to them
+    operations: HashMap<String, SpawnHandle>,
+}
+
+impl GraphQLWsSession {
+    pub fn new(schema: web::Data<Schema>, context: Context) -> Self {
+        GraphQLWsSession {
+            schema,
+            context: Arc::new(context),
+            operations: HashMap::new(),
+        }
+    }
+
+    fn send(ctx: &mut ws::WebsocketContext<Self>, msg: serde_json::Value) {
+        ctx.text(msg.to_string());
+    }
+
+    fn handle_client_message(&mut self, text: &str, ctx: &mut ws::WebsocketContext<Self>) {
+        let msg = match serde_json::from_str::<ClientMessage>(text) {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::warn!("invalid graphql-ws message received: {}", e);
+                Self::send(ctx, json!({
+                    "type": "connection_error",
+                    "payload": { "message": format!("invalid message: {}", e) },
+                }));
+                return;
+            }
+        };
+        match msg {
+            ClientMessage::ConnectionInit {} => {
+                Self::send(ctx, json!({ "type": "connection_ack" }));
+            }
+            ClientMessage::Start { id, payload } => {
+                let max_operations = self.context.serve_config.graphql.max_operations_per_connection;
+                if self.operations.len() >= max_operations {
+                    Self::send(ctx, json!({
+                        "id": id,
+                        "type": "error",
+                        "payload": { "message": format!("too many operations, at most {} may run at the same time on a connection", max_operations) },
+                    }));
+                    return;
+                }
+                if self.operations.contains_key(&id) {
+                    Self::send(ctx, json!({
+                        "id": id,
+                        "type": "error",
+                        "payload": { "message": "an operation with this id is already running" },
+                    }));
+                    return;
+                }
+                let request = match payload.check(&self.context.serve_config.graphql) {
+                    Ok(r) => r,
+                    Err(message) => {
+                        Self::send(ctx, json!({
+                            "id": id,
+                            "type": "error",
+                            "payload": { "message": message },
+                        }));
+                        return;
+                    }
+                };
+                let operation = self.run_operation(id.clone(), request, ctx);
+                let handle = ctx.spawn(operation.into_actor(self));
+                self.operations.insert(id, handle);
+            }
+            ClientMessage::Stop { id } => {
+                if let Some(handle) = self.operations.remove(&id) {
+                    ctx.cancel_future(handle);
+                    Self::send(ctx, json!({ "id": id, "type": "complete" }));
+                }
+            }
+            ClientMessage::ConnectionTerminate {} => {
+                ctx.stop();
+            }
+        }
+    }
+
+    /// returns a future executing the given request and sending its results
+    /// to the client. Queries and mutations yield a single result and are
+    /// subject to the configured timeout, subscriptions yield one per event.
+    fn run_operation(&self, id: String, request: juniper::http::GraphQLRequest, ctx: &mut ws::WebsocketContext<Self>) -> impl std::future::Future<Output = ()> {
+        let schema = self.schema.clone();
+        let context = self.context.clone();
+        let addr = ctx.address();
+        async move {
+            let send = |msg: serde_json::Value| addr.do_send(ServerMessage(msg.to_string()));
+
+            match juniper::http::resolve_into_stream(&request, &schema, &context).await {
+                Ok((Value::Object(fields), _)) => {
+                    // every root field of a subscription is a stream of its
+                    // own; each event is sent as a result containing that field
+                    let streams = Object::into_iter(fields)
+                        .filter_map(|(name, value)| match value {
+                            Value::Scalar(s) => Some(s.map(move |item| (name.clone(), item))),
+                            _ => None,
+                        });
+                    let mut events = stream::select_all(streams);
+                    while let Some((name, item)) = events.next().await {
+                        let payload = match item {
+                            Ok(value) => {
+                                let mut data = Object::with_capacity(1);
+                                data.add_field(name, value);
+                                json!({ "data": Value::Object(data) })
+                            }
+                            Err(e) => json!({ "data": null, "errors": [e] }),
+                        };
+                        send(json!({ "id": id, "type": "data", "payload": payload }));
+                    }
+                }
+                Ok(_) => {
+                    send(json!({
+                        "id": id,
+                        "type": "error",
+                        "payload": { "message": "subscription did not resolve to an object" },
+                    }));
+                }
+                Err(GraphQLError::NotSubscription) => {
+                    let execution = request.execute(&schema, &context);
+                    let response = match context.serve_config.graphql.timeout {
+                        Some(timeout) => tokio::time::timeout(timeout, execution).await.ok(),
+                        None => Some(execution.await),
+                    };
+                    match response {
+                        Some(response) => send(json!({ "id": id, "type": "data", "payload": response })),
+                        None => send(json!({
+                            "id": id,
+                            "type": "error",
+                            "payload": { "message": "request timed out" },
+                        })),
+                    }
+                }
+                Err(e) => {
+                    send(json!({ "id": id, "type": "error", "payload": e }));
+                }
+            }
+            addr.do_send(OperationFinished(id));
+        }
+    }
+}
+
+impl Actor for GraphQLWsSession {
+    type Context = ws::WebsocketContext<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        let expires_at = self.context.claims.as_ref().and_then(|c| c.expires_at());
+        if let Some(expires_at) = expires_at {
+            // to_std fails for tokens that already expired, which closes the
+            // connection right away
+            let remaining = (expires_at - Utc::now()).to_std().unwrap_or_default();
+            ctx.run_later(remaining, |_, ctx| {
+                Self::send(ctx, json!({
+                    "type": "connection_error",
+                    "payload": { "message": "the access token expired" },
+                }));
+                ctx.close(Some(ws::CloseReason {
+                    code: ws::CloseCode::Policy,
+                    description: Some("access token expired".into()),
+                }));
+                ctx.stop();
+            });
+        }
+    }
+}
+
+impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for GraphQLWsSession {
+    fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        match msg {
+            Ok(ws::Message::Text(text)) => self.handle_client_message(&text, ctx),
+            Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
+            Ok(ws::Message::Close(reason)) => {
+                ctx.close(reason);
+                ctx.stop();
+            }
+            Ok(_) => (),
+            Err(e) => {
+                log::warn!("graphql-ws protocol error: {}", e);
+                ctx.stop();
+            }
+        }
+    }
+}
+
+impl Handler<ServerMessage> for GraphQLWsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: ServerMessage, ctx: &mut Self::Context) {
+        ctx.text(msg.0);
+    }
+}
+
+impl Handler<OperationFinished> for GraphQLWsSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: OperationFinished, ctx: &mut Self::Context) {
+        // if the operation is gone, the client stopped it and was already told
+        if self.operations.remove(&msg.0).is_some() {
+            Self::send(ctx, json!({ "id": msg.0, "type": "complete" }));
+        }
+    }
+}
+
+/// upgrades the request to a WebSocket connection handled by a new session
+pub fn start(req: &HttpRequest, stream: web::Payload, schema: web::Data<Schema>, context: Context) -> Result<HttpResponse, Error> {
+    ws::start_with_protocols(GraphQLWsSession::new(schema, context), &[PROTOCOL], req, stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt::Debug;
+
+    use actix_web::{rt::System, test, App};
+    use futures_util::{Sink, SinkExt, Stream};
+
+    use super::*;
+    use crate::auth::Claims;
+    use crate::graphql_schema::{Mutation, Query, Subscription};
+
+    fn context(graphql: &str) -> Context {
+        Context::for_tests(crate::cli::serve_config_from_yaml(&format!(r#"
+port: 8081
+interface_addresses: [127.0.0.1]
+graphql: {}
+site_list:
+  sites:
+  - name: Wiki
+    url: https://wiki.example
+"#, graphql)))
+    }
+
+    /// a server accepting graphql-ws connections at / for the given context
+    fn server(context: Context) -> test::TestServer {
+        test::start(move || {
+            let context = context.clone();
+            App::new()
+                .data(Schema::new(Query, Mutation, Subscription))
+                .route("/", web::get().to(move |req: HttpRequest, payload: web::Payload, schema: web::Data<Schema>| {
+                    let context = context.clone();
+                    async move { start(&req, payload, schema, context) }
+                }))
+        })
+    }
+
+    async fn send<S: Sink<ws::Message> + Unpin>(conn: &mut S, msg: serde_json::Value) where S::Error: Debug {
+        conn.send(ws::Message::Text(msg.to_string())).await.unwrap();
+    }
+
+    async fn receive<S: Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin>(conn: &mut S) -> ws::Frame {
+        tokio::time::timeout(std::time::Duration::from_secs(5), conn.next()).await
+            .expect("no message received")
+            .expect("connection closed")
+            .unwrap()
+    }
+
+    async fn receive_json<S: Stream<Item = Result<ws::Frame, ws::ProtocolError>> + Unpin>(conn: &mut S) -> serde_json::Value {
+        match receive(conn).await {
+            ws::Frame::Text(text) => serde_json::from_slice(&text).unwrap(),
+            frame => panic!("expected a text frame, got {:?}", frame),
+        }
+    }
+
+    #[test]
+    fn runs_and_stops_operations() {
+        System::new("test").block_on(async move {
+            let mut srv = server(context("{}"));
+            let mut conn = srv.ws_at("/").await.unwrap();
+
+            send(&mut conn, json!({ "type": "connection_init", "payload": {} })).await;
+            assert_eq!(receive_json(&mut conn).await, json!({ "type": "connection_ack" }));
+
+            // queries yield a single result, followed by complete
+            send(&mut conn, json!({ "id": "1", "type": "start", "payload": { "query": "{ sites { name } }" } })).await;
+            let data = receive_json(&mut conn).await;
+            assert_eq!(data["id"], "1");
+            assert_eq!(data["type"], "data");
+            assert!(data["payload"]["data"]["sites"].is_array(), "{}", data);
+            assert_eq!(receive_json(&mut conn).await, json!({ "id": "1", "type": "complete" }));
+
+            // subscriptions run until they are stopped
+            send(&mut conn, json!({ "id": "2", "type": "start", "payload": { "query": "subscription { sitesChanged { name } }" } })).await;
+            send(&mut conn, json!({ "id": "2", "type": "stop" })).await;
+            assert_eq!(receive_json(&mut conn).await, json!({ "id": "2", "type": "complete" }));
+        });
+    }
+
+    #[test]
+    fn limits_the_operations_per_connection() {
+        System::new("test").block_on(async move {
+            let mut srv = server(context("{ max_operations_per_connection: 1 }"));
+            let mut conn = srv.ws_at("/").await.unwrap();
+
+            let subscription = json!({ "query": "subscription { sitesChanged { name } }" });
+            send(&mut conn, json!({ "id": "1", "type": "start", "payload": subscription })).await;
+            send(&mut conn, json!({ "id": "2", "type": "start", "payload": subscription })).await;
+            let error = receive_json(&mut conn).await;
+            assert_eq!(error["id"], "2");
+            assert_eq!(error["type"], "error");
+
+            // stopping an operation makes room for another one
+            send(&mut conn, json!({ "id": "1", "type": "stop" })).await;
+            assert_eq!(receive_json(&mut conn).await, json!({ "id": "1", "type": "complete" }));
+            send(&mut conn, json!({ "id": "3", "type": "start", "payload": subscription })).await;
+            send(&mut conn, json!({ "id": "3", "type": "stop" })).await;
+            assert_eq!(receive_json(&mut conn).await, json!({ "id": "3", "type": "complete" }));
+        });
+    }
+
+    #[test]
+    fn closes_the_connection_when_the_token_expires() {
+        System::new("test").block_on(async move {
+            let exp = Utc::now().timestamp() + 1;
+            let claims = Claims::from(json!({ "sub": "alice", "exp": exp }));
+            let mut srv = server(Context { claims: Some(claims), ..context("{}") });
+            let mut conn = srv.ws_at("/").await.unwrap();
+
+            send(&mut conn, json!({ "type": "connection_init", "payload": {} })).await;
+            assert_eq!(receive_json(&mut conn).await, json!({ "type": "connection_ack" }));
+            assert_eq!(receive_json(&mut conn).await["type"], "connection_error");
+            match receive(&mut conn).await {
+                ws::Frame::Close(Some(reason)) => assert_eq!(reason.code, ws::CloseCode::Policy),
+                frame => panic!("expected a close frame, got {:?}", frame),
+            }
+        });
+    }
+}
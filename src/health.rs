@@ -0,0 +1,292 @@
+//! Periodic health checks of the sites that have a `health_check`. The
+//! checks run in a background task of the actix system; the latest result
+//! of every site is kept in a [`HealthMonitor`].
+
+use std::{collections::HashMap, sync::{Arc, RwLock}, time::{Duration, Instant}};
+
+use actix_web::client::Client;
+use schemars::JsonSchema;
+use serde::Serialize;
+
+use crate::site::{HealthCheck, Site};
+use crate::site_store::SiteStore;
+
+/// how often the monitor looks for checks that are due
+const TICK: Duration = Duration::from_secs(1);
+/// the message of sites that did not respond, whatever the reason
+const UNREACHABLE_MESSAGE: &str = "the site did not respond";
+
+#[derive(Serialize, JsonSchema, juniper::GraphQLEnum, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    // an attribute rather than a doc comment, which would make the JSON
+    // schema list this variant separately
+    #[graphql(description = "the site has not been checked yet")]
+    Unknown,
+    Up,
+    Down,
+}
+
+/// The result of the latest health check of a site
+#[derive(Serialize, JsonSchema, juniper::GraphQLObject, Debug, Clone, PartialEq)]
+#[graphql(description = "The result of the latest health check of a site")]
+pub struct SiteHealth {
+    pub status: HealthStatus,
+    /// HTTP status of the response, if there was one
+    pub http_status: Option<i32>,
+    /// time of the check, in RFC 3339 format. None if not checked yet
+    pub checked_at: Option<String>,
+    /// why the site is considered down, like `unexpected HTTP status 503`
+    pub message: Option<String>,
+    /// the error the check ran into. It may reveal internals like host
+    /// names and addresses, so it is only logged, never sent to clients
+    #[serde(skip)]
+    #[graphql(skip)]
+    detail: Option<String>,
+}
+
+impl SiteHealth {
+    fn unknown() -> Self {
+        SiteHealth { status: HealthStatus::Unknown, http_status: None, checked_at: None, message: None, detail: None }
+    }
+
+    fn checked(status: HealthStatus, http_status: Option<u16>, message: Option<String>) -> Self {
+        SiteHealth {
+            status,
+            http_status: http_status.map(i32::from),
+            checked_at: Some(chrono::Utc::now().to_rfc3339()),
+            message,
+            detail: None,
+        }
+    }
+
+    /// a site that did not respond, with the error as detail
+    fn unreachable(detail: String) -> Self {
+        SiteHealth {
+            detail: Some(detail),
+            ..SiteHealth::checked(HealthStatus::Down, None, Some(UNREACHABLE_MESSAGE.to_string()))
+        }
+    }
+}
+
+/// a check to run, with the defaults of the site applied
+#[derive(Debug, Clone, PartialEq)]
+struct DueCheck {
+    site: String,
+    url: String,
+    check: HealthCheck,
+}
+
+struct Entry {
+    /// the check the result belongs to. If the site's check changes, the
+    /// result is dropped.
+    check: HealthCheck,
+    next_check: Instant,
+    health: SiteHealth,
+}
+
+/// Holds the latest health of every site with a health check, by site name
+#[derive(Default)]
+pub struct HealthMonitor {
+    entries: RwLock<HashMap<String, Entry>>,
+}
+
+impl HealthMonitor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// the health of the site, None if it has no health check
+    pub fn health(&self, site: &str) -> Option<SiteHealth> {
+        self.entries.read().unwrap()
+            .get(site)
+            .map(|e| e.health.clone())
+    }
+
+    /// the health of the given sites, by site name. Sites without a health
+    /// check are left out.
+    pub fn health_of(&self, sites: &[&Site]) -> HashMap<String, SiteHealth> {
+        let entries = self.entries.read().unwrap();
+        sites.iter()
+            .filter_map(|s| entries.get(s.name()).map(|e| (s.name().to_string(), e.health.clone())))
+            .collect()
+    }
+
+    /// Returns the checks that are due and schedules their next run.
+    /// Results of sites that were removed or whose check changed are
+    /// dropped, so these sites are checked right away.
+    fn due_checks(&self, sites: &[Site], now: Instant) -> Vec<DueCheck> {
+        let mut entries = self.entries.write().unwrap();
+        entries.retain(|name, entry| sites.iter()
+            .any(|s| s.name() == name && s.health_check() == Some(&entry.check))
+        );
+        let mut due = Vec::new();
+        for site in sites {
+            let check = match site.health_check() {
+                Some(c) => c,
+                None => continue,
+            };
+            let entry = entries.entry(site.name().to_string()).or_insert_with(|| Entry {
+                check: check.clone(),
+                next_check: now,
+                health: SiteHealth::unknown(),
+            });
+            if entry.next_check <= now {
+                entry.next_check = now + check.interval();
+                due.push(DueCheck {
+                    site: site.name().to_string(),
+                    url: check.url.clone().unwrap_or_else(|| site.url().to_string()),
+                    check: check.clone(),
+                });
+            }
+        }
+        due
+    }
+
+    /// stores the result, unless the site or its check changed meanwhile
+    fn record(&self, due: &DueCheck, health: SiteHealth) {
+        if let Some(entry) = self.entries.write().unwrap().get_mut(&due.site) {
+            if entry.check == due.check {
+                match health.status {
+                    HealthStatus::Down if entry.health.status != HealthStatus::Down =>
+                        log::warn!("site '{}' is down: {}", due.site, health.detail.as_deref().or(health.message.as_deref()).unwrap_or_default()),
+                    HealthStatus::Up if entry.health.status == HealthStatus::Down =>
+                        log::info!("site '{}' is up again", due.site),
+                    _ => (),
+                }
+                entry.health = health;
+            }
+        }
+    }
+}
+
+/// Starts checking the sites of the store in the background. Must be called
+/// from within the actix system.
+pub fn start(monitor: Arc<HealthMonitor>, site_store: Arc<SiteStore>) {
+    actix_web::rt::spawn(async move {
+        let client = Client::default();
+        loop {
+            for due in monitor.due_checks(&site_store.sites(), Instant::now()) {
+                let monitor = monitor.clone();
+                let client = client.clone();
+                actix_web::rt::spawn(async move {
+                    let health = check(&client, &due.url, &due.check).await;
+                    monitor.record(&due, health);
+                });
+            }
+            actix_web::rt::time::delay_for(TICK).await;
+        }
+    });
+}
+
+/// requests the URL once and evaluates the response
+async fn check(client: &Client, url: &str, check: &HealthCheck) -> SiteHealth {
+    match client.get(url).timeout(check.timeout()).send().await {
+        Ok(response) => {
+            let status = response.status().as_u16();
+            if check.accepts(status) {
+                SiteHealth::checked(HealthStatus::Up, Some(status), None)
+            } else {
+                SiteHealth::checked(HealthStatus::Down, Some(status), Some(format!("unexpected HTTP status {}", status)))
+            }
+        }
+        Err(e) => SiteHealth::unreachable(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    /// Serves every connection with the given status line until the test
+    /// ends. Returns the URL of the server.
+    fn stub_server(status_line: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/health", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut buf = [0; 1024];
+                let _ = stream.read(&mut buf);
+                let _ = write!(stream, "HTTP/1.1 {}\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status_line);
+            }
+        });
+        url
+    }
+
+    fn health_check(expected_status: Option<u16>) -> HealthCheck {
+        HealthCheck { url: None, expected_status, interval_secs: Some(30), timeout_secs: Some(2) }
+    }
+
+    fn run_check(url: &str, c: &HealthCheck) -> SiteHealth {
+        let (url, c) = (url.to_string(), c.clone());
+        actix_web::rt::System::new("test").block_on(async move {
+            check(&Client::default(), &url, &c).await
+        })
+    }
+
+    #[test]
+    fn checks_the_response_status() {
+        let ok = stub_server("200 OK");
+        let unavailable = stub_server("503 Service Unavailable");
+
+        let health = run_check(&ok, &health_check(None));
+        assert_eq!(health.status, HealthStatus::Up);
+        assert_eq!(health.http_status, Some(200));
+        assert!(health.checked_at.is_some());
+
+        let health = run_check(&unavailable, &health_check(None));
+        assert_eq!(health.status, HealthStatus::Down);
+        assert_eq!(health.http_status, Some(503));
+
+        assert_eq!(run_check(&unavailable, &health_check(Some(503))).status, HealthStatus::Up);
+        assert_eq!(run_check(&ok, &health_check(Some(204))).status, HealthStatus::Down);
+    }
+
+    #[test]
+    fn unreachable_sites_are_down() {
+        // bind and drop a listener to get a port nobody listens on
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let health = run_check(&format!("http://127.0.0.1:{}/", port), &health_check(None));
+        assert_eq!(health.status, HealthStatus::Down);
+        assert_eq!(health.http_status, None);
+        assert_eq!(health.message.as_deref(), Some(UNREACHABLE_MESSAGE));
+        // the connection error is only logged
+        assert!(health.detail.is_some());
+        let json = serde_json::to_value(&health).unwrap();
+        assert_eq!(json["message"], UNREACHABLE_MESSAGE);
+        assert!(json.get("detail").is_none());
+    }
+
+    fn site(name: &str, health_check: Option<HealthCheck>) -> Site {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "url": format!("https://{}.example", name),
+            "claim_rules": [],
+            "health_check": health_check,
+        })).unwrap()
+    }
+
+    #[test]
+    fn schedules_checks_by_interval() {
+        let monitor = HealthMonitor::new();
+        let sites = vec![site("a", Some(health_check(None))), site("b", None)];
+        let now = Instant::now();
+
+        let due = monitor.due_checks(&sites, now);
+        assert_eq!(due.len(), 1);
+        assert_eq!(due[0].url, "https://a.example");
+        assert_eq!(monitor.health("a"), Some(SiteHealth::unknown()));
+        assert_eq!(monitor.health("b"), None);
+
+        assert!(monitor.due_checks(&sites, now + Duration::from_secs(29)).is_empty());
+        assert_eq!(monitor.due_checks(&sites, now + Duration::from_secs(30)).len(), 1);
+
+        // a changed check is run right away, a removed site is forgotten
+        let changed = vec![site("a", Some(health_check(Some(204))))];
+        assert_eq!(monitor.due_checks(&changed, now + Duration::from_secs(31)).len(), 1);
+        monitor.due_checks(&[], now);
+        assert_eq!(monitor.health("a"), None);
+    }
+}
@@ -0,0 +1,201 @@
+//! Translation catalogs and locale negotiation. Catalogs are JSON files
+//! mapping message keys to texts, one per locale, like `locales/de.json`.
+//! Built-in catalogs ship with the built-in templates; catalogs in the
+//! template directory add to or override them.
+
+use std::{collections::HashMap, path::Path, sync::Arc};
+
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::Value;
+
+use crate::templates::{self, Theme};
+
+/// directory of the catalogs, relative to the template directory
+pub const LOCALES_DIR: &str = "locales";
+const CATALOG_SUFFIX: &str = ".json";
+
+/// true if `locale` is a language tag like `de` or `de-CH`
+pub fn is_valid_locale(locale: &str) -> bool {
+    let mut subtags = locale.split('-');
+    let language = subtags.next().unwrap_or_default();
+    (2..=8).contains(&language.len())
+        && language.chars().all(|c| c.is_ascii_alphabetic())
+        && subtags.all(|s| (1..=8).contains(&s.len()) && s.chars().all(|c| c.is_ascii_alphanumeric()))
+}
+
+/// `de-CH` becomes `de-ch`; tags are compared case-insensitively
+pub fn normalize(locale: &str) -> String {
+    locale.trim().replace('_', "-").to_ascii_lowercase()
+}
+
+/// the locale itself, followed by its more general forms: `de-ch`, `de`
+pub fn fallbacks(locale: &str) -> impl Iterator<Item = &str> {
+    let mut rest = Some(locale);
+    std::iter::from_fn(move || {
+        let current = rest?;
+        rest = current.rsplit_once('-').map(|(general, _)| general);
+        Some(current)
+    })
+}
+
+/// Returns the locales of an `Accept-Language` header, most preferred first
+pub fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut weighted = header.split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let locale = parts.next()?.trim();
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            (locale != "*" && quality > 0.0 && is_valid_locale(locale)).then(|| (normalize(locale), quality))
+        })
+        .collect::<Vec<_>>();
+    // sort_by is stable, so entries of equal quality keep their order
+    weighted.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+    weighted.into_iter().map(|(locale, _)| locale).collect()
+}
+
+/// The translation catalogs of all locales
+#[derive(Debug)]
+pub struct Catalogs {
+    default_locale: String,
+    catalogs: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalogs {
+    /// Loads the theme's built-in catalogs and those in the `locales`
+    /// directory of the template directory, if there is one.
+    pub fn load(template_dir: Option<&Path>, theme: Theme, default_locale: &str) -> Result<Catalogs, String> {
+        let mut catalogs = Catalogs { default_locale: normalize(default_locale), catalogs: HashMap::new() };
+        for (name, content) in templates::resources(theme) {
+            if let Some(locale) = name.strip_prefix(LOCALES_DIR).and_then(|n| n.strip_prefix('/')) {
+                catalogs.add(locale, content)
+                    .unwrap_or_else(|e| panic!("could not parse internal catalog {}: {}", name, e));
+            }
+        }
+        let dir = match template_dir.map(|d| d.join(LOCALES_DIR)) {
+            Some(d) if d.is_dir() => d,
+            _ => return Ok(catalogs),
+        };
+        let entries = std::fs::read_dir(&dir)
+            .map_err(|e| format!("cannot read directory '{}': {}", dir.to_string_lossy(), e))?;
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+            if !name.ends_with(CATALOG_SUFFIX) {
+                continue
+            }
+            let content = std::fs::read(&path)
+                .map_err(|e| format!("cannot read catalog '{}': {}", path.to_string_lossy(), e))?;
+            catalogs.add(&name, &content)
+                .map_err(|e| format!("catalog '{}': {}", path.to_string_lossy(), e))?;
+        }
+        Ok(catalogs)
+    }
+
+    /// adds the messages of a catalog file to those already known for its locale
+    fn add(&mut self, file_name: &str, content: &[u8]) -> Result<(), String> {
+        let locale = file_name.strip_suffix(CATALOG_SUFFIX).unwrap_or(file_name);
+        if !is_valid_locale(locale) {
+            return Err(format!("'{}' is not a valid locale", locale))
+        }
+        let messages = serde_json::from_slice::<HashMap<String, String>>(content)
+            .map_err(|e| format!("expected an object of message texts: {}", e))?;
+        self.catalogs.entry(normalize(locale)).or_default().extend(messages);
+        Ok(())
+    }
+
+    pub fn default_locale(&self) -> &str {
+        &self.default_locale
+    }
+
+    /// Returns the first of the requested locales there is a catalog for,
+    /// in its most specific available form, or the default locale
+    pub fn negotiate<'a>(&self, requested: impl IntoIterator<Item = &'a str>) -> String {
+        requested.into_iter()
+            .map(normalize)
+            .find_map(|r| fallbacks(&r).find(|l| self.catalogs.contains_key(*l)).map(String::from))
+            .unwrap_or_else(|| self.default_locale.clone())
+    }
+
+    /// Looks the key up in the locale's catalog, then in the catalogs of
+    /// its more general forms and finally in the default locale's catalog
+    pub fn translate(&self, locale: &str, key: &str) -> Option<&str> {
+        let locale = normalize(locale);
+        let text = fallbacks(&locale)
+            .chain(std::iter::once(self.default_locale.as_str()))
+            .find_map(|l| self.catalogs.get(l)?.get(key));
+        text.map(String::as_str)
+    }
+}
+
+/// The `t` helper: `{{t "welcome" name=access_token.name}}` renders the
+/// message `welcome` in the locale of the page, replacing `{name}` in it.
+/// Unknown keys are rendered as they are.
+pub struct TranslateHelper {
+    pub catalogs: Arc<Catalogs>,
+}
+
+impl HelperDef for TranslateHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let key = h.param(0)
+            .and_then(|p| p.value().as_str())
+            .ok_or_else(|| RenderError::new("t: the first parameter must be the message key"))?;
+        let locale = ctx.data().get("locale")
+            .and_then(Value::as_str)
+            .unwrap_or_else(|| self.catalogs.default_locale());
+        let mut text = self.catalogs.translate(locale, key).unwrap_or(key).to_string();
+        for (name, value) in h.hash() {
+            let value = match value.value() {
+                Value::String(s) => s.clone(),
+                Value::Null => String::new(),
+                v => v.to_string(),
+            };
+            text = text.replace(&format!("{{{}}}", name), &value);
+        }
+        Ok(ScopedJson::Derived(Value::String(text)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalogs() -> Catalogs {
+        let mut c = Catalogs { default_locale: "en".into(), catalogs: HashMap::new() };
+        c.add("en.json", br#"{"welcome": "Welcome back, {name}!", "search": "Search"}"#).unwrap();
+        c.add("de.json", br#"{"welcome": "Willkommen, {name}!"}"#).unwrap();
+        c.add("de-CH.json", r#"{"welcome": "Grüezi, {name}!"}"#.as_bytes()).unwrap();
+        c
+    }
+
+    #[test]
+    fn negotiates_the_most_specific_available_locale() {
+        let c = catalogs();
+        assert_eq!(parse_accept_language("fr-CH, de;q=0.8, en;q=0.9, *;q=0.5"), vec!["fr-ch", "en", "de"]);
+        assert_eq!(c.negotiate(parse_accept_language("fr-CH, de;q=0.8, en;q=0.9").iter().map(String::as_str)), "en");
+        assert_eq!(c.negotiate(vec!["de-AT", "en"]), "de");
+        assert_eq!(c.negotiate(vec!["de_CH"]), "de-ch");
+        assert_eq!(c.negotiate(vec!["it"]), "en");
+    }
+
+    #[test]
+    fn renders_translations_with_fallbacks() {
+        let mut hb = Handlebars::new();
+        hb.register_helper("t", Box::new(TranslateHelper { catalogs: Arc::new(catalogs()) }));
+        let render = |locale: &str, template: &str| hb.render_template(template, &serde_json::json!({
+            "locale": locale,
+            "user": { "name": "<Jane>" },
+        })).unwrap();
+        assert_eq!(render("de-ch", r#"{{t "welcome" name=user.name}}"#), "Grüezi, &lt;Jane&gt;!");
+        assert_eq!(render("de", r#"{{t "search"}}"#), "Search");
+        assert_eq!(render("fr", r#"{{t "welcome" name=user.name}}"#), "Welcome back, &lt;Jane&gt;!");
+        assert_eq!(render("en", r#"{{t "unknown.key"}}"#), "unknown.key");
+    }
+}
@@ -1,35 +1,50 @@
 
 mod auth;
 mod graphql_schema;
+mod graphql_limits;
+mod graphql_ws;
 mod cookie_auth;
 mod site;
 mod cli;
 mod templates;
 mod error;
 mod option_condition;
+mod explain;
+mod site_store;
+mod store;
+mod user_store;
+mod health;
+mod announcements;
+mod rfc3339;
+mod expr;
+mod i18n;
+mod template_helpers;
+mod rest_api;
+mod search;
 
 use actix_web::middleware::Condition;
-use serde::{Serialize};
+use serde::{Deserialize, Serialize};
 
 use actix_files::NamedFile;
-use actix_session::CookieSession;
+use actix_session::{CookieSession, UserSession};
 use actix_web::dev::ServiceRequest;
-use actix_web::{get, post, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::{get, post, web, App, HttpMessage, HttpRequest, HttpResponse, HttpServer, Responder};
 use actix_web_httpauth::extractors::bearer::{BearerAuth, Config};
-use actix_web_httpauth::extractors::AuthenticationError;
+use actix_web_httpauth::headers::www_authenticate::bearer::Bearer;
+use actix_web_httpauth::extractors::{AuthExtractor, AuthenticationError};
+use futures_util::future::{ready, Ready};
 use actix_web_httpauth::middleware::HttpAuthentication;
 use auth::{Claims, OidcAuth};
 use serde_json::Map;
-use site::{Operator, Operand, Site};
+use site::Site;
 use option_condition::OptionCondition;
 
 use std::fs::{DirBuilder, OpenOptions};
 use std::io::Write;
-use std::{fmt, path::PathBuf, sync::Arc};
+use std::{collections::HashMap, fmt, path::PathBuf, sync::Arc};
 
 
-use juniper::{EmptyMutation, EmptySubscription};
-use juniper_actix::{graphiql_handler, graphql_handler, playground_handler};
+use juniper_actix::{graphiql_handler, playground_handler};
 use web::Payload;
 
 
@@ -38,11 +53,17 @@ use web::Payload;
 
 use handlebars::Handlebars;
 
-use graphql_schema::{Context, Query, Schema};
+use graphql_schema::{Context, Mutation, Query, Schema, Subscription};
+use site_store::SiteStore;
+use user_store::UserStore;
+use health::HealthMonitor;
+use announcements::{Announcement, AnnouncementStore};
 
 const GRAPHQL_PATH: &str = "/graphql";
+const GRAPHQL_SUBSCRIPTIONS_PATH: &str = "/subscriptions";
 const EXCHANGE_TOKEN_PATH: &str = "/web/.exchange-token";
-const HBS_SUFFIX: &str = ".hbs";
+/// minimum length of the session cookie encryption key, as required by CookieSession
+const SESSION_KEY_MIN_LEN: usize = 32;
 
 #[derive(fmt::Debug)]
 pub enum Error {
@@ -51,24 +72,31 @@ pub enum Error {
     TokenExchangeFailure(String),
     TokenExchangeResponseError(auth::ErrorResponse),
     JWTValidationFailed,
+    TokenDecodeFailure(String),
 }
 
 #[derive(Debug, Clone)]
 pub enum AppConfig {
     Serve(ServeConfig),
-    InitTemplates(InitTemplatesConfig)
+    InitTemplates(InitTemplatesConfig),
+    CheckConfig(CheckConfigConfig),
+    Explain(ExplainConfig),
+    PrintConfig(PrintConfigConfig),
 }
 
 impl AppConfig {
     pub fn common(&self) -> &CommonConfig {
         match self {
             Self::Serve(s) => &s.common,
-            Self::InitTemplates(i) => &i.common
+            Self::InitTemplates(i) => &i.common,
+            Self::CheckConfig(c) => &c.common,
+            Self::Explain(e) => &e.common,
+            Self::PrintConfig(p) => &p.common,
         }
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct CommonConfig {
     logging: Option<String>,
     template_dir: Option<PathBuf>,
@@ -78,19 +106,11 @@ impl CommonConfig {
     pub const DEFAULT_TEMPLATE_DIR: &'static str = "templates";
 }
 
-impl Default for CommonConfig {
-    fn default() -> Self {
-        CommonConfig {
-            logging: None,
-            template_dir: None
-        }
-    }
-}
-
 #[derive(Debug, Clone)]
 pub struct ServeAuthConfig {
     authorization_server_url: url::Url,
     client_id: String,
+    client_secret: Option<String>,
 }
 #[derive(Debug, Clone)]
 pub struct ServeConfig {
@@ -99,18 +119,134 @@ pub struct ServeConfig {
     interface_addresses: Vec<std::net::IpAddr>,
     auth: Option<ServeAuthConfig>,
     scope: String,
+    session_key: Option<String>,
     site_list: site::SiteList,
+    admin_claim_rules: Vec<site::ClaimRule>,
+    deny_claim_rules: Vec<site::ClaimRule>,
+    default_visible: bool,
+    site_store: Option<PathBuf>,
+    user_store: Option<PathBuf>,
+    announcements: Vec<announcements::Announcement>,
+    announcement_store: Option<PathBuf>,
+    variables: site::Variables,
+    default_locale: String,
+    /// None sends the built-in policy, an empty policy none at all
+    content_security_policy: Option<String>,
+    theme: templates::Theme,
+    graphql: graphql_limits::GraphQLLimits,
     dev_mode_enabled: bool,
 }
 
+impl ServeConfig {
+    fn visibility_policy(&self) -> site::VisibilityPolicy<'_> {
+        site::VisibilityPolicy {
+            deny_claim_rules: &self.deny_claim_rules,
+            default_visible: self.default_visible,
+            variables: &self.variables,
+        }
+    }
+
+    /// Returns the scopes to request at login: the configured scope, plus
+    /// all scopes required by sites.
+    fn requested_scopes(&self) -> Vec<&str> {
+        let mut scopes = Vec::new();
+        let site_scopes = self.site_list.sites()
+            .iter()
+            .flat_map(|site| site.required_scopes())
+            .map(String::as_str);
+        for scope in self.scope.split_whitespace().chain(site_scopes) {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        scopes
+    }
+
+    /// Returns a warning for every claim rule, deny rule and `when`
+    /// expression that checks a claim which is only issued for a scope that
+    /// is not requested
+    fn missing_scope_warnings(&self) -> Vec<String> {
+        fn numbered(what: &str, rules: &[site::ClaimRule]) -> Vec<(String, String)> {
+            rules.iter().enumerate().map(|(i, r)| (format!("{} #{}", what, i + 1), r.path.clone())).collect()
+        }
+
+        // what checks a claim, and the path of the claim
+        let mut checks: Vec<(String, String)> = Vec::new();
+        checks.extend(numbered("admin claim rule", &self.admin_claim_rules));
+        checks.extend(numbered("deny claim rule", &self.deny_claim_rules));
+        for site in self.site_list.sites() {
+            let site_checks = numbered("claim rule", &site.claim_rules).into_iter()
+                .chain(numbered("deny claim rule", &site.deny_claim_rules))
+                .chain(site.when().map(|w| w.claim_paths()).unwrap_or_default()
+                    .into_iter()
+                    .map(|path| ("the when expression".to_string(), path)));
+            checks.extend(site_checks.map(|(what, path)| (format!("site '{}': {}", site.name(), what), path)));
+            for set in site.resolved_rule_sets() {
+                checks.extend(numbered("claim rule", set.claim_rules())
+                    .into_iter()
+                    .map(|(what, path)| (format!("rule set '{}': {}", set.name(), what), path)));
+            }
+        }
+
+        let scopes = self.requested_scopes();
+        let mut warnings = Vec::new();
+        for (what, path) in checks {
+            if let Some(scope) = auth::scope_for_claim_path(&path) {
+                // rule sets shared by several sites are reported once
+                let warning = format!("{} checks '{}', which is only issued with scope '{}', but that scope is not requested. Add it to 'scope' or to a site's 'required_scopes'.", what, path, scope);
+                if !scopes.contains(&scope) && !warnings.contains(&warning) {
+                    warnings.push(warning);
+                }
+            }
+        }
+        warnings
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct InitTemplatesConfig {
-    common: CommonConfig
+    common: CommonConfig,
+    theme: templates::Theme,
+}
+
+#[derive(Debug, Clone)]
+pub struct CheckConfigConfig {
+    common: CommonConfig,
+    config_file: String,
+    config_format: Option<cli::ConfigFormat>,
+    no_auth: bool,
+    overrides: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct PrintConfigConfig {
+    common: CommonConfig,
+    content: String,
+}
+
+#[derive(Debug, Clone)]
+pub enum ClaimsSource {
+    Token(String),
+    TokenFile(PathBuf),
+    ClaimsFile(PathBuf),
+}
+
+#[derive(Debug, Clone)]
+pub struct ExplainConfig {
+    common: CommonConfig,
+    serve: ServeConfig,
+    claims_source: ClaimsSource,
+    verify: bool,
 }
 
 struct WebContext<'a> {
     hb: Handlebars<'a>,
     app_config: ServeConfig,
+    site_store: Arc<SiteStore>,
+    user_store: Arc<UserStore>,
+    health_monitor: Arc<HealthMonitor>,
+    announcement_store: Arc<AnnouncementStore>,
+    catalogs: Arc<i18n::Catalogs>,
 }
 
 impl std::error::Error for Error {}
@@ -133,6 +269,9 @@ impl fmt::Display for Error {
             Error::JWTValidationFailed => {
                 write!(f, "token validation failed")
             }
+            Error::TokenDecodeFailure(msg) => {
+                write!(f, "cannot decode token: {}", msg)
+            }
         }
     }
 }
@@ -142,49 +281,189 @@ async fn hello() -> impl Responder {
     HttpResponse::Found().header("location", "/web/index.html").finish()
 }
 
-fn matches_operand(v: &serde_json::Value, op: &Operand) -> bool {
-    match op {
-        Operand::Value {value} => v.eq(value),
-        Operand::Regex {regex} => if let Some(s)=v.as_str() {
-                regex.is_match(s)
-            } else {
-                false
-            }
+/// Returns true if the site is shown to a user with the given claims, see
+/// [`Site::visibility`] for the rules
+fn is_site_for_claims(serve_config: &ServeConfig, site: &Site, claims: &Claims, now: chrono::DateTime<chrono::Utc>) -> bool {
+    site.visibility(claims, &serve_config.visibility_policy(), now).is_visible()
+}
+
+/// Returns the sites that are visible for the given claims.
+/// This is the visibility logic shared by the dashboard and the APIs.
+fn visible_sites<'a>(serve_config: &ServeConfig, sites: &'a [Site], claims_opt: Option<&Claims>) -> Vec<&'a Site> {
+    let now = chrono::Utc::now();
+    if let Some(claims) = claims_opt {
+        // with claims, we check against them
+        sites
+        .iter().filter(|site|is_site_for_claims(serve_config, site, claims, now))
+        .collect()    
+    } else if serve_config.auth.is_none() {
+        // no claims, but auth disabled means we do not check for matching
+        // rules, but simply deliver all elements enabled on this instance
+        // (intended for testing)
+        sites.iter().filter(|site| site.is_enabled(now, &serve_config.variables)).collect()
+    } else {
+        // no claims, auth enabled -> no elements visible
+        Vec::new()
     }
 }
 
-fn is_site_for_claims(site: &Site, claims: &Claims) -> bool {
-    
-    for r in &site.claim_rules {
+/// Returns the active announcements for the given claims that the user
+/// has not dismissed, most severe first. `sites` are the sites visible for
+/// the claims.
+fn visible_announcements<'a>(serve_config: &ServeConfig, announcements: &'a [Announcement], sites: &[&Site], claims_opt: Option<&Claims>, dismissed: &[String]) -> Vec<&'a Announcement> {
+    let now = chrono::Utc::now();
+    let mut visible = announcements.iter()
+        .filter(|a| a.is_active(now) && a.is_visible(claims_opt, serve_config.auth.is_some(), sites))
+        .filter(|a| !dismissed.iter().any(|d| d == a.id()))
+        .collect::<Vec<_>>();
+    // sort_by_key is stable, so announcements of equal severity keep their order
+    visible.sort_by_key(|a| std::cmp::Reverse(a.severity()));
+    visible
+}
 
-        let v = if let Some(v) = claims.get_path(r.path.as_str()) {
-            v
-        } else {
-            continue
-        };
+/// A site as templates see it: all of its fields, plus its name and
+/// description in the locale of the page
+#[derive(Serialize)]
+struct LocalizedSite<'a> {
+    #[serde(flatten)]
+    site: &'a Site,
+    display_name: &'a str,
+    display_description: Option<&'a str>,
+    /// favicon of the site's origin, None if the URL has none
+    icon_url: Option<String>,
+}
 
+impl<'a> LocalizedSite<'a> {
+    fn localize(sites: Vec<&'a Site>, locale: &'a str) -> Vec<Self> {
+        sites.into_iter()
+            .map(|site| LocalizedSite {
+                site,
+                display_name: site.localized_name(locale),
+                display_description: site.localized_description(locale),
+                icon_url: templates::favicon_url(site.url()),
+            })
+            .collect()
+    }
+}
 
-        let rule_matches = match r.operator.clone() {
-            Operator::Matches => matches_operand(v, &r.operand),
-            Operator::ContainsMatch => if let Some(a) = v.as_array() {
-                    a.iter().find(|v| matches_operand(v, &r.operand)).is_some()
-                } else {
-                    false
-                }
-        };
+/// A personal link as templates see it
+#[derive(Serialize)]
+struct PersonalLinkView {
+    #[serde(flatten)]
+    link: user_store::PersonalLink,
+    /// the link's icon, or else the favicon of its origin
+    icon_url: Option<String>,
+}
 
-        if rule_matches {
-            return true
-        }
+impl From<user_store::PersonalLink> for PersonalLinkView {
+    fn from(link: user_store::PersonalLink) -> Self {
+        let icon_url = link.icon.clone().or_else(|| templates::favicon_url(&link.url));
+        PersonalLinkView { link, icon_url }
     }
-    
-    false
 }
 
 #[derive(Serialize)]
 struct HbsContext <'a> {
     access_token: &'a serde_json::Value,
-    sites: Vec<&'a Site>,
+    /// locale of the page, like `de`. Used by the `t` helper
+    locale: &'a str,
+    /// the visible sites the user did not hide, in the user's order
+    sites: Vec<LocalizedSite<'a>>,
+    pinned_sites: Vec<LocalizedSite<'a>>,
+    hidden_sites: Vec<LocalizedSite<'a>>,
+    personal_links: Vec<PersonalLinkView>,
+    /// latest health of the visible sites that have a health check, by site name
+    health: HashMap<String, health::SiteHealth>,
+    announcements: Vec<announcements::AnnouncementView<'a>>,
+    /// the most severe announcement severity of every site that has announcements
+    site_announcements: HashMap<&'a str, announcements::Severity>,
+    /// token to send in the X-CSRF-Token header with GraphQL requests
+    /// authenticated by the session cookie, None if auth is disabled
+    csrf_token: Option<String>,
+}
+
+/// query string of the search endpoint
+#[derive(Deserialize)]
+struct SearchQuery {
+    #[serde(default)]
+    q: String,
+    /// redirect to the best match instead of listing all matches
+    #[serde(default)]
+    lucky: bool,
+    /// 'suggestions' for the OpenSearch suggestions format
+    format: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SearchResult<'a> {
+    name: &'a str,
+    url: &'a str,
+    description: Option<&'a str>,
+    category: Option<&'a str>,
+    score: f64,
+}
+
+#[get("/.search")]
+async fn handle_search(req: HttpRequest, wc: web::Data<WebContext<'_>>, query: web::Query<SearchQuery>) -> HttpResponse {
+    let ext = req.extensions();
+    let all_sites = wc.site_store.sites();
+    let sites = visible_sites(&wc.app_config, &all_sites, ext.get::<Claims>());
+    let hits = search::search(&sites, &query.q);
+
+    if query.lucky {
+        let location = match hits.first() {
+            Some(hit) => hit.site.url().to_string(),
+            // nothing found, so let the user look for themselves
+            None => format!("index.html?q={}", url::form_urlencoded::byte_serialize(query.q.as_bytes()).collect::<String>()),
+        };
+        return HttpResponse::Found()
+            .set_header(http::header::LOCATION, location)
+            .finish()
+    }
+
+    if query.format.as_deref() == Some("suggestions") {
+        let names = hits.iter().map(|h| h.site.name()).collect::<Vec<_>>();
+        return HttpResponse::Ok()
+            .content_type("application/x-suggestions+json")
+            .body(serde_json::json!([query.q, names]).to_string())
+    }
+
+    HttpResponse::Ok().json(hits.iter()
+        .map(|h| SearchResult {
+            name: h.site.name(),
+            url: h.site.url(),
+            description: h.site.description(),
+            category: h.site.category(),
+            score: h.score,
+        })
+        .collect::<Vec<_>>()
+    )
+}
+
+/// OpenSearch description, so browsers can use the dashboard as search engine
+#[get("/.opensearch.xml")]
+async fn handle_opensearch(req: HttpRequest) -> HttpResponse {
+    let info = req.connection_info();
+    let search_url = xml_escape(&format!("{}://{}/web/.search", info.scheme(), info.host()));
+    let body = format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<OpenSearchDescription xmlns="http://a9.com/-/spec/opensearch/1.1/">
+  <ShortName>Dashboard</ShortName>
+  <Description>Find sites on the dashboard</Description>
+  <InputEncoding>UTF-8</InputEncoding>
+  <Url type="text/html" method="get" template="{url}?lucky=true&amp;q={{searchTerms}}"/>
+  <Url type="application/x-suggestions+json" method="get" template="{url}?format=suggestions&amp;q={{searchTerms}}"/>
+</OpenSearchDescription>
+"#, url = search_url);
+    HttpResponse::Ok()
+        .content_type("application/opensearchdescription+xml")
+        .body(body)
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
 }
 
 #[get("/{template_name:.*}")]
@@ -196,23 +475,57 @@ impl WebContext<'_> {
 
     pub async fn handle_web(&self, req: HttpRequest, wc: &WebContext<'_>, template_name: &String) -> impl Responder{
 
-        if wc.hb.has_template(template_name) {
+        if templates::is_page(template_name) && wc.hb.has_template(template_name) {
             
             let ext = req.extensions();
             let claims_opt = ext.get::<Claims>();
 
-            let sites = if let Some(claims) = claims_opt {
-                // with claims, we check against them
-                wc.app_config.site_list.sites()
-                .iter().filter(|site|is_site_for_claims(site, claims))
-                .collect()    
-            } else if wc.app_config.auth.is_none() {
-                // no claims, but auth disabled means we do not check for matching
-                // rules, but simply deliver all elements (intended for testing)
-                wc.app_config.site_list.sites().iter().collect()
+            let all_sites = wc.site_store.sites();
+            let sites = visible_sites(&wc.app_config, &all_sites, claims_opt);
+            let sub = claims_opt.and_then(Claims::sub);
+            let preferences = sub
+                .map(|sub| wc.user_store.preferences(sub))
+                .unwrap_or_default();
+            let personal_links = sub
+                .map(|sub| wc.user_store.links(sub))
+                .unwrap_or_default();
+            let health = wc.health_monitor.health_of(&sites);
+            let dismissed = sub
+                .map(|sub| wc.user_store.dismissed_announcements(sub))
+                .unwrap_or_default();
+            let all_announcements = wc.announcement_store.announcements();
+            let announcements = visible_announcements(&wc.app_config, &all_announcements, &sites, claims_opt, &dismissed);
+            let mut site_announcements = HashMap::new();
+            for a in announcements.iter() {
+                for name in a.sites() {
+                    let severity = site_announcements.entry(name.as_str()).or_insert_with(|| a.severity());
+                    *severity = a.severity().max(*severity);
+                }
+            }
+            // the user's choice wins over the token, which wins over the browser
+            let accept_language = req.headers().get(http::header::ACCEPT_LANGUAGE)
+                .and_then(|h| h.to_str().ok())
+                .map(i18n::parse_accept_language)
+                .unwrap_or_default();
+            let locale = wc.catalogs.negotiate(preferences.locale.as_deref().into_iter()
+                .chain(claims_opt.and_then(|c| c.value().get("locale")).and_then(|l| l.as_str()))
+                .chain(accept_language.iter().map(String::as_str))
+            );
+            // the pages may show icons and images from these
+            let image_urls = sites.iter().map(|s| s.url().to_string())
+                .chain(personal_links.iter().flat_map(|l| std::iter::once(l.url.clone()).chain(l.icon.clone())))
+                .chain(announcements.iter().flat_map(|a| a.image_urls()))
+                .collect::<Vec<_>>();
+            let arranged = preferences.arrange(sites);
+            let csrf_token = if wc.app_config.auth.is_some() {
+                match cookie_auth::csrf_token(&req.get_session()) {
+                    Ok(t) => Some(t),
+                    Err(e) => return HttpResponse::InternalServerError()
+                        .set_header("Content-Type", "text/plain")
+                        .body(e.to_string())
+                }
             } else {
-                // no claims, auth enabled -> no elements visible
-                Vec::new()
+                None
             };
             let empty = serde_json::Value::Object(Map::new());
             let ctx = HbsContext {
@@ -222,22 +535,33 @@ impl WebContext<'_> {
                     } else {
                         &empty
                     },
-                sites: sites
+                locale: &locale,
+                sites: LocalizedSite::localize(arranged.sites, &locale),
+                pinned_sites: LocalizedSite::localize(arranged.pinned_sites, &locale),
+                hidden_sites: LocalizedSite::localize(arranged.hidden_sites, &locale),
+                personal_links: personal_links.into_iter().map(PersonalLinkView::from).collect(),
+                health,
+                announcements: announcements.into_iter()
+                    .map(|a| announcements::AnnouncementView::new(a, sub.is_some()))
+                    .collect(),
+                site_announcements,
+                csrf_token,
             };
-            let content_type = match template_name.rsplit_once(".") {
-                Some((_, suffix)) => match suffix.to_ascii_lowercase().as_str() {
-                    "html" => "text/html",
-                    "txt" => "text/plain",
-                    "js" => "application/javascript",
-                    "json" => "application/json",
-                    _ => "text/plain"
-                }
-                None => "text/plain"
+            let content_type = templates::content_type(template_name);
+            let content_security_policy = match &wc.app_config.content_security_policy {
+                Some(policy) => policy.clone(),
+                None => templates::content_security_policy(image_urls.iter().map(String::as_str)),
             };
-            return match wc.hb.render(&template_name, &ctx) {
-                Ok(body) => HttpResponse::Ok()
-                    .set_header("Content-Type", content_type)
-                    .body(body),
+            return match wc.hb.render(template_name, &ctx) {
+                Ok(body) => {
+                    let mut response = HttpResponse::Ok();
+                    response.set_header("Content-Type", content_type)
+                        .set_header("X-Content-Type-Options", "nosniff");
+                    if !content_security_policy.is_empty() {
+                        response.set_header("Content-Security-Policy", content_security_policy);
+                    }
+                    response.body(body)
+                },
                 Err(e) => HttpResponse::InternalServerError()
                     .set_header("Content-Type", "text/plain")
                     .body(e.desc)                   
@@ -245,8 +569,8 @@ impl WebContext<'_> {
         }
 
 
-        // check for non-template files on file system
-        if let Some(template_dir) = wc.app_config.common.template_dir.clone() {
+        // check for non-template files on file system, they replace the theme's
+        if let Ok(template_dir) = resolve_template_dir(&wc.app_config.common).canonicalize() {
             let dir_content_response = template_dir
                 .join(PathBuf::from(&template_name))
                 .canonicalize().ok()
@@ -260,57 +584,226 @@ impl WebContext<'_> {
         }
 
         // check for non-template files in builtin list
-        if let Some(v) = templates::resources().get(template_name.as_str()) {
-            return HttpResponse::Ok().body(*v);
+        if let Some(v) = templates::resources(wc.app_config.theme).get(template_name.as_str()) {
+            return HttpResponse::Ok()
+                .set_header("Content-Type", templates::content_type(template_name))
+                .set_header("X-Content-Type-Options", "nosniff")
+                .body(*v);
         }
 
         HttpResponse::NotFound().finish()
     }
 }
 
+/// Returns true if the user may manage sites. As with sites, everybody
+/// is an admin if auth is disabled.
+fn is_admin(serve_config: &ServeConfig, claims_opt: Option<&Claims>) -> bool {
+    match claims_opt {
+        Some(claims) => site::any_rule_matches(&serve_config.admin_claim_rules, claims),
+        None => serve_config.auth.is_none()
+    }
+}
+
+/// the shared state the GraphQL API works on
+struct GraphQLStores {
+    site_store: Arc<SiteStore>,
+    health_monitor: Arc<HealthMonitor>,
+    announcement_store: Arc<AnnouncementStore>,
+    user_store: Arc<UserStore>,
+}
+
+fn graphql_context(req: &HttpRequest, serve_config: &web::Data<ServeConfig>, stores: &GraphQLStores) -> Context {
+    let ext = req.extensions();
+    let claims = ext.get::<Claims>();
+    let all_sites = stores.site_store.sites();
+    Context {
+        sites: visible_sites(serve_config, &all_sites, claims).into_iter().cloned().collect(),
+        claims: claims.cloned(),
+        is_admin: is_admin(serve_config, claims),
+        site_store: stores.site_store.clone(),
+        health_monitor: stores.health_monitor.clone(),
+        announcement_store: stores.announcement_store.clone(),
+        user_store: stores.user_store.clone(),
+        serve_config: serve_config.clone(),
+    }
+}
+
+/// query string of GraphQL GET requests
+#[derive(Deserialize)]
+struct GraphQLGetQuery {
+    query: String,
+    #[serde(rename = "operationName")]
+    operation_name: Option<String>,
+    /// JSON encoded variables
+    variables: Option<String>,
+}
+
 #[get("/graphql")]
 async fn handle_graphql_get(
     req: HttpRequest,
-    payload: Payload,
     schema: web::Data<Schema>,
-) -> impl Responder {
-    let context = Context {};
-    graphql_handler(&schema, &context, req, payload).await
+    serve_config: web::Data<ServeConfig>,
+    stores: web::Data<GraphQLStores>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let q = web::Query::<GraphQLGetQuery>::from_query(req.query_string())?.into_inner();
+    let variables = match q.variables {
+        Some(v) => Some(serde_json::from_str(&v).map_err(actix_web::error::ErrorBadRequest)?),
+        None => None,
+    };
+    let request = graphql_limits::BatchRequest::Single(graphql_limits::Request {
+        query: q.query,
+        operation_name: q.operation_name,
+        variables,
+    });
+    let context = graphql_context(&req, &serve_config, &stores);
+    Ok(execute_graphql(&schema, &context, &serve_config.graphql, request).await)
 }
 
 #[post("/graphql")]
 async fn handle_graphql_post(
+    req: HttpRequest,
+    body: String,
+    schema: web::Data<Schema>,
+    serve_config: web::Data<ServeConfig>,
+    stores: web::Data<GraphQLStores>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let request = match req.content_type() {
+        "application/json" => serde_json::from_str(&body).map_err(actix_web::error::ErrorBadRequest)?,
+        "application/graphql" => graphql_limits::BatchRequest::Single(graphql_limits::Request {
+            query: body,
+            operation_name: None,
+            variables: None,
+        }),
+        _ => return Err(actix_web::error::ErrorUnsupportedMediaType(
+            "GraphQL requests must have content type 'application/json' or 'application/graphql'"
+        )),
+    };
+    let context = graphql_context(&req, &serve_config, &stores);
+    Ok(execute_graphql(&schema, &context, &serve_config.graphql, request).await)
+}
+
+/// checks the request against the configured limits and executes it
+async fn execute_graphql(schema: &Schema, context: &Context, limits: &graphql_limits::GraphQLLimits, request: graphql_limits::BatchRequest) -> HttpResponse {
+    let request = match request.check(limits) {
+        Ok(r) => r,
+        Err(message) => return graphql_error(HttpResponse::BadRequest(), &message),
+    };
+    let execution = request.execute(schema, context);
+    let response = match limits.timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, execution).await {
+            Ok(response) => response,
+            Err(_) => return graphql_error(HttpResponse::ServiceUnavailable(), "request timed out"),
+        },
+        None => execution.await,
+    };
+    let mut builder = if response.is_ok() {
+        HttpResponse::Ok()
+    } else {
+        HttpResponse::BadRequest()
+    };
+    builder.json(&response)
+}
+
+fn graphql_error(mut builder: actix_web::dev::HttpResponseBuilder, message: &str) -> HttpResponse {
+    builder.json(serde_json::json!({ "errors": [{ "message": message }] }))
+}
+
+#[get("/subscriptions")]
+async fn handle_graphql_subscriptions(
     req: HttpRequest,
     payload: Payload,
     schema: web::Data<Schema>,
-) -> impl Responder {
-    let context = Context {};
-    graphql_handler(&schema, &context, req, payload).await
+    serve_config: web::Data<ServeConfig>,
+    stores: web::Data<GraphQLStores>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let context = graphql_context(&req, &serve_config, &stores);
+    graphql_ws::start(&req, payload, schema.clone(), context)
 }
 
 #[get("/graphiql")]
 async fn handle_graphiql() -> impl Responder {
-    graphiql_handler(GRAPHQL_PATH, None).await
+    graphiql_handler(GRAPHQL_PATH, Some(GRAPHQL_SUBSCRIPTIONS_PATH)).await
 }
 
 #[get("/playground")]
 async fn handle_playground() -> impl Responder {
-    playground_handler(GRAPHQL_PATH, None).await
+    playground_handler(GRAPHQL_PATH, Some(GRAPHQL_SUBSCRIPTIONS_PATH)).await
+}
+
+/// Credentials of an API request: the bearer token if the request has an
+/// Authorization header, None if the session cookie is to be used instead
+struct ApiCredentials(Option<BearerAuth>);
+
+impl AuthExtractor for ApiCredentials {
+    type Error = AuthenticationError<Bearer>;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_service_request(req: &ServiceRequest) -> Self::Future {
+        if req.headers().contains_key(http::header::AUTHORIZATION) {
+            ready(BearerAuth::from_service_request(req).into_inner().map(|b| ApiCredentials(Some(b))))
+        } else {
+            ready(Ok(ApiCredentials(None)))
+        }
+    }
 }
 
+/// validates GraphQL requests. Session authenticated requests always need
+/// the CSRF token, because GET requests may contain mutations as well.
 async fn validator(
     req: ServiceRequest,
-    credentials: BearerAuth,
+    credentials: ApiCredentials,
+) -> Result<ServiceRequest, actix_web::Error> {
+    authenticate_api_request(req, credentials, true).await
+}
+
+/// validates REST API requests. Only session authenticated requests that
+/// may change something need the CSRF token.
+async fn rest_validator(
+    req: ServiceRequest,
+    credentials: ApiCredentials,
+) -> Result<ServiceRequest, actix_web::Error> {
+    let csrf_required = !matches!(*req.method(), http::Method::GET | http::Method::HEAD);
+    authenticate_api_request(req, credentials, csrf_required).await
+}
+
+async fn authenticate_api_request(
+    req: ServiceRequest,
+    credentials: ApiCredentials,
+    csrf_required: bool,
 ) -> Result<ServiceRequest, actix_web::Error> {
     let config = req
         .app_data::<Config>()
-        .map(|data| data.clone())
-        .unwrap_or_else(Default::default);
-    let auth = req.app_data::<Arc<OidcAuth>>()
-        .map(|data| data.clone())
+        .cloned()
+        .unwrap_or_default();
+    let token = match credentials.0 {
+        Some(bearer) => bearer.token().to_string(),
+        None => {
+            // no bearer token, so fall back to the session of the /web
+            // pages. As browsers send the cookie with any request, the
+            // CSRF token must be presented as well where required.
+            let token = match cookie_auth::session_access_token(&req)? {
+                Some(t) => t,
+                None => return Err(AuthenticationError::from(config).into()),
+            };
+            // WebSocket connections cannot carry custom headers
+            let query_param_allowed = req.path() == format!("/gql{}", GRAPHQL_SUBSCRIPTIONS_PATH)
+                && cookie_auth::is_websocket_upgrade(&req);
+            if csrf_required && !cookie_auth::has_valid_csrf_token(&req, query_param_allowed) {
+                return Err(actix_web::error::ErrorForbidden("missing or invalid CSRF token"))
+            }
+            token
+        }
+    };
+    // only registered if auth is enabled, and so is this validator
+    let auth = req.app_data::<Option<Arc<OidcAuth>>>()
+        .cloned()
+        .flatten()
         .unwrap();
-    match auth.validate_token(credentials.token()).await {
-        Ok(_res) => Ok(req),
+    match auth.validate_token(&token).await {
+        Ok(claims) => {
+            req.extensions_mut().insert(claims);
+            Ok(req)
+        },
         Err(_) => Err(AuthenticationError::from(config).into()),
     }
 }
@@ -319,13 +812,15 @@ async fn validator(
 struct ResWebCookieAuthHandler {
     auth_uri: String,
     oidc_auth: Arc<OidcAuth>,
+    scopes: String,
 }
 
 impl ResWebCookieAuthHandler {
-    fn new(oidc_auth: Arc<OidcAuth>, auth_uri: String) -> ResWebCookieAuthHandler {
+    fn new(oidc_auth: Arc<OidcAuth>, auth_uri: String, scopes: String) -> ResWebCookieAuthHandler {
         ResWebCookieAuthHandler {
             auth_uri,
-            oidc_auth
+            oidc_auth,
+            scopes,
         }
     }
 }
@@ -337,7 +832,7 @@ impl cookie_auth::CookieAuthHandler for ResWebCookieAuthHandler {
     }
 
     fn client_id(&self) -> &str {
-        &self.oidc_auth.client_id()
+        self.oidc_auth.client_id()
     }
 
     fn token_exchange_path(&self) -> &str {
@@ -347,6 +842,10 @@ impl cookie_auth::CookieAuthHandler for ResWebCookieAuthHandler {
     fn auth_uri(&self) -> &str {
         &self.auth_uri
     }
+
+    fn scopes(&self) -> &str {
+        &self.scopes
+    }
 }
 
 
@@ -393,6 +892,12 @@ fn main() {
     match cfg {
         AppConfig::Serve(cfg) => {
             log::info!("Configured to listen on port {} on interfaces {:#?}", cfg.port, cfg.interface_addresses);
+            if cfg.auth.is_some() {
+                log::info!("Requesting scopes '{}' at login", cfg.requested_scopes().join(" "));
+                for w in cfg.missing_scope_warnings() {
+                    log::warn!("{}", w);
+                }
+            }
         
             tokio::runtime::Builder::new()
             .enable_all()
@@ -408,6 +913,27 @@ fn main() {
                 Err(e) => log::error!("could not write templates ({})", e)
             }
         }
+        AppConfig::CheckConfig(cfg) => {
+            let errors = cli::check_config(&cfg);
+            if errors.is_empty() {
+                log::info!("configuration file '{}' is valid", cfg.config_file);
+            } else {
+                for e in errors.iter() {
+                    log::error!("{}", e);
+                }
+                log::error!("configuration file '{}' has {} error(s)", cfg.config_file, errors.len());
+                std::process::exit(1)
+            }
+        }
+        AppConfig::PrintConfig(cfg) => {
+            print!("{}", cfg.content);
+        }
+        AppConfig::Explain(cfg) => {
+            if let Err(e) = explain::explain(&cfg) {
+                log::error!("{}", e);
+                std::process::exit(1)
+            }
+        }
     }
 }
 
@@ -418,9 +944,7 @@ async fn launch_async(serve_config: ServeConfig) -> std::io::Result<()> {
     local_set.spawn_local(async {
         async_main(serve_config).await
     });
-    local_set.run_until( async move {
-        actix_sys.await
-    }).await
+    local_set.run_until(actix_sys).await
 }
 
 async fn async_main(serve_config: ServeConfig) -> std::io::Result<()> {
@@ -432,7 +956,7 @@ async fn async_main(serve_config: ServeConfig) -> std::io::Result<()> {
     
     let oidc = match &serve_config.auth {
         Some(auth_config) => {
-            let auth = Arc::new(OidcAuth::new(auth_config.authorization_server_url.to_string(), &auth_config.client_id, None));
+            let auth = Arc::new(OidcAuth::new(auth_config.authorization_server_url.to_string(), &auth_config.client_id, auth_config.client_secret.as_deref()));
             match auth.get_oidc_config().await {
                 Err(e) => {
                     log::error!("cannot load oidc config from IDP at {}", auth_config.authorization_server_url.to_string());
@@ -450,38 +974,89 @@ async fn async_main(serve_config: ServeConfig) -> std::io::Result<()> {
         let d = resolve_template_dir(&serve_config.common);
         if d.exists() {
             log::info!("Using template directory '{}'.", d.to_string_lossy());
-            log::info!("Files not found in the template directory will be served from the internal file store");
+            log::info!("Files not found in the template directory will be served from the internal file store, theme '{}'", serve_config.theme.name());
             Some(d)
         } else {
-            log::info!("Using internal template filestore");
+            log::info!("Using internal template filestore, theme '{}'", serve_config.theme.name());
             None
         }
     };
 
+    let catalogs = match i18n::Catalogs::load(template_dir.as_deref(), serve_config.theme, &serve_config.default_locale) {
+        Ok(c) => Arc::new(c),
+        Err(e) => {
+            log::error!("cannot load translation catalogs: {}", e);
+            return Ok(())
+        }
+    };
+
+    let session_key = match &serve_config.session_key {
+        Some(k) => k.as_bytes().to_vec(),
+        None => {
+            if oidc.is_some() {
+                log::warn!("No 'session_key' configured, session cookies are encrypted with a built-in key. Configure a secret key for production use.");
+            }
+            vec![0; SESSION_KEY_MIN_LEN]
+        }
+    };
+
+    let scopes = serve_config.requested_scopes().join(" ");
+
+    let site_store = match SiteStore::open(serve_config.site_list.clone(), serve_config.site_store.clone()) {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            log::error!("cannot open site store: {}", e);
+            return Ok(())
+        }
+    };
+
+    let user_store = match UserStore::open(serve_config.user_store.clone()) {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            log::error!("cannot open user store: {}", e);
+            return Ok(())
+        }
+    };
+
+    let announcement_store = match AnnouncementStore::open(serve_config.announcements.clone(), serve_config.announcement_store.clone()) {
+        Ok(s) => Arc::new(s),
+        Err(e) => {
+            log::error!("cannot open announcement store: {}", e);
+            return Ok(())
+        }
+    };
+
+    let health_monitor = Arc::new(HealthMonitor::new());
+    health::start(health_monitor.clone(), site_store.clone());
+
     let mut actix_srv = HttpServer::new(move || {
         let mut hb = Handlebars::new();
         hb.set_dev_mode(serve_config.dev_mode_enabled);
-        let builtins = templates::resources();
-        let builtin_templates = builtins
-            .iter()
-            .filter_map(|t| match t.0.strip_suffix(HBS_SUFFIX){
-                Some(n) => Some((n, t.1)),
-                None => None
-            });
-        
-        for t in builtin_templates {
-            match hb.register_template_string(t.0, String::from_utf8_lossy(t.1)) {
-                Ok(_) => (),
-                Err(e) => panic!("could not parse internal template {}", e)
-            }
-        }
+        templates::register_builtins(&mut hb, serve_config.theme);
+        // registered after the built-in templates, so files in the template
+        // directory, partials and layouts included, override them
         if let Some(d) = template_dir.clone() {
-            hb.register_templates_directory(HBS_SUFFIX, d).unwrap();
+            hb.register_templates_directory(templates::HBS_SUFFIX, d).unwrap();
         }
-        let web_context = web::Data::new(WebContext{hb, app_config: serve_config.clone()});
+        hb.register_helper("t", Box::new(i18n::TranslateHelper { catalogs: catalogs.clone() }));
+        template_helpers::register(&mut hb);
+        let web_context = web::Data::new(WebContext{hb, app_config: serve_config.clone(), site_store: site_store.clone(), user_store: user_store.clone(), health_monitor: health_monitor.clone(), announcement_store: announcement_store.clone(), catalogs: catalogs.clone()});
+        let gql_serve_config = web::Data::new(serve_config.clone());
+        let gql_stores = web::Data::new(GraphQLStores {
+            site_store: site_store.clone(),
+            health_monitor: health_monitor.clone(),
+            announcement_store: announcement_store.clone(),
+            user_store: user_store.clone(),
+        });
+        let gql_limits = serve_config.graphql.clone();
+        let api_serve_config = web::Data::new(serve_config.clone());
+        let api_site_store = web::Data::new(site_store.clone());
+        let api_user_store = web::Data::new(user_store.clone());
+        let api_health_monitor = web::Data::new(health_monitor.clone());
+        let api_announcement_store = web::Data::new(announcement_store.clone());
 
         let cookie_auth = if let Some((oidc_config, auth)) = &oidc {
-            let h = ResWebCookieAuthHandler::new(auth.clone(), oidc_config.authorization_endpoint.clone());
+            let h = ResWebCookieAuthHandler::new(auth.clone(), oidc_config.authorization_endpoint.clone(), scopes.clone());
             Some(cookie_auth::CookieAuth::new(h))
         } else {
             None
@@ -496,23 +1071,64 @@ async fn async_main(serve_config: ServeConfig) -> std::io::Result<()> {
                     cookie_auth
                 ))
                 .wrap(Condition::new(oidc.is_some(),
-                    CookieSession::private(&[0; 32]) // <- create cookie based session middleware
-                    .secure(false)
+                    session_middleware(&session_key)
                 ))
+                .service(handle_search)
+                .service(handle_opensearch)
                 .service(handle_web)
             )
             .service(
                 web::scope("gql")
                 .data(Schema::new(
                     Query,
-                    EmptyMutation::<Context>::new(),
-                    EmptySubscription::<Context>::new(),
+                    Mutation,
+                    Subscription,
+                ))
+                .app_data(gql_serve_config)
+                .app_data(gql_stores)
+                .app_data(oidc.as_ref().map(|(_, auth)| auth.clone()))
+                .wrap(Condition::new(oidc.is_some(),
+                    HttpAuthentication::with_fn(validator)
+                ))
+                // makes the /web session available to the validator
+                .wrap(Condition::new(oidc.is_some(),
+                    session_middleware(&session_key)
                 ))
-                .wrap(HttpAuthentication::bearer(validator))
                 .service(handle_graphql_get)
                 .service(handle_graphql_post)
-                .service(handle_graphiql)
-                .service(handle_playground)
+                .service(handle_graphql_subscriptions)
+                .configure(move |cfg| {
+                    if gql_limits.graphiql {
+                        cfg.service(handle_graphiql);
+                    }
+                    if gql_limits.playground {
+                        cfg.service(handle_playground);
+                    }
+                })
+            )
+            // registered before the scope, so it is available without authentication
+            .service(rest_api::handle_openapi)
+            .service(
+                web::scope("api/v1")
+                .app_data(api_serve_config)
+                .app_data(api_site_store)
+                .app_data(api_user_store)
+                .app_data(api_health_monitor)
+                .app_data(api_announcement_store)
+                .app_data(oidc.as_ref().map(|(_, auth)| auth.clone()))
+                .wrap(Condition::new(oidc.is_some(),
+                    HttpAuthentication::with_fn(rest_validator)
+                ))
+                .wrap(Condition::new(oidc.is_some(),
+                    session_middleware(&session_key)
+                ))
+                .service(rest_api::handle_sites)
+                .service(rest_api::handle_get_preferences)
+                .service(rest_api::handle_put_preferences)
+                .service(rest_api::handle_get_links)
+                .service(rest_api::handle_put_links)
+                .service(rest_api::handle_announcements)
+                .service(rest_api::handle_dismiss_announcement)
             )
     });
 
@@ -523,6 +1139,13 @@ async fn async_main(serve_config: ServeConfig) -> std::io::Result<()> {
     actix_srv.run().await
 }
 
+/// the cookie based session shared by the /web pages and the /gql API
+fn session_middleware(session_key: &[u8]) -> CookieSession {
+    CookieSession::private(session_key)
+        .path("/")
+        .secure(false)
+}
+
 fn resolve_template_dir(cfg: &CommonConfig) -> PathBuf {
     if let Some(p) = cfg.template_dir.clone() {
         p
@@ -534,16 +1157,17 @@ fn resolve_template_dir(cfg: &CommonConfig) -> PathBuf {
 fn init_templates(cfg: &InitTemplatesConfig) -> Result<PathBuf,Box<dyn std::error::Error>> {
     let path = resolve_template_dir(&cfg.common);
 
-    match DirBuilder::new()
+    if let Err(e) = DirBuilder::new()
         .recursive(true)
-        .create(&path) {
-        Err(e) => return Err(Box::from(e)),
-        Ok(_) => (),
-    };
+        .create(&path) { return Err(Box::from(e)) };
 
-    let resources = templates::resources();
+    let resources = templates::resources(cfg.theme);
     for file_content in resources.iter() {
         let file_path = path.join(file_content.0);
+        // catalogs live in a subdirectory
+        if let Some(parent) = file_path.parent() {
+            DirBuilder::new().recursive(true).create(parent)?;
+        }
         let mut file = match OpenOptions::new().write(true).create_new(true).open(&file_path) {
             Ok(f) => f,
             Err(e) => {
@@ -556,9 +1180,113 @@ fn init_templates(cfg: &InitTemplatesConfig) -> Result<PathBuf,Box<dyn std::erro
         }
     };
     
-    log::info!("Created default templates for customization at path '{}'", path.to_string_lossy());
+    log::info!("Created templates of theme '{}' for customization at path '{}'", cfg.theme.name(), path.to_string_lossy());
     log::info!("To start customizing, let {} serve in development mode. To find out how, consult the help, like this:\n", cli::CARGO_PKG_NAME);
     log::info!("\t{} help {}\n", cli::CARGO_PKG_NAME, cli::SERVE_SCMD_NAME);
 
     Ok(path)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_unauthenticated_subscription_connections() {
+        actix_web::rt::System::new("test").block_on(async move {
+            let mut app = actix_web::test::init_service(App::new()
+                .service(web::scope("gql")
+                    .wrap(HttpAuthentication::with_fn(validator))
+                    .wrap(CookieSession::signed(&[0; SESSION_KEY_MIN_LEN]))
+                    .service(handle_graphql_subscriptions)
+                )
+            ).await;
+            let request = actix_web::test::TestRequest::get()
+                .uri(&format!("/gql{}", GRAPHQL_SUBSCRIPTIONS_PATH))
+                .header(http::header::UPGRADE, "websocket")
+                .header(http::header::CONNECTION, "upgrade")
+                .header(http::header::SEC_WEBSOCKET_VERSION, "13")
+                .header(http::header::SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+                .to_request();
+            // the validator fails the request before it is handled
+            let error = actix_web::dev::Service::call(&mut app, request).await.err().unwrap();
+            assert_eq!(error.as_response_error().status_code(), http::StatusCode::UNAUTHORIZED);
+        });
+    }
+
+    #[test]
+    fn requested_scopes_add_site_scopes_once() {
+        let cfg = cli::serve_config_from_yaml(r#"
+port: 8080
+interface_addresses: [127.0.0.1]
+scope: openid email
+site_list:
+  sites:
+    - { name: a, url: "https://a.example", claim_rules: [], required_scopes: [roles, email] }
+    - { name: b, url: "https://b.example", claim_rules: [], required_scopes: [roles, phone] }
+"#);
+        assert_eq!(cfg.requested_scopes(), ["openid", "email", "roles", "phone"]);
+    }
+
+    #[test]
+    fn warns_about_claims_of_scopes_not_requested_in_all_rules() {
+        let cfg = cli::serve_config_from_yaml(r#"
+port: 8080
+interface_addresses: [127.0.0.1]
+scope: openid profile
+admin_claim_rules:
+  - { path: email, operator: Matches, operand: { value: admin@example.com } }
+deny_claim_rules:
+  - { path: phone_number_verified, operator: Matches, operand: { value: false } }
+site_list:
+  rule_sets:
+    - name: admins
+      claim_rules:
+        - { path: realm_access.roles, operator: ContainsMatch, operand: { value: admin } }
+  sites:
+    - name: a
+      url: "https://a.example"
+      claim_rules:
+        - { path: preferred_username, operator: Matches, operand: { value: alice } }
+        - { path: address.country, operator: Matches, operand: { value: DE } }
+      deny_claim_rules:
+        - { path: email_verified, operator: Matches, operand: { value: false } }
+      when: 'phone_number == "1" || level >= 3'
+      rule_sets: [admins]
+    - name: b
+      url: "https://b.example"
+      claim_rules: []
+      rule_sets: [admins]
+"#);
+        let warnings = cfg.missing_scope_warnings();
+        let checks = warnings.iter()
+            .map(|w| w.split(" checks ").next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(checks, [
+            "admin claim rule #1",
+            "deny claim rule #1",
+            "site 'a': claim rule #2",
+            "site 'a': deny claim rule #1",
+            "site 'a': the when expression",
+            "rule set 'admins': claim rule #1",
+        ]);
+        assert!(warnings[0].contains("scope 'email'"), "{}", warnings[0]);
+    }
+
+    #[test]
+    fn no_warnings_if_scopes_are_requested() {
+        let cfg = cli::serve_config_from_yaml(r#"
+port: 8080
+interface_addresses: [127.0.0.1]
+scope: openid
+site_list:
+  sites:
+    - name: a
+      url: "https://a.example"
+      required_scopes: [email]
+      claim_rules:
+        - { path: email, operator: Matches, operand: { value: alice@example.com } }
+        - { path: groups, operator: ContainsMatch, operand: { value: it } }
+"#);
+        assert!(cfg.missing_scope_warnings().is_empty());
+    }
+}
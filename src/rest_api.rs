@@ -0,0 +1,530 @@
+//! Versioned REST API, for clients that do not want to speak GraphQL.
+//! Everything except the OpenAPI document is mounted under /api/v1 and
+//! authenticated like the GraphQL API.
+
+use std::sync::Arc;
+
+use actix_web::{get, http::header, post, put, web, HttpRequest, HttpResponse};
+use schemars::{gen::SchemaSettings, JsonSchema};
+use serde::Serialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+
+use crate::announcements::{AnnouncementStore, AnnouncementView};
+use crate::auth::Claims;
+use crate::health::{HealthMonitor, SiteHealth};
+use crate::site::Site;
+use crate::site_store::SiteStore;
+use crate::user_store::{PersonalLink, SitePreferences, UserStore};
+use crate::ServeConfig;
+
+/// A site as returned by the API. Claim rules are left out, users should
+/// not learn how visibility is decided.
+#[derive(Serialize, JsonSchema)]
+#[schemars(rename = "Site")]
+struct ApiSite<'a> {
+    name: &'a str,
+    description: Option<&'a str>,
+    #[schemars(url)]
+    url: &'a str,
+    category: Option<&'a str>,
+    tags: &'a [String],
+    /// null if the site has no health check
+    health: Option<SiteHealth>,
+}
+
+impl<'a> ApiSite<'a> {
+    fn new(site: &'a Site, health_monitor: &HealthMonitor) -> Self {
+        ApiSite {
+            name: site.name(),
+            description: site.description(),
+            url: site.url(),
+            category: site.category(),
+            tags: site.tags(),
+            health: health_monitor.health(site.name()),
+        }
+    }
+}
+
+/// the sites visible to the calling user
+#[get("/sites")]
+async fn handle_sites(
+    req: HttpRequest,
+    serve_config: web::Data<ServeConfig>,
+    site_store: web::Data<Arc<SiteStore>>,
+    health_monitor: web::Data<Arc<HealthMonitor>>,
+) -> HttpResponse {
+    let ext = req.extensions();
+    let all_sites = site_store.sites();
+    let sites = crate::visible_sites(&serve_config, &all_sites, ext.get::<Claims>())
+        .into_iter()
+        .map(|site| ApiSite::new(site, &health_monitor))
+        .collect::<Vec<_>>();
+    let body = match serde_json::to_string(&sites) {
+        Ok(b) => b,
+        Err(e) => return HttpResponse::InternalServerError().body(e.to_string()),
+    };
+
+    let etag = etag(&body);
+    let not_modified = if_none_match(&req, &etag);
+    let mut response = if not_modified {
+        HttpResponse::NotModified()
+    } else {
+        HttpResponse::Ok()
+    };
+    // the list differs per user, so shared caches must not store it
+    response
+        .set_header(header::ETAG, etag.as_str())
+        .set_header(header::CACHE_CONTROL, "private, no-cache");
+    if not_modified {
+        response.finish()
+    } else {
+        response.content_type("application/json").body(body)
+    }
+}
+
+/// the calling user's site preferences, restricted to the sites they can see
+#[get("/preferences")]
+async fn handle_get_preferences(
+    req: HttpRequest,
+    serve_config: web::Data<ServeConfig>,
+    site_store: web::Data<Arc<SiteStore>>,
+    user_store: web::Data<Arc<UserStore>>,
+) -> HttpResponse {
+    let ext = req.extensions();
+    let (claims, sub) = match user(ext.get::<Claims>()) {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    let all_sites = site_store.sites();
+    let sites = crate::visible_sites(&serve_config, &all_sites, Some(claims));
+    HttpResponse::Ok().json(user_store.preferences(sub).restricted_to(&sites))
+}
+
+/// replaces the calling user's site preferences. Sites the user cannot see
+/// are dropped, the preferences actually stored are returned.
+#[put("/preferences")]
+async fn handle_put_preferences(
+    req: HttpRequest,
+    preferences: web::Json<SitePreferences>,
+    serve_config: web::Data<ServeConfig>,
+    site_store: web::Data<Arc<SiteStore>>,
+    user_store: web::Data<Arc<UserStore>>,
+) -> HttpResponse {
+    // cloned, as the request's extensions must not be borrowed across the
+    // await of the store
+    let claims = req.extensions().get::<Claims>().cloned();
+    let (claims, sub) = match user(claims.as_ref()) {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    if let Some(locale) = preferences.locale.as_deref().filter(|l| !crate::i18n::is_valid_locale(l)) {
+        return HttpResponse::BadRequest().body(format!("'{}' is not a valid locale", locale))
+    }
+    let all_sites = site_store.sites();
+    let sites = crate::visible_sites(&serve_config, &all_sites, Some(claims));
+    let preferences = preferences.restricted_to(&sites);
+    match user_store.set_preferences(sub, preferences.clone()).await {
+        Ok(()) => HttpResponse::Ok().json(preferences),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("cannot store preferences")
+        }
+    }
+}
+
+/// the calling user's personal links
+#[get("/links")]
+async fn handle_get_links(
+    req: HttpRequest,
+    user_store: web::Data<Arc<UserStore>>,
+) -> HttpResponse {
+    let ext = req.extensions();
+    match user(ext.get::<Claims>()) {
+        Ok((_, sub)) => HttpResponse::Ok().json(user_store.links(sub)),
+        Err(response) => response,
+    }
+}
+
+/// replaces the calling user's personal links
+#[put("/links")]
+async fn handle_put_links(
+    req: HttpRequest,
+    links: web::Json<Vec<PersonalLink>>,
+    user_store: web::Data<Arc<UserStore>>,
+) -> HttpResponse {
+    let claims = req.extensions().get::<Claims>().cloned();
+    let sub = match user(claims.as_ref()) {
+        Ok((_, sub)) => sub,
+        Err(response) => return response,
+    };
+    let links = links.into_inner();
+    if let Err(e) = crate::user_store::validate_links(&links) {
+        return HttpResponse::BadRequest().body(e)
+    }
+    match user_store.set_links(sub, links.clone()).await {
+        Ok(()) => HttpResponse::Ok().json(links),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("cannot store personal links")
+        }
+    }
+}
+
+/// the active announcements for the calling user that they did not
+/// dismiss, most severe first
+#[get("/announcements")]
+async fn handle_announcements(
+    req: HttpRequest,
+    serve_config: web::Data<ServeConfig>,
+    site_store: web::Data<Arc<SiteStore>>,
+    user_store: web::Data<Arc<UserStore>>,
+    announcement_store: web::Data<Arc<AnnouncementStore>>,
+) -> HttpResponse {
+    let ext = req.extensions();
+    let claims = ext.get::<Claims>();
+    let sub = claims.and_then(Claims::sub);
+    let dismissed = sub
+        .map(|sub| user_store.dismissed_announcements(sub))
+        .unwrap_or_default();
+    let all_sites = site_store.sites();
+    let sites = crate::visible_sites(&serve_config, &all_sites, claims);
+    let all_announcements = announcement_store.announcements();
+    let announcements = crate::visible_announcements(&serve_config, &all_announcements, &sites, claims, &dismissed)
+        .into_iter()
+        .map(|a| AnnouncementView::new(a, sub.is_some()))
+        .collect::<Vec<_>>();
+    HttpResponse::Ok()
+        .set_header(header::CACHE_CONTROL, "private, no-cache")
+        .json(announcements)
+}
+
+/// hides the announcement from the calling user
+#[post("/announcements/{id}/dismiss")]
+async fn handle_dismiss_announcement(
+    req: HttpRequest,
+    web::Path(id): web::Path<String>,
+    serve_config: web::Data<ServeConfig>,
+    site_store: web::Data<Arc<SiteStore>>,
+    user_store: web::Data<Arc<UserStore>>,
+    announcement_store: web::Data<Arc<AnnouncementStore>>,
+) -> HttpResponse {
+    let claims = req.extensions().get::<Claims>().cloned();
+    let (claims, sub) = match user(claims.as_ref()) {
+        Ok(u) => u,
+        Err(response) => return response,
+    };
+    let all_sites = site_store.sites();
+    let sites = crate::visible_sites(&serve_config, &all_sites, Some(claims));
+    let all_announcements = announcement_store.announcements();
+    let visible = crate::visible_announcements(&serve_config, &all_announcements, &sites, Some(claims), &[]);
+    if !visible.iter().any(|a| a.id() == id) {
+        return HttpResponse::NotFound().body(format!("there is no announcement with id '{}'", id))
+    }
+    let existing = visible.iter().map(|a| a.id()).collect::<Vec<_>>();
+    match user_store.dismiss_announcement(sub, &id, &existing).await {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(e) => {
+            log::error!("{}", e);
+            HttpResponse::InternalServerError().body("cannot store dismissed announcement")
+        }
+    }
+}
+
+/// the claims and subject of the calling user. Per-user data requires a
+/// user, so it is not available with authentication disabled.
+fn user(claims: Option<&Claims>) -> Result<(&Claims, &str), HttpResponse> {
+    let claims = claims.ok_or_else(|| HttpResponse::NotFound()
+        .body("user data is not available with authentication disabled"))?;
+    let sub = claims.sub().ok_or_else(|| HttpResponse::BadRequest()
+        .body("the access token has no 'sub' claim"))?;
+    Ok((claims, sub))
+}
+
+/// a strong entity tag for the given response body
+fn etag(body: &str) -> String {
+    // a digest of the body, so that it is the same for every server instance
+    // and release
+    let digest = Sha256::digest(body.as_bytes());
+    let hex = digest.iter().take(16).map(|b| format!("{:02x}", b)).collect::<String>();
+    format!("\"{}\"", hex)
+}
+
+/// true if the request's If-None-Match header matches the entity tag
+fn if_none_match(req: &HttpRequest, etag: &str) -> bool {
+    req.headers().get(header::IF_NONE_MATCH)
+        .and_then(|h| h.to_str().ok())
+        .map(|h| h.split(',')
+            .map(|t| t.trim())
+            .any(|t| t == "*" || t.trim_start_matches("W/") == etag)
+        )
+        .unwrap_or(false)
+}
+
+#[get("/api/v1/openapi.json")]
+async fn handle_openapi() -> HttpResponse {
+    HttpResponse::Ok().json(openapi())
+}
+
+/// the schemas of the request and response bodies, generated from the
+/// types they are serialized from
+fn component_schemas() -> serde_json::Value {
+    let mut generator = SchemaSettings::openapi3().into_generator();
+    generator.subschema_for::<ApiSite>();
+    generator.subschema_for::<PersonalLink>();
+    generator.subschema_for::<SitePreferences>();
+    generator.subschema_for::<AnnouncementView>();
+    // the visitors adapt the schemas to OpenAPI, like wrapping references
+    // that have a description in allOf
+    let mut definitions = generator.take_definitions();
+    for visitor in generator.visitors_mut() {
+        for schema in definitions.values_mut() {
+            visitor.visit_schema(schema);
+        }
+    }
+    json!(definitions)
+}
+
+fn openapi() -> serde_json::Value {
+    let string = json!({ "type": "string" });
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "resweb API",
+            "version": crate::cli::CARGO_PKG_VERSION,
+        },
+        "servers": [{ "url": "/api/v1" }],
+        "security": [{ "bearerAuth": [] }, { "sessionCookie": [] }],
+        "paths": {
+            "/sites": {
+                "get": {
+                    "summary": "the sites visible to the calling user",
+                    "operationId": "getSites",
+                    "responses": {
+                        "200": {
+                            "description": "the visible sites, in configured order. The user's arrangement is part of the preferences",
+                            "headers": {
+                                "ETag": { "schema": string },
+                            },
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/Site" },
+                                    },
+                                },
+                            },
+                        },
+                        "304": { "description": "the site list matches the If-None-Match header" },
+                        "401": { "description": "no or invalid access token" },
+                    },
+                },
+            },
+            "/links": {
+                "get": {
+                    "summary": "the calling user's personal links",
+                    "operationId": "getLinks",
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/Links" },
+                        "401": { "description": "no or invalid access token" },
+                    },
+                },
+                "put": {
+                    "summary": "replaces the calling user's personal links",
+                    "description": "URL and icon must be http or https URLs. Session authenticated requests must carry the CSRF token in the X-CSRF-Token header.",
+                    "operationId": "putLinks",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": {
+                                    "type": "array",
+                                    "maxItems": 100,
+                                    "items": { "$ref": "#/components/schemas/PersonalLink" },
+                                },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/Links" },
+                        "400": { "description": "a link is invalid" },
+                        "401": { "description": "no or invalid access token" },
+                        "403": { "description": "missing or invalid CSRF token" },
+                    },
+                },
+            },
+            "/announcements": {
+                "get": {
+                    "summary": "the active announcements for the calling user, most severe first",
+                    "description": "Announcements the user dismissed are left out.",
+                    "operationId": "getAnnouncements",
+                    "responses": {
+                        "200": {
+                            "description": "the announcements",
+                            "content": {
+                                "application/json": {
+                                    "schema": {
+                                        "type": "array",
+                                        "items": { "$ref": "#/components/schemas/Announcement" },
+                                    },
+                                },
+                            },
+                        },
+                        "401": { "description": "no or invalid access token" },
+                    },
+                },
+            },
+            "/announcements/{id}/dismiss": {
+                "post": {
+                    "summary": "hides the announcement from the calling user",
+                    "description": "Session authenticated requests must carry the CSRF token in the X-CSRF-Token header.",
+                    "operationId": "dismissAnnouncement",
+                    "parameters": [{ "name": "id", "in": "path", "required": true, "schema": string }],
+                    "responses": {
+                        "204": { "description": "the announcement was dismissed" },
+                        "401": { "description": "no or invalid access token" },
+                        "403": { "description": "missing or invalid CSRF token" },
+                        "404": { "description": "the user has no active announcement with this id" },
+                    },
+                },
+            },
+            "/preferences": {
+                "get": {
+                    "summary": "the calling user's site preferences",
+                    "operationId": "getPreferences",
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/Preferences" },
+                        "401": { "description": "no or invalid access token" },
+                    },
+                },
+                "put": {
+                    "summary": "replaces the calling user's site preferences",
+                    "description": "Sites the user cannot see are dropped. Session authenticated requests must carry the CSRF token in the X-CSRF-Token header.",
+                    "operationId": "putPreferences",
+                    "requestBody": {
+                        "required": true,
+                        "content": {
+                            "application/json": {
+                                "schema": { "$ref": "#/components/schemas/Preferences" },
+                            },
+                        },
+                    },
+                    "responses": {
+                        "200": { "$ref": "#/components/responses/Preferences" },
+                        "401": { "description": "no or invalid access token" },
+                        "403": { "description": "missing or invalid CSRF token" },
+                    },
+                },
+            },
+        },
+        "components": {
+            "schemas": component_schemas(),
+            "responses": {
+                "Links": {
+                    "description": "the personal links as stored",
+                    "content": {
+                        "application/json": {
+                            "schema": {
+                                "type": "array",
+                                "items": { "$ref": "#/components/schemas/PersonalLink" },
+                            },
+                        },
+                    },
+                },
+                "Preferences": {
+                    "description": "the preferences as stored",
+                    "content": {
+                        "application/json": {
+                            "schema": { "$ref": "#/components/schemas/Preferences" },
+                        },
+                    },
+                },
+            },
+            "securitySchemes": {
+                "bearerAuth": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "bearerFormat": "JWT",
+                },
+                "sessionCookie": {
+                    "type": "apiKey",
+                    "in": "cookie",
+                    "name": "actix-session",
+                    "description": "the session of the dashboard web pages",
+                },
+            },
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test, App};
+
+    #[test]
+    fn etags_do_not_depend_on_the_process() {
+        // the first half of the SHA-256 digest of the empty string
+        assert_eq!(etag(""), "\"e3b0c44298fc1c149afbf4c8996fb924\"");
+        assert_ne!(etag("[]"), etag("[{}]"));
+    }
+
+    #[test]
+    fn sites_are_not_sent_again_if_unmodified() {
+        let serve_config = crate::cli::serve_config_from_yaml(r#"
+port: 8081
+interface_addresses: [127.0.0.1]
+site_list:
+  sites:
+  - name: Wiki
+    url: https://wiki.example
+"#);
+        let site_store = Arc::new(SiteStore::open(serve_config.site_list.clone(), None).unwrap());
+        actix_web::rt::System::new("test").block_on(async move {
+            let mut app = test::init_service(App::new()
+                .app_data(web::Data::new(serve_config))
+                .app_data(web::Data::new(site_store))
+                .app_data(web::Data::new(Arc::new(HealthMonitor::new())))
+                .service(handle_sites)
+            ).await;
+
+            let response = test::call_service(&mut app, test::TestRequest::get().uri("/sites").to_request()).await;
+            assert_eq!(response.status(), 200);
+            let etag = response.headers().get(header::ETAG).unwrap().clone();
+            let sites: serde_json::Value = serde_json::from_slice(&test::read_body(response).await).unwrap();
+            assert_eq!(sites[0]["name"], "Wiki");
+
+            let request = test::TestRequest::get().uri("/sites").header(header::IF_NONE_MATCH, etag.clone()).to_request();
+            let response = test::call_service(&mut app, request).await;
+            assert_eq!(response.status(), 304);
+            assert_eq!(response.headers().get(header::ETAG), Some(&etag));
+            assert!(test::read_body(response).await.is_empty());
+
+            let request = test::TestRequest::get().uri("/sites").header(header::IF_NONE_MATCH, "\"other\"").to_request();
+            assert_eq!(test::call_service(&mut app, request).await.status(), 200);
+        });
+    }
+
+    fn references(value: &serde_json::Value) -> Vec<&str> {
+        match value {
+            serde_json::Value::Object(o) => o.iter()
+                .flat_map(|(k, v)| match (k.as_str(), v.as_str()) {
+                    ("$ref", Some(r)) => vec![r],
+                    _ => references(v),
+                })
+                .collect(),
+            serde_json::Value::Array(a) => a.iter().flat_map(references).collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn openapi_references_resolve() {
+        let document = openapi();
+        let references = references(&document);
+        assert!(references.contains(&"#/components/schemas/SiteHealth"));
+        for reference in references {
+            assert!(document.pointer(reference.trim_start_matches('#')).is_some(), "{} does not resolve", reference);
+        }
+    }
+}
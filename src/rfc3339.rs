@@ -0,0 +1,16 @@
+//! (de)serializes optional times in RFC 3339 format, like
+//! `2021-06-05T08:00:00+02:00`. Use with `#[serde(default, with = "crate::rfc3339")]`.
+
+use chrono::{DateTime, FixedOffset};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+pub fn serialize<S: Serializer>(time: &Option<DateTime<FixedOffset>>, serializer: S) -> Result<S::Ok, S::Error> {
+    time.map(|t| t.to_rfc3339()).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<DateTime<FixedOffset>>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|s| DateTime::parse_from_rfc3339(&s)
+            .map_err(|e| D::Error::custom(format!("cannot parse time '{}': {}", s, e))))
+        .transpose()
+}
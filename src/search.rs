@@ -0,0 +1,155 @@
+//! Ranked, typo-tolerant search over sites. Every word of the query must
+//! match one of a site's fields; the better and the more important the
+//! matching field, the higher the site ranks.
+
+use crate::site::Site;
+
+/// weights of the fields a site is searched by
+const NAME_WEIGHT: f64 = 4.0;
+const KEYWORD_WEIGHT: f64 = 3.0;
+const CATEGORY_WEIGHT: f64 = 2.0;
+const DESCRIPTION_WEIGHT: f64 = 1.0;
+
+/// quality of a match between a query term and a word
+const EXACT: f64 = 1.0;
+const PREFIX: f64 = 0.8;
+const SUBSTRING: f64 = 0.6;
+const FUZZY: f64 = 0.3;
+
+/// query terms shorter than this must not contain typos
+const FUZZY_MIN_LEN: usize = 4;
+
+pub struct SearchHit<'a> {
+    pub site: &'a Site,
+    pub score: f64,
+}
+
+/// Returns the sites matching the query, best matches first. Sites with the
+/// same score keep their order.
+pub fn search<'a>(sites: &[&'a Site], query: &str) -> Vec<SearchHit<'a>> {
+    let terms = words(query);
+    if terms.is_empty() {
+        return Vec::new()
+    }
+    let mut hits = sites.iter()
+        .filter_map(|site| score(site, &terms).map(|score| SearchHit { site, score }))
+        .collect::<Vec<_>>();
+    // sort_by is stable, so equal scores keep the sites' order
+    hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap());
+    hits
+}
+
+/// the score of the site, None if a term does not match
+fn score(site: &Site, terms: &[String]) -> Option<f64> {
+    let fields: Vec<(f64, Vec<String>)> = vec![
+        (NAME_WEIGHT, words(site.name())),
+        (KEYWORD_WEIGHT, site.keywords().iter().flat_map(|k| words(k)).collect()),
+        (CATEGORY_WEIGHT, site.category().map(words).unwrap_or_default()),
+        (DESCRIPTION_WEIGHT, site.description().map(words).unwrap_or_default()),
+    ];
+    terms.iter()
+        .map(|term| fields.iter()
+            .flat_map(|(weight, words)| words.iter().map(move |w| weight * match_quality(term, w)))
+            .fold(0.0, f64::max)
+        )
+        .try_fold(0.0, |total, term_score| if term_score > 0.0 {
+            Some(total + term_score)
+        } else {
+            None
+        })
+}
+
+fn match_quality(term: &str, word: &str) -> f64 {
+    if word == term {
+        EXACT
+    } else if word.starts_with(term) {
+        PREFIX
+    } else if word.contains(term) {
+        SUBSTRING
+    } else if term.chars().count() >= FUZZY_MIN_LEN && within_one_edit(term, word) {
+        FUZZY
+    } else {
+        0.0
+    }
+}
+
+/// true if the strings differ by at most one inserted, deleted or
+/// replaced character
+fn within_one_edit(a: &str, b: &str) -> bool {
+    let a = a.chars().collect::<Vec<_>>();
+    let b = b.chars().collect::<Vec<_>>();
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if long.len() - short.len() > 1 {
+        return false
+    }
+    let prefix = short.iter().zip(long.iter()).take_while(|(x, y)| x == y).count();
+    if prefix == long.len() {
+        true
+    } else if short.len() == long.len() {
+        short[prefix + 1..] == long[prefix + 1..]
+    } else {
+        short[prefix..] == long[prefix + 1..]
+    }
+}
+
+/// splits the text into lower case words
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &str, description: Option<&str>, keywords: &[&str], category: Option<&str>) -> Site {
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "description": description,
+            "url": "https://example.com",
+            "category": category,
+            "keywords": keywords,
+            "claim_rules": [],
+        })).unwrap()
+    }
+
+    fn names(sites: &[Site], query: &str) -> Vec<String> {
+        search(&sites.iter().collect::<Vec<_>>(), query)
+            .iter()
+            .map(|h| h.site.name().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn ranks_by_field_and_match_quality() {
+        let sites = vec![
+            site("Wiki", Some("knowledge base for the jira workflow"), &[], None),
+            site("Issue Tracker", None, &["jira"], Some("Development")),
+            site("Jira", None, &[], None),
+        ];
+        assert_eq!(names(&sites, "jira"), vec!["Jira", "Issue Tracker", "Wiki"]);
+        assert_eq!(names(&sites, "issue dev"), vec!["Issue Tracker"]);
+        assert!(names(&sites, "grafana").is_empty());
+    }
+
+    #[test]
+    fn tolerates_typos_in_longer_terms() {
+        let sites = vec![site("Grafana", None, &[], None)];
+        assert_eq!(names(&sites, "grafna"), vec!["Grafana"]);
+        assert_eq!(names(&sites, "grafanna"), vec!["Grafana"]);
+        assert!(names(&sites, "grefena").is_empty());
+        assert!(names(&sites, "gra x").is_empty());
+    }
+
+    #[test]
+    fn one_edit() {
+        assert!(within_one_edit("abc", "abc"));
+        assert!(within_one_edit("abc", "abd"));
+        assert!(within_one_edit("abc", "ab"));
+        assert!(within_one_edit("abc", "xabc"));
+        assert!(!within_one_edit("abc", "cab"));
+        assert!(!within_one_edit("abc", "a"));
+    }
+}
@@ -1,14 +1,34 @@
 
+use std::collections::BTreeMap;
+use std::sync::{atomic::{AtomicBool, Ordering}, Arc};
+
+use chrono::{DateTime, FixedOffset, Utc};
+use schemars::JsonSchema;
 use serde::{Serialize, Deserialize};
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+use crate::auth::Claims;
+use crate::expr::Expression;
+
+/// deployment variables, like `environment: staging`, which sites can be
+/// conditioned on
+pub type Variables = BTreeMap<String, String>;
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct SiteList {
+    /// named lists of claim rules that sites can refer to in their `rule_sets`
+    #[serde(default)]
+    rule_sets: Vec<RuleSet>,
+    /// the sites that can appear on the dashboard
     sites: Vec<Site>,
 }
 
 impl SiteList {
     pub fn new() -> Self {
-        SiteList{ sites: Vec::new()}
+        SiteList{ rule_sets: Vec::new(), sites: Vec::new()}
+    }
+
+    pub fn with_rule_sets(rule_sets: Vec<RuleSet>, sites: Vec<Site>) -> Self {
+        SiteList{ rule_sets, sites }
     }
 }
 
@@ -16,35 +36,803 @@ impl SiteList {
     pub fn sites(&self) -> &Vec<Site> {
         &self.sites
     }
+
+    pub fn into_parts(self) -> (Vec<RuleSet>, Vec<Site>) {
+        (self.rule_sets, self.sites)
+    }
+
+    /// Returns a description of every problem with the rule sets and the
+    /// sites, including references to unknown rule sets
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        for (i, set) in self.rule_sets.iter().enumerate() {
+            errors.extend(set.validate()
+                .into_iter()
+                .map(|e| format!("rule set '{}': {}", set.name(), e)));
+            if self.rule_sets[..i].iter().any(|other| other.name() == set.name()) {
+                errors.push(format!("rule set name '{}' is used more than once", set.name()))
+            }
+        }
+        for (i, site) in self.sites.iter().enumerate() {
+            // preferences, health and announcements refer to sites by name
+            if self.sites[..i].iter().any(|other| other.name() == site.name()) {
+                errors.push(format!("site name '{}' is used more than once", site.name()))
+            }
+            errors.extend(site.validate()
+                .into_iter()
+                .chain(site.clone().resolve_rule_sets(&self.rule_sets).err())
+                .map(|e| format!("site '{}': {}", site.name(), e)));
+        }
+        errors
+    }
+
+    /// resolves the rule sets the sites refer to, see [`Site::resolve_rule_sets`]
+    pub fn resolve_rule_sets(&mut self) -> Result<(), String> {
+        for site in self.sites.iter_mut() {
+            site.resolve_rule_sets(&self.rule_sets)
+                .map_err(|e| format!("site '{}': {}", site.name, e))?;
+        }
+        Ok(())
+    }
+}
+
+/// A named list of claim rules, for rules that several sites share
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct RuleSet {
+    /// the name sites refer to the rule set by
+    name: String,
+    /// a site referring to the rule set is displayed if any of these rules
+    /// match the user's access token
+    claim_rules: Vec<ClaimRule>,
+}
+
+impl RuleSet {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn claim_rules(&self) -> &[ClaimRule] {
+        &self.claim_rules
+    }
+
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.name.trim().is_empty() {
+            errors.push("name must not be empty".to_string());
+        }
+        for (i, r) in self.claim_rules.iter().enumerate() {
+            if let Some(e) = r.validate() {
+                errors.push(format!("claim rule #{} {}", i + 1, e));
+            }
+        }
+        errors
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct Site {
+    /// name displayed on the dashboard
     name: String,
     description: Option<String>,
+    /// absolute URL the site is linked to
     url: String,
+    /// category for grouping sites on the dashboard
+    category: Option<String>,
+    /// free-form tags for filtering sites
+    #[serde(default)]
+    tags: Vec<String>,
+    /// alternative names and terms the site can be found by when searching
+    #[serde(default)]
+    keywords: Vec<String>,
+    /// name and description in other languages, by locale, like
+    /// `de: {description: Firmenwiki}`
+    #[serde(default)]
+    translations: BTreeMap<String, SiteTranslation>,
+    /// the site is displayed if any of these rules match the user's access token
+    #[serde(default)]
     pub claim_rules: Vec<ClaimRule>,
+    /// names of rule sets; the site is also displayed if any of their rules
+    /// match the user's access token
+    #[serde(default)]
+    rule_sets: Vec<String>,
+    /// the rule sets named in `rule_sets`, once resolved
+    #[serde(skip)]
+    resolved_rule_sets: Vec<RuleSet>,
+    /// the site is also displayed if this expression is true for the user's
+    /// access token, like `'wiki_admin' in realm_access.roles || level >= 3`
+    #[serde(default)]
+    when: Option<Expression>,
+    /// set once a failure to evaluate `when` has been logged, so that it is
+    /// not logged again for every request. Shared by the clones of the site
+    #[serde(skip)]
+    when_failure_logged: Arc<AtomicBool>,
+    /// the site is hidden from users whose access token matches any of these
+    /// rules, even if claim_rules match
+    #[serde(default)]
+    pub deny_claim_rules: Vec<ClaimRule>,
+    /// OpenID scopes that must be requested at login for the claims checked
+    /// by claim_rules to be present in the access token
+    #[serde(default)]
+    required_scopes: Vec<String>,
+    /// checks periodically whether the site is up, if set
+    #[serde(default)]
+    health_check: Option<HealthCheck>,
+    /// time from which the site is shown, like `2021-06-05T08:00:00+02:00`.
+    /// Shown right away if not set
+    #[serde(default, with = "crate::rfc3339")]
+    #[schemars(with = "Option<String>")]
+    visible_from: Option<DateTime<FixedOffset>>,
+    /// time until which the site is shown. Shown until removed if not set
+    #[serde(default, with = "crate::rfc3339")]
+    #[schemars(with = "Option<String>")]
+    visible_until: Option<DateTime<FixedOffset>>,
+    /// the site is only shown if all of these conditions on the deployment
+    /// variables hold, whatever the claims
+    #[serde(default)]
+    conditions: Vec<VariableCondition>,
+}
+
+impl Site {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    pub fn translations(&self) -> &BTreeMap<String, SiteTranslation> {
+        &self.translations
+    }
+
+    /// the translations for the locale and its more general forms, most
+    /// specific first
+    fn translations_for<'a>(&'a self, locale: &'a str) -> impl Iterator<Item = &'a SiteTranslation> {
+        let locale = crate::i18n::normalize(locale);
+        let mut translations = self.translations.iter()
+            .filter_map(|(l, t)| {
+                let l = crate::i18n::normalize(l);
+                crate::i18n::fallbacks(&locale).position(|f| f == l).map(|rank| (rank, t))
+            })
+            .collect::<Vec<_>>();
+        translations.sort_by_key(|(rank, _)| *rank);
+        translations.into_iter().map(|(_, t)| t)
+    }
+
+    /// the name to display in the locale, falls back to `name`
+    pub fn localized_name<'a>(&'a self, locale: &'a str) -> &'a str {
+        self.translations_for(locale)
+            .find_map(|t| t.name.as_deref())
+            .unwrap_or(&self.name)
+    }
+
+    /// the description in the locale, falls back to `description`
+    pub fn localized_description<'a>(&'a self, locale: &'a str) -> Option<&'a str> {
+        self.translations_for(locale)
+            .find_map(|t| t.description.as_deref())
+            .or(self.description.as_deref())
+    }
+
+    pub fn tags(&self) -> &[String] {
+        &self.tags
+    }
+
+    pub fn keywords(&self) -> &[String] {
+        &self.keywords
+    }
+
+    pub fn required_scopes(&self) -> &[String] {
+        &self.required_scopes
+    }
+
+    pub fn health_check(&self) -> Option<&HealthCheck> {
+        self.health_check.as_ref()
+    }
+
+    pub fn visible_from(&self) -> Option<&DateTime<FixedOffset>> {
+        self.visible_from.as_ref()
+    }
+
+    pub fn visible_until(&self) -> Option<&DateTime<FixedOffset>> {
+        self.visible_until.as_ref()
+    }
+
+    pub fn conditions(&self) -> &[VariableCondition] {
+        &self.conditions
+    }
+
+    pub fn when(&self) -> Option<&Expression> {
+        self.when.as_ref()
+    }
+
+    pub fn rule_sets(&self) -> &[String] {
+        &self.rule_sets
+    }
+
+    /// the rule sets named in `rule_sets`, empty until they are resolved
+    pub fn resolved_rule_sets(&self) -> &[RuleSet] {
+        &self.resolved_rule_sets
+    }
+
+    /// Looks up the rule sets the site refers to, so that their rules are
+    /// evaluated by [`Site::visibility`]. Fails if one of them is unknown.
+    pub fn resolve_rule_sets(&mut self, rule_sets: &[RuleSet]) -> Result<(), String> {
+        let mut resolved = Vec::with_capacity(self.rule_sets.len());
+        for name in self.rule_sets.iter() {
+            match rule_sets.iter().find(|s| &s.name == name) {
+                Some(s) => resolved.push(s.clone()),
+                None => return Err(format!("refers to unknown rule set '{}'", name))
+            }
+        }
+        self.resolved_rule_sets = resolved;
+        Ok(())
+    }
+
+    /// true if `now` lies between `visible_from` and `visible_until`
+    pub fn is_within_window(&self, now: DateTime<Utc>) -> bool {
+        self.visible_from.is_none_or(|t| t <= now) && self.visible_until.is_none_or(|t| now < t)
+    }
+
+    /// true if the site is shown on this instance at the given time. Claim
+    /// rules are checked on top of this.
+    pub fn is_enabled(&self, now: DateTime<Utc>, variables: &Variables) -> bool {
+        self.is_within_window(now) && self.conditions.iter().all(|c| c.holds(variables))
+    }
+
+    /// Decides whether the site is shown to a user with the given claims.
+    /// The first of these steps that applies decides:
+    /// 1. a site that is not enabled (see [`Site::is_enabled`]) is hidden
+    /// 2. if any of the policy's deny rules matches, the site is hidden
+    /// 3. if any of the site's deny rules matches, the site is hidden
+    /// 4. if any of the site's claim rules or the rules of its rule sets
+    ///    matches, or its `when` expression is true, the site is visible
+    /// 5. a site without claim rules, rule sets and `when` expression is
+    ///    visible if the policy says so
+    /// 6. otherwise, the site is hidden
+    pub fn visibility(&self, claims: &Claims, policy: &VisibilityPolicy, now: DateTime<Utc>) -> Visibility {
+        let first_match = |rules: &[ClaimRule]| rules.iter()
+            .position(|r| r.evaluate(claims) == RuleOutcome::Matched);
+        if !self.is_enabled(now, policy.variables) {
+            Visibility::Disabled
+        } else if let Some(i) = first_match(policy.deny_claim_rules) {
+            Visibility::DeniedGlobally(i)
+        } else if let Some(i) = first_match(&self.deny_claim_rules) {
+            Visibility::Denied(i)
+        } else if let Some(i) = first_match(&self.claim_rules) {
+            Visibility::Allowed(i)
+        } else if let Some((set, i)) = self.resolved_rule_sets.iter()
+            .enumerate()
+            .find_map(|(set, s)| first_match(&s.claim_rules).map(|i| (set, i))) {
+            Visibility::AllowedByRuleSet(set, i)
+        } else if self.when.as_ref().is_some_and(|w| w.evaluate(claims).unwrap_or_else(|e| {
+            if !self.when_failure_logged.swap(true, Ordering::Relaxed) {
+                log::warn!("cannot evaluate the when expression of site '{}': {} (further failures are logged at debug level)", self.name, e);
+            } else {
+                log::debug!("cannot evaluate the when expression of site '{}': {}", self.name, e);
+            }
+            false
+        })) {
+            Visibility::AllowedByExpression
+        } else if self.claim_rules.is_empty() && self.rule_sets.is_empty() && self.when.is_none() {
+            if policy.default_visible {
+                Visibility::DefaultVisible
+            } else {
+                Visibility::DefaultHidden
+            }
+        } else {
+            Visibility::NoRuleMatched
+        }
+    }
+
+    /// Returns a description of every problem with this site that can be
+    /// detected without contacting it.
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Err(e) = url::Url::parse(&self.url) {
+            errors.push(format!("cannot parse url '{}': {}", self.url, e));
+        }
+        for (i, r) in self.claim_rules.iter().enumerate() {
+            if let Some(e) = r.validate() {
+                errors.push(format!("claim rule #{} {}", i + 1, e));
+            }
+        }
+        for (i, r) in self.deny_claim_rules.iter().enumerate() {
+            if let Some(e) = r.validate() {
+                errors.push(format!("deny claim rule #{} {}", i + 1, e));
+            }
+        }
+        for (i, name) in self.rule_sets.iter().enumerate() {
+            if self.rule_sets[..i].contains(name) {
+                errors.push(format!("rule set '{}' is referred to more than once", name));
+            }
+        }
+        for locale in self.translations.keys() {
+            if !crate::i18n::is_valid_locale(locale) {
+                errors.push(format!("translation locale '{}' is not a valid language tag like 'de' or 'de-CH'", locale));
+            }
+        }
+        if let (Some(from), Some(until)) = (self.visible_from, self.visible_until) {
+            if until <= from {
+                errors.push("visible_until must be after visible_from".to_string());
+            }
+        }
+        for (i, c) in self.conditions.iter().enumerate() {
+            if c.variable.trim().is_empty() {
+                errors.push(format!("condition #{} has an empty variable name", i + 1));
+            }
+        }
+        if let Some(h) = &self.health_check {
+            errors.extend(h.validate().into_iter().map(|e| format!("health check {}", e)));
+        }
+        errors
+    }
+}
+
+/// The name and description of a site in another language. Fields that
+/// are not set are taken from the site.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default, PartialEq)]
+pub struct SiteTranslation {
+    pub name: Option<String>,
+    pub description: Option<String>,
+}
+
+/// How to find out whether a site is up. The site is requested with GET
+/// every `interval_secs` seconds.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    /// absolute URL to request, defaults to the site's url
+    pub url: Option<String>,
+    /// HTTP status the site must respond with to be up. If not set, any
+    /// status below 400 means the site is up
+    pub expected_status: Option<u16>,
+    /// seconds between two checks, defaults to 60
+    pub interval_secs: Option<u64>,
+    /// seconds to wait for a response before the site is considered down,
+    /// defaults to 10
+    pub timeout_secs: Option<u64>,
+}
+
+impl HealthCheck {
+    const DEFAULT_INTERVAL_SECS: u64 = 60;
+    const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+    pub fn interval(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.interval_secs.unwrap_or(Self::DEFAULT_INTERVAL_SECS))
+    }
+
+    pub fn timeout(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(self.timeout_secs.unwrap_or(Self::DEFAULT_TIMEOUT_SECS))
+    }
+
+    /// true if a response with the given status means the site is up
+    pub fn accepts(&self, status: u16) -> bool {
+        match self.expected_status {
+            Some(expected) => status == expected,
+            None => status < 400,
+        }
+    }
+
+    fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if let Some(u) = &self.url {
+            if let Err(e) = url::Url::parse(u) {
+                errors.push(format!("cannot parse url '{}': {}", u, e));
+            }
+        }
+        if let Some(status) = self.expected_status {
+            if !(100..=599).contains(&status) {
+                errors.push(format!("has an invalid expected_status {}", status));
+            }
+        }
+        for (key, value) in [("interval_secs", self.interval_secs), ("timeout_secs", self.timeout_secs)].iter() {
+            if *value == Some(0) {
+                errors.push(format!("'{}' must be greater than 0", key));
+            }
+        }
+        errors
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 pub struct ClaimRule {
+    /// dot-separated path of the claim in the access token, like `realm_access.roles`
     pub path: String,
     pub operator: Operator,
     pub operand: Operand,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// The settings of an instance that decide about the visibility of all sites
+pub struct VisibilityPolicy<'a> {
+    /// rules hiding every site from the users they match
+    pub deny_claim_rules: &'a [ClaimRule],
+    /// whether sites without claim rules are visible
+    pub default_visible: bool,
+    pub variables: &'a Variables,
+}
+
+/// The result of [`Site::visibility`], with the step that decided it. Rules
+/// are referenced by their index.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Visibility {
+    /// hidden: outside of the visibility window or a condition does not hold
+    Disabled,
+    /// hidden by a global deny rule
+    DeniedGlobally(usize),
+    /// hidden by one of the site's deny rules
+    Denied(usize),
+    /// visible because of one of the site's claim rules
+    Allowed(usize),
+    /// visible because of a rule of one of the site's rule sets: the index
+    /// of the rule set and of the rule in it
+    AllowedByRuleSet(usize, usize),
+    /// visible because the site's `when` expression is true
+    AllowedByExpression,
+    /// visible because the site has neither claim rules, rule sets nor a `when`
+    /// expression, and sites are visible by default
+    DefaultVisible,
+    /// hidden because the site has neither claim rules, rule sets nor a `when`
+    /// expression, and sites are hidden by default
+    DefaultHidden,
+    /// hidden because none of the site's claim rules matched and its `when`
+    /// expression is not true
+    NoRuleMatched,
+}
+
+impl Visibility {
+    pub fn is_visible(&self) -> bool {
+        matches!(self, Visibility::Allowed(_) | Visibility::AllowedByRuleSet(..) | Visibility::AllowedByExpression | Visibility::DefaultVisible)
+    }
+}
+
+impl std::fmt::Display for Visibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Visibility::Disabled => write!(f, "hidden, because it is outside of its visibility window or a condition does not hold"),
+            Visibility::DeniedGlobally(i) => write!(f, "hidden by global deny claim rule #{}", i + 1),
+            Visibility::Denied(i) => write!(f, "hidden by deny claim rule #{}", i + 1),
+            Visibility::Allowed(i) => write!(f, "visible because of claim rule #{}", i + 1),
+            Visibility::AllowedByRuleSet(set, i) => write!(f, "visible because of claim rule #{} of rule set #{}", i + 1, set + 1),
+            Visibility::AllowedByExpression => write!(f, "visible because its when expression is true"),
+            Visibility::DefaultVisible => write!(f, "visible, because it has neither claim rules, rule sets nor a when expression and sites are visible by default"),
+            Visibility::DefaultHidden => write!(f, "hidden, because it has neither claim rules, rule sets nor a when expression and sites are hidden by default"),
+            Visibility::NoRuleMatched => write!(f, "hidden, because no claim rule matched and no when expression is true"),
+        }
+    }
+}
+
+/// A condition on a deployment variable, like `{variable: environment, value: staging}`
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
+pub struct VariableCondition {
+    /// name of the variable
+    pub variable: String,
+    /// the variable must be equal to the value, or match the regex
+    #[serde(flatten)]
+    pub operand: Operand,
+}
+
+impl VariableCondition {
+    /// true if the variable is set and matches. Variables are strings, so
+    /// only string values can match.
+    pub fn holds(&self, variables: &Variables) -> bool {
+        variables.get(&self.variable)
+            .is_some_and(|v| self.operand.matches(&serde_json::Value::String(v.clone())))
+    }
+}
+
+impl std::fmt::Display for VariableCondition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} matches {}", self.variable, self.operand)
+    }
+}
+
+/// The result of evaluating a single [`ClaimRule`] against a set of claims
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuleOutcome {
+    Matched,
+    /// the claims do not contain the rule's path
+    PathMissing,
+    /// `ContainsMatch` was used on a claim that is not an array
+    NotAnArray,
+    /// a regex was used on a claim that is not a string
+    NotAString,
+    /// the claim has the right type, but its value does not match
+    NoMatch,
+}
+
+impl std::fmt::Display for RuleOutcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            RuleOutcome::Matched => "matched",
+            RuleOutcome::PathMissing => "path not found in claims",
+            RuleOutcome::NotAnArray => "type mismatch: claim is not an array",
+            RuleOutcome::NotAString => "type mismatch: claim is not a string",
+            RuleOutcome::NoMatch => "value does not match",
+        })
+    }
+}
+
+/// Returns true if any of the rules matches the claims
+pub fn any_rule_matches(rules: &[ClaimRule], claims: &Claims) -> bool {
+    rules.iter().any(|r| r.evaluate(claims) == RuleOutcome::Matched)
+}
+
+impl ClaimRule {
+    /// Returns a description of the problem if the rule is invalid
+    pub fn validate(&self) -> Option<String> {
+        if self.path.split('.').any(str::is_empty) {
+            Some(format!("has an invalid path '{}'", self.path))
+        } else {
+            None
+        }
+    }
+
+    pub fn evaluate(&self, claims: &Claims) -> RuleOutcome {
+        let v = match claims.get_path(self.path.as_str()) {
+            Some(v) => v,
+            None => return RuleOutcome::PathMissing
+        };
+
+        match self.operator {
+            Operator::Matches => match &self.operand {
+                Operand::Regex{..} if !v.is_string() => RuleOutcome::NotAString,
+                _ if self.operand.matches(v) => RuleOutcome::Matched,
+                _ => RuleOutcome::NoMatch
+            },
+            Operator::ContainsMatch => match v.as_array() {
+                Some(a) if a.iter().any(|v| self.operand.matches(v)) => RuleOutcome::Matched,
+                Some(_) => RuleOutcome::NoMatch,
+                None => RuleOutcome::NotAnArray
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for ClaimRule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} {:?} {}", self.path, self.operator, self.operand)
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone)]
 #[serde(untagged)]
 pub enum Operand {
-    Value{ value: serde_json::Value },
+    Value{
+        /// the claim must be equal to this JSON value
+        value: serde_json::Value
+    },
     Regex{ 
+        /// the claim must be a string matching this regular expression
         #[serde(with = "serde_regex")]
+        #[schemars(with = "String")]
         regex: regex::Regex 
     },
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl Operand {
+    pub fn matches(&self, v: &serde_json::Value) -> bool {
+        match self {
+            Operand::Value {value} => v.eq(value),
+            Operand::Regex {regex} => if let Some(s)=v.as_str() {
+                    regex.is_match(s)
+                } else {
+                    false
+                }
+        }
+    }
+}
+
+impl std::fmt::Display for Operand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operand::Value {value} => write!(f, "{}", value),
+            Operand::Regex {regex} => write!(f, "/{}/", regex),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, juniper::GraphQLEnum, Debug, Clone)]
 pub enum Operator {
+    /// the claim itself must match the operand
     Matches,
+    /// the claim must be an array containing an element that matches the operand
     ContainsMatch
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(value: serde_json::Value) -> Site {
+        let mut s = serde_json::json!({ "name": "a", "url": "https://a.example", "claim_rules": [] });
+        s.as_object_mut().unwrap().extend(value.as_object().unwrap().clone());
+        serde_json::from_value(s).unwrap()
+    }
+
+    fn time(s: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn is_visible_within_window() {
+        let s = site(serde_json::json!({
+            "visible_from": "2021-06-01T00:00:00+02:00",
+            "visible_until": "2021-06-02T00:00:00+02:00",
+        }));
+        assert!(!s.is_within_window(time("2021-05-31T21:59:59Z")));
+        assert!(s.is_within_window(time("2021-05-31T22:00:00Z")));
+        assert!(!s.is_within_window(time("2021-06-01T22:00:00Z")));
+        assert!(s.validate().is_empty());
+
+        let reversed = site(serde_json::json!({
+            "visible_from": "2021-06-02T00:00:00Z",
+            "visible_until": "2021-06-01T00:00:00Z",
+        }));
+        assert_eq!(reversed.validate(), vec!["visible_until must be after visible_from"]);
+    }
+
+    #[test]
+    fn requires_all_conditions() {
+        let s = site(serde_json::json!({
+            "conditions": [
+                { "variable": "environment", "value": "staging" },
+                { "variable": "region", "regex": "^eu-" },
+            ],
+        }));
+        let vars = |pairs: &[(&str, &str)]| pairs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect::<Variables>();
+        let now = Utc::now();
+        assert!(s.is_enabled(now, &vars(&[("environment", "staging"), ("region", "eu-west")])));
+        assert!(!s.is_enabled(now, &vars(&[("environment", "production"), ("region", "eu-west")])));
+        assert!(!s.is_enabled(now, &vars(&[("environment", "staging")])));
+        assert!(site(serde_json::json!({})).is_enabled(now, &Variables::new()));
+    }
+
+    fn rule(path: &str, value: serde_json::Value) -> ClaimRule {
+        ClaimRule { path: path.into(), operator: Operator::ContainsMatch, operand: Operand::Value { value } }
+    }
+
+    #[test]
+    fn deny_rules_take_precedence() {
+        let claims = Claims::from(serde_json::json!({ "groups": ["admins", "partners"], "flags": ["suspended"] }));
+        let variables = Variables::new();
+        let no_global_rules = VisibilityPolicy { deny_claim_rules: &[], default_visible: false, variables: &variables };
+        let global_rules = [rule("flags", "suspended".into())];
+        let global = VisibilityPolicy { deny_claim_rules: &global_rules, ..no_global_rules };
+        let now = Utc::now();
+
+        let console = site(serde_json::json!({
+            "claim_rules": [rule("groups", "users".into()), rule("groups", "admins".into())],
+        }));
+        assert_eq!(console.visibility(&claims, &no_global_rules, now), Visibility::Allowed(1));
+        assert_eq!(console.visibility(&claims, &global, now), Visibility::DeniedGlobally(0));
+
+        let internal = site(serde_json::json!({
+            "claim_rules": [rule("groups", "admins".into())],
+            "deny_claim_rules": [rule("groups", "partners".into())],
+        }));
+        assert_eq!(internal.visibility(&claims, &no_global_rules, now), Visibility::Denied(0));
+        assert_eq!(internal.visibility(&claims, &global, now), Visibility::DeniedGlobally(0));
+
+        let disabled = site(serde_json::json!({
+            "claim_rules": [rule("groups", "admins".into())],
+            "visible_until": "2000-01-01T00:00:00Z",
+        }));
+        assert_eq!(disabled.visibility(&claims, &global, now), Visibility::Disabled);
+    }
+
+    #[test]
+    fn default_policy_applies_to_sites_without_claim_rules() {
+        let claims = Claims::from(serde_json::json!({ "groups": ["users"], "flags": ["suspended"] }));
+        let variables = Variables::new();
+        let hidden = VisibilityPolicy { deny_claim_rules: &[], default_visible: false, variables: &variables };
+        let visible = VisibilityPolicy { default_visible: true, ..hidden };
+        let now = Utc::now();
+
+        let open = site(serde_json::json!({}));
+        assert_eq!(open.visibility(&claims, &hidden, now), Visibility::DefaultHidden);
+        assert_eq!(open.visibility(&claims, &visible, now), Visibility::DefaultVisible);
+
+        let restricted = site(serde_json::json!({ "claim_rules": [rule("groups", "admins".into())] }));
+        assert_eq!(restricted.visibility(&claims, &visible, now), Visibility::NoRuleMatched);
+
+        let denied = site(serde_json::json!({ "deny_claim_rules": [rule("flags", "suspended".into())] }));
+        assert_eq!(denied.visibility(&claims, &visible, now), Visibility::Denied(0));
+        assert!(!denied.visibility(&claims, &visible, now).is_visible());
+    }
+
+    #[test]
+    fn rule_sets_allow_in_addition_to_claim_rules() {
+        let claims = Claims::from(serde_json::json!({ "groups": ["ops"] }));
+        let variables = Variables::new();
+        let policy = VisibilityPolicy { deny_claim_rules: &[], default_visible: true, variables: &variables };
+        let now = Utc::now();
+        let list = |sites: serde_json::Value| serde_json::from_value::<SiteList>(serde_json::json!({
+            "rule_sets": [
+                { "name": "staff", "claim_rules": [rule("groups", "staff".into())] },
+                { "name": "operators", "claim_rules": [rule("groups", "admins".into()), rule("groups", "ops".into())] },
+            ],
+            "sites": sites,
+        })).unwrap();
+
+        let mut sites = list(serde_json::json!([
+            { "name": "a", "url": "https://a.example", "claim_rules": [rule("groups", "staff".into())], "rule_sets": ["staff", "operators"] },
+            { "name": "b", "url": "https://b.example", "rule_sets": ["staff"] },
+        ]));
+        assert!(sites.validate().is_empty());
+        sites.resolve_rule_sets().unwrap();
+        let (_, sites) = sites.into_parts();
+        assert_eq!(sites[0].visibility(&claims, &policy, now), Visibility::AllowedByRuleSet(1, 1));
+        // a site with only rule sets is not visible by default
+        assert_eq!(sites[1].visibility(&claims, &policy, now), Visibility::NoRuleMatched);
+
+        let mut unknown = list(serde_json::json!([{ "name": "c", "url": "https://c.example", "rule_sets": ["auditors"] }]));
+        assert_eq!(unknown.validate(), vec!["site 'c': refers to unknown rule set 'auditors'"]);
+        assert!(unknown.resolve_rule_sets().is_err());
+
+        let duplicate = list(serde_json::json!([
+            { "name": "a", "url": "https://a.example" },
+            { "name": "a", "url": "https://b.example" },
+        ]));
+        assert_eq!(duplicate.validate(), vec!["site name 'a' is used more than once"]);
+    }
+
+    #[test]
+    fn when_expression_allows_in_addition_to_claim_rules() {
+        let claims = Claims::from(serde_json::json!({ "groups": ["users"], "dept": "IT", "level": 3 }));
+        let variables = Variables::new();
+        let policy = VisibilityPolicy { deny_claim_rules: &[], default_visible: true, variables: &variables };
+        let now = Utc::now();
+
+        let by_rule = site(serde_json::json!({
+            "claim_rules": [rule("groups", "users".into())],
+            "when": "dept == 'HR'",
+        }));
+        assert_eq!(by_rule.visibility(&claims, &policy, now), Visibility::Allowed(0));
+
+        let by_expression = site(serde_json::json!({ "when": "dept == 'IT' && level >= 3" }));
+        assert_eq!(by_expression.visibility(&claims, &policy, now), Visibility::AllowedByExpression);
+
+        // an expression that is false hides the site, despite the default policy
+        let neither = site(serde_json::json!({ "when": "'admins' in groups" }));
+        assert_eq!(neither.visibility(&claims, &policy, now), Visibility::NoRuleMatched);
+
+        let invalid = serde_json::from_value::<Site>(serde_json::json!({
+            "name": "a", "url": "https://a.example", "when": "level >= true",
+        }));
+        assert_eq!(invalid.unwrap_err().to_string(), "invalid expression at 1:10: expected a number or a string, found a boolean");
+
+        // evaluation failures hide the site, and are logged once per site
+        let too_many = Claims::from(serde_json::json!({ "groups": vec!["users"; 20_000] }));
+        let failing = site(serde_json::json!({ "when": "'admins' in groups" }));
+        assert!(!failing.when_failure_logged.load(Ordering::Relaxed));
+        assert_eq!(failing.visibility(&too_many, &policy, now), Visibility::NoRuleMatched);
+        assert!(failing.clone().when_failure_logged.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn localizes_name_and_description() {
+        let s = site(serde_json::json!({
+            "description": "Company wiki",
+            "translations": {
+                "de": { "name": "Wiki (intern)", "description": "Firmenwiki" },
+                "de-CH": { "description": "Firmenwiki Schweiz" },
+            },
+        }));
+        assert_eq!(s.localized_name("de-ch"), "Wiki (intern)");
+        assert_eq!(s.localized_description("de-ch"), Some("Firmenwiki Schweiz"));
+        assert_eq!(s.localized_description("de-AT"), Some("Firmenwiki"));
+        assert_eq!(s.localized_name("fr"), "a");
+        assert_eq!(s.localized_description("fr"), Some("Company wiki"));
+
+        let invalid = site(serde_json::json!({ "translations": { "en US": {} } }));
+        assert_eq!(invalid.validate().len(), 1);
+    }
+}
@@ -0,0 +1,383 @@
+use std::{path::PathBuf, sync::{Arc, Mutex, RwLock}};
+
+use tokio::sync::broadcast;
+
+use crate::site::{RuleSet, Site, SiteList};
+
+/// number of unreceived change notifications kept per subscriber. As a
+/// notification carries no data, subscribers that fall behind lose nothing.
+const CHANGES_CAPACITY: usize = 16;
+
+/// Holds the sites shown on the dashboard: the static sites from the
+/// configuration file, followed by the sites managed at runtime by admins.
+/// Likewise, the rule sets the sites can refer to are the static ones
+/// followed by the managed ones.
+///
+/// Managed sites and rule sets are persisted to a JSON file, if one is
+/// configured, so they survive restarts. Static ones are read-only, and
+/// static sites can only refer to static rule sets.
+pub struct SiteStore {
+    static_sites: Vec<Site>,
+    static_rule_sets: Vec<RuleSet>,
+    store_path: Option<PathBuf>,
+    /// held while a change is validated and persisted, so that changes are
+    /// written in the order they are made. Readers never wait for it, the
+    /// locks below are only held to swap in the changed state.
+    writer: Mutex<()>,
+    managed: RwLock<Managed>,
+    /// snapshot of all sites with their rule sets resolved, rebuilt on
+    /// every change
+    all_sites: RwLock<Arc<Vec<Site>>>,
+    /// notifies subscribers whenever the sites change
+    changes: broadcast::Sender<()>,
+}
+
+/// the sites and rule sets managed at runtime
+#[derive(Clone, Default)]
+struct Managed {
+    rule_sets: Vec<RuleSet>,
+    sites: Vec<Site>,
+}
+
+impl SiteStore {
+    /// Creates the store, loading previously managed sites and rule sets
+    /// from `store_path` if that file exists. The static sites' rule sets
+    /// must already be resolved.
+    pub fn open(static_site_list: SiteList, store_path: Option<PathBuf>) -> Result<SiteStore, String> {
+        let (static_rule_sets, static_sites) = static_site_list.into_parts();
+        let (rule_sets, sites) = match &store_path {
+            Some(p) if p.exists() => {
+                let content = std::fs::read_to_string(p)
+                    .map_err(|e| format!("cannot read site store {}: {}", p.to_string_lossy(), e))?;
+                let site_list: SiteList = serde_json::from_str(&content)
+                    .map_err(|e| format!("cannot parse site store {}: {}", p.to_string_lossy(), e))?;
+                site_list.into_parts()
+            },
+            _ => (Vec::new(), Vec::new())
+        };
+
+        let store = SiteStore {
+            static_sites,
+            static_rule_sets,
+            store_path,
+            writer: Mutex::new(()),
+            managed: RwLock::new(Managed::default()),
+            all_sites: RwLock::new(Arc::new(Vec::new())),
+            changes: broadcast::channel(CHANGES_CAPACITY).0,
+        };
+        let mut loaded = Managed::default();
+        for rule_set in rule_sets {
+            store.validate_rule_set(&loaded, &rule_set, None)?;
+            loaded.rule_sets.push(rule_set);
+        }
+        for site in sites {
+            store.validate(&loaded, &site, None)?;
+            loaded.sites.push(site);
+        }
+        store.replace_managed(loaded);
+        Ok(store)
+    }
+
+    /// returns all sites, static ones first
+    pub fn sites(&self) -> Arc<Vec<Site>> {
+        self.all_sites.read().unwrap().clone()
+    }
+
+    /// returns all rule sets, static ones first
+    pub fn rule_sets(&self) -> Vec<RuleSet> {
+        self.static_rule_sets.iter()
+            .chain(self.managed.read().unwrap().rule_sets.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// returns a receiver that is notified every time the sites change
+    pub fn subscribe(&self) -> broadcast::Receiver<()> {
+        self.changes.subscribe()
+    }
+
+    pub fn is_managed(&self, name: &str) -> bool {
+        self.managed.read().unwrap()
+            .sites
+            .iter()
+            .any(|s| s.name() == name)
+    }
+
+    pub fn is_managed_rule_set(&self, name: &str) -> bool {
+        self.managed.read().unwrap()
+            .rule_sets
+            .iter()
+            .any(|s| s.name() == name)
+    }
+
+    pub fn create(&self, site: Site) -> Result<Site, String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed();
+        self.validate(&managed, &site, None)?;
+        managed.sites.push(site.clone());
+        self.persist_and_replace(managed)?;
+        Ok(site)
+    }
+
+    pub fn update(&self, name: &str, site: Site) -> Result<Site, String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed();
+        let index = self.managed_index(&managed.sites, name)?;
+        self.validate(&managed, &site, Some(name))?;
+        managed.sites[index] = site.clone();
+        self.persist_and_replace(managed)?;
+        Ok(site)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed();
+        let index = self.managed_index(&managed.sites, name)?;
+        managed.sites.remove(index);
+        self.persist_and_replace(managed)
+    }
+
+    /// Puts the managed sites into the given order. `names` must contain the
+    /// name of every managed site exactly once.
+    pub fn reorder(&self, names: &[String]) -> Result<(), String> {
+        let _writer = self.writer.lock().unwrap();
+        let managed = self.managed();
+        if names.len() != managed.sites.len() {
+            return Err(format!("expected the names of all {} managed sites, but got {}", managed.sites.len(), names.len()))
+        }
+        let mut sites = Vec::with_capacity(names.len());
+        for name in names {
+            if sites.iter().any(|s: &Site| s.name() == name) {
+                return Err(format!("site '{}' is listed more than once", name))
+            }
+            sites.push(managed.sites[self.managed_index(&managed.sites, name)?].clone());
+        }
+        self.persist_and_replace(Managed { sites, ..managed })
+    }
+
+    pub fn create_rule_set(&self, rule_set: RuleSet) -> Result<RuleSet, String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed();
+        self.validate_rule_set(&managed, &rule_set, None)?;
+        managed.rule_sets.push(rule_set.clone());
+        self.persist_and_replace(managed)?;
+        Ok(rule_set)
+    }
+
+    /// Replaces the rule set with the given name. Sites referring to it use
+    /// the new rules right away. It can only be renamed if no site refers
+    /// to it.
+    pub fn update_rule_set(&self, name: &str, rule_set: RuleSet) -> Result<RuleSet, String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed();
+        let index = self.managed_rule_set_index(&managed.rule_sets, name)?;
+        self.validate_rule_set(&managed, &rule_set, Some(name))?;
+        if rule_set.name() != name {
+            self.ensure_unreferenced(&managed, name)?;
+        }
+        managed.rule_sets[index] = rule_set.clone();
+        self.persist_and_replace(managed)?;
+        Ok(rule_set)
+    }
+
+    /// Deletes the rule set with the given name, which no site may refer to
+    pub fn delete_rule_set(&self, name: &str) -> Result<(), String> {
+        let _writer = self.writer.lock().unwrap();
+        let mut managed = self.managed();
+        let index = self.managed_rule_set_index(&managed.rule_sets, name)?;
+        self.ensure_unreferenced(&managed, name)?;
+        managed.rule_sets.remove(index);
+        self.persist_and_replace(managed)
+    }
+
+    fn managed_index(&self, managed: &[Site], name: &str) -> Result<usize, String> {
+        match managed.iter().position(|s| s.name() == name) {
+            Some(i) => Ok(i),
+            None if self.static_sites.iter().any(|s| s.name() == name) =>
+                Err(format!("site '{}' is defined in the configuration file and cannot be changed at runtime", name)),
+            None => Err(format!("there is no site named '{}'", name))
+        }
+    }
+
+    fn managed_rule_set_index(&self, managed: &[RuleSet], name: &str) -> Result<usize, String> {
+        match managed.iter().position(|s| s.name() == name) {
+            Some(i) => Ok(i),
+            None if self.static_rule_sets.iter().any(|s| s.name() == name) =>
+                Err(format!("rule set '{}' is defined in the configuration file and cannot be changed at runtime", name)),
+            None => Err(format!("there is no rule set named '{}'", name))
+        }
+    }
+
+    /// Applies the same checks as configuration loading, and makes sure
+    /// that site names stay unique and rule sets exist. `replaced` is the
+    /// name of the site that `site` replaces, if any.
+    fn validate(&self, managed: &Managed, site: &Site, replaced: Option<&str>) -> Result<(), String> {
+        let errors = site.validate();
+        if !errors.is_empty() {
+            return Err(format!("site '{}': {}", site.name(), errors.join("; ")))
+        }
+        let name_taken = self.static_sites.iter()
+            .chain(managed.sites.iter())
+            .any(|s| s.name() == site.name() && Some(s.name()) != replaced);
+        if name_taken {
+            return Err(format!("a site named '{}' already exists", site.name()))
+        }
+        site.clone().resolve_rule_sets(&self.all_rule_sets(managed))
+            .map_err(|e| format!("site '{}': {}", site.name(), e))
+    }
+
+    /// like [`SiteStore::validate`], for rule sets
+    fn validate_rule_set(&self, managed: &Managed, rule_set: &RuleSet, replaced: Option<&str>) -> Result<(), String> {
+        let errors = rule_set.validate();
+        if !errors.is_empty() {
+            return Err(format!("rule set '{}': {}", rule_set.name(), errors.join("; ")))
+        }
+        let name_taken = self.static_rule_sets.iter()
+            .chain(managed.rule_sets.iter())
+            .any(|s| s.name() == rule_set.name() && Some(s.name()) != replaced);
+        if name_taken {
+            return Err(format!("a rule set named '{}' already exists", rule_set.name()))
+        }
+        Ok(())
+    }
+
+    fn ensure_unreferenced(&self, managed: &Managed, rule_set: &str) -> Result<(), String> {
+        match managed.sites.iter().find(|s| s.rule_sets().iter().any(|r| r == rule_set)) {
+            Some(site) => Err(format!("rule set '{}' is still used by site '{}'", rule_set, site.name())),
+            None => Ok(())
+        }
+    }
+
+    fn all_rule_sets(&self, managed: &Managed) -> Vec<RuleSet> {
+        self.static_rule_sets.iter()
+            .chain(managed.rule_sets.iter())
+            .cloned()
+            .collect()
+    }
+
+    /// a copy of the managed sites and rule sets, to change and pass to
+    /// [`SiteStore::persist_and_replace`]
+    fn managed(&self) -> Managed {
+        self.managed.read().unwrap().clone()
+    }
+
+    /// Persists the changed sites and rule sets and makes them current. The
+    /// caller must hold the writer lock.
+    fn persist_and_replace(&self, updated: Managed) -> Result<(), String> {
+        if let Some(p) = &self.store_path {
+            let site_list = SiteList::with_rule_sets(updated.rule_sets.clone(), updated.sites.clone());
+            crate::store::persist_json(p, &site_list, "site store")?;
+        }
+        self.replace_managed(updated);
+        Ok(())
+    }
+
+    fn replace_managed(&self, updated: Managed) {
+        let rule_sets = self.all_rule_sets(&updated);
+        let all = self.static_sites.iter()
+            .cloned()
+            .chain(updated.sites.iter().map(|s| {
+                let mut site = s.clone();
+                // every change was validated, so all rule sets exist
+                if let Err(e) = site.resolve_rule_sets(&rule_sets) {
+                    log::error!("site '{}': {}", site.name(), e);
+                }
+                site
+            }))
+            .collect();
+        *self.managed.write().unwrap() = updated;
+        *self.all_sites.write().unwrap() = Arc::new(all);
+        // sending only fails if nobody is subscribed, which is fine
+        let _ = self.changes.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(name: &str, rule_sets: &[&str]) -> Site {
+        serde_json::from_value(serde_json::json!({ "name": name, "url": "https://a.example", "rule_sets": rule_sets })).unwrap()
+    }
+
+    fn rule_set(name: &str) -> RuleSet {
+        serde_json::from_value(serde_json::json!({ "name": name, "claim_rules": [] })).unwrap()
+    }
+
+    /// the static site `Wiki`, which refers to the static rule set `staff`
+    fn open(store_path: Option<PathBuf>) -> SiteStore {
+        let mut site_list = SiteList::with_rule_sets(vec![rule_set("staff")], vec![site("Wiki", &["staff"])]);
+        site_list.resolve_rule_sets().unwrap();
+        SiteStore::open(site_list, store_path).unwrap()
+    }
+
+    fn names(store: &SiteStore) -> Vec<String> {
+        store.sites().iter().map(|s| s.name().to_string()).collect()
+    }
+
+    #[test]
+    fn static_sites_and_rule_sets_are_read_only() {
+        let store = open(None);
+        assert_eq!(
+            store.update("Wiki", site("Wiki", &[])).unwrap_err(),
+            "site 'Wiki' is defined in the configuration file and cannot be changed at runtime"
+        );
+        assert!(store.delete("Wiki").is_err());
+        assert!(store.reorder(&["Wiki".into()]).is_err());
+        assert!(store.update_rule_set("staff", rule_set("staff")).is_err());
+        assert!(store.delete_rule_set("staff").is_err());
+        assert_eq!(store.delete("Mail").unwrap_err(), "there is no site named 'Mail'");
+        assert_eq!(names(&store), vec!["Wiki"]);
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let store = open(None);
+        assert_eq!(store.create(site("Wiki", &[])).unwrap_err(), "a site named 'Wiki' already exists");
+        store.create(site("Mail", &[])).unwrap();
+        assert!(store.create(site("Mail", &[])).is_err());
+        store.create(site("Chat", &[])).unwrap();
+        assert_eq!(store.update("Chat", site("Mail", &[])).unwrap_err(), "a site named 'Mail' already exists");
+        // keeping its own name is no conflict
+        store.update("Chat", site("Chat", &["staff"])).unwrap();
+        assert_eq!(store.create_rule_set(rule_set("staff")).unwrap_err(), "a rule set named 'staff' already exists");
+        assert_eq!(store.reorder(&["Mail".into(), "Mail".into()]).unwrap_err(), "site 'Mail' is listed more than once");
+        assert_eq!(names(&store), vec!["Wiki", "Mail", "Chat"]);
+    }
+
+    #[test]
+    fn rule_sets_in_use_are_kept() {
+        let store = open(None);
+        store.create_rule_set(rule_set("ops")).unwrap();
+        store.create(site("Grafana", &["ops", "staff"])).unwrap();
+        assert_eq!(store.create(site("Mail", &["auditors"])).unwrap_err(), "site 'Mail': refers to unknown rule set 'auditors'");
+        assert_eq!(store.delete_rule_set("ops").unwrap_err(), "rule set 'ops' is still used by site 'Grafana'");
+        assert!(store.update_rule_set("ops", rule_set("operators")).is_err());
+        // the rules can change without renaming
+        store.update_rule_set("ops", rule_set("ops")).unwrap();
+
+        store.delete("Grafana").unwrap();
+        store.update_rule_set("ops", rule_set("operators")).unwrap();
+        store.delete_rule_set("operators").unwrap();
+        assert!(store.rule_sets().iter().map(RuleSet::name).eq(["staff"]));
+    }
+
+    #[test]
+    fn reloads_managed_sites_and_rule_sets() {
+        let path = std::env::temp_dir().join(format!("resweb-site-store-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store = open(Some(path.clone()));
+        store.create_rule_set(rule_set("ops")).unwrap();
+        store.create(site("Mail", &[])).unwrap();
+        store.create(site("Grafana", &["ops"])).unwrap();
+        store.reorder(&["Grafana".into(), "Mail".into()]).unwrap();
+        drop(store);
+
+        let reloaded = open(Some(path.clone()));
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(names(&reloaded), vec!["Wiki", "Grafana", "Mail"]);
+        assert!(reloaded.is_managed("Grafana") && !reloaded.is_managed("Wiki"));
+        assert!(reloaded.is_managed_rule_set("ops"));
+        assert_eq!(reloaded.sites()[1].resolved_rule_sets()[0].name(), "ops");
+    }
+}
@@ -0,0 +1,18 @@
+//! Helpers shared by the file-backed stores
+
+use std::path::Path;
+
+use serde::Serialize;
+
+/// Writes the value as pretty-printed JSON to the store file at `path`.
+/// The JSON goes to a temporary file first, which then replaces the store,
+/// so that a crash cannot leave a half-written store behind. `what` names
+/// the store in errors, like `site store`.
+pub fn persist_json<T: Serialize>(path: &Path, value: &T, what: &str) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(value)
+        .map_err(|e| e.to_string())?;
+    let tmp_path = path.with_extension("tmp");
+    std::fs::write(&tmp_path, content)
+        .and_then(|_| std::fs::rename(&tmp_path, path))
+        .map_err(|e| format!("cannot write {} {}: {}", what, path.to_string_lossy(), e))
+}
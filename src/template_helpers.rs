@@ -0,0 +1,216 @@
+//! Handlebars helpers for templates. The claim helpers check the
+//! `access_token` of the page with [`ClaimRule`]s, so they follow the same
+//! path and operator rules as the sites' `claim_rules`:
+//!
+//! ```handlebars
+//! {{#if (claim_contains "realm_access.roles" "admin")}}...{{/if}}
+//! ```
+
+use chrono::{DateTime, FixedOffset, TimeZone, Utc, format::{Item, StrftimeItems}};
+use handlebars::{Context, Handlebars, Helper, HelperDef, RenderContext, RenderError, ScopedJson};
+use serde_json::Value;
+
+use crate::auth::Claims;
+use crate::site::{ClaimRule, Operand, Operator, RuleOutcome};
+
+/// format of `format_date` if none is given
+const DEFAULT_DATE_FORMAT: &str = "%Y-%m-%d %H:%M";
+
+/// Registers all helpers of this module
+pub fn register(hb: &mut Handlebars) {
+    hb.register_helper("has_claim", Box::new(ClaimHelper::HasClaim));
+    hb.register_helper("claim_contains", Box::new(ClaimHelper::Contains));
+    hb.register_helper("claim_matches", Box::new(ClaimHelper::Matches));
+    hb.register_helper("format_date", Box::new(FormatDateHelper));
+    hb.register_helper("json", Box::new(JsonHelper));
+    hb.register_helper("url_encode", Box::new(UrlEncodeHelper));
+}
+
+fn param<'a>(h: &'a Helper, index: usize, helper: &str, what: &str) -> Result<&'a Value, RenderError> {
+    h.param(index)
+        .map(|p| p.value())
+        .ok_or_else(|| RenderError::new(format!("{}: {} is missing", helper, what)))
+}
+
+fn str_param<'a>(h: &'a Helper, index: usize, helper: &str, what: &str) -> Result<&'a str, RenderError> {
+    param(h, index, helper, what)?
+        .as_str()
+        .ok_or_else(|| RenderError::new(format!("{}: {} must be a string", helper, what)))
+}
+
+/// The claim helpers, each of which evaluates a claim rule against the
+/// `access_token` of the page. Without a token, they are all false.
+enum ClaimHelper {
+    /// `has_claim path`: the token contains the claim
+    HasClaim,
+    /// `claim_contains path value`: the claim is an array containing the value
+    Contains,
+    /// `claim_matches path regex`: the claim is a string matching the regex
+    Matches,
+}
+
+impl ClaimHelper {
+    fn name(&self) -> &'static str {
+        match self {
+            ClaimHelper::HasClaim => "has_claim",
+            ClaimHelper::Contains => "claim_contains",
+            ClaimHelper::Matches => "claim_matches",
+        }
+    }
+
+    /// the rule the helper's parameters describe
+    fn rule(&self, h: &Helper) -> Result<ClaimRule, RenderError> {
+        let name = self.name();
+        let path = str_param(h, 0, name, "the claim path")?.to_string();
+        let (operator, operand) = match self {
+            // any value matches, so only the path matters
+            ClaimHelper::HasClaim => (Operator::Matches, Operand::Regex { regex: regex::Regex::new("").unwrap() }),
+            ClaimHelper::Contains => (Operator::ContainsMatch, Operand::Value { value: param(h, 1, name, "the value")?.clone() }),
+            ClaimHelper::Matches => {
+                let regex = regex::Regex::new(str_param(h, 1, name, "the regex")?)
+                    .map_err(|e| RenderError::new(format!("{}: invalid regex: {}", name, e)))?;
+                (Operator::Matches, Operand::Regex { regex })
+            }
+        };
+        let rule = ClaimRule { path, operator, operand };
+        match rule.validate() {
+            Some(e) => Err(RenderError::new(format!("{}: the rule {}", name, e))),
+            None => Ok(rule),
+        }
+    }
+}
+
+impl HelperDef for ClaimHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        ctx: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let rule = self.rule(h)?;
+        let claims = Claims::from(ctx.data().get("access_token").cloned().unwrap_or(Value::Null));
+        let result = match (self, rule.evaluate(&claims)) {
+            (ClaimHelper::HasClaim, outcome) => outcome != RuleOutcome::PathMissing,
+            (_, outcome) => outcome == RuleOutcome::Matched,
+        };
+        Ok(ScopedJson::Derived(Value::Bool(result)))
+    }
+}
+
+/// `format_date value [format]`: formats an RFC 3339 time or a Unix
+/// timestamp, like the token's `exp`, with a strftime format
+struct FormatDateHelper;
+
+impl HelperDef for FormatDateHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let format = match h.param(1) {
+            Some(_) => str_param(h, 1, "format_date", "the format")?,
+            None => DEFAULT_DATE_FORMAT,
+        };
+        // formatting with an invalid format would panic
+        if StrftimeItems::new(format).any(|i| matches!(i, Item::Error)) {
+            return Err(RenderError::new(format!("format_date: invalid format '{}'", format)))
+        }
+        let time: DateTime<FixedOffset> = match param(h, 0, "format_date", "the date")? {
+            Value::String(s) => DateTime::parse_from_rfc3339(s)
+                .map_err(|e| RenderError::new(format!("format_date: cannot parse '{}': {}", s, e)))?,
+            Value::Number(n) => match n.as_i64().and_then(|secs| Utc.timestamp_opt(secs, 0).single()) {
+                Some(t) => t.into(),
+                None => return Err(RenderError::new(format!("format_date: {} is not a valid timestamp", n))),
+            },
+            // a missing claim renders as nothing, like it would without the helper
+            Value::Null => return Ok(ScopedJson::Derived(Value::String(String::new()))),
+            v => return Err(RenderError::new(format!("format_date: expected a string or a number, found {}", v))),
+        };
+        Ok(ScopedJson::Derived(Value::String(time.format(format).to_string())))
+    }
+}
+
+/// `json value`: the value as JSON. `<`, `>` and `&` are escaped, so
+/// `{{{json value}}}` can be used inside a `<script>` element.
+struct JsonHelper;
+
+impl HelperDef for JsonHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let json = param(h, 0, "json", "the value")?.to_string()
+            .replace('<', "\\u003c")
+            .replace('>', "\\u003e")
+            .replace('&', "\\u0026");
+        Ok(ScopedJson::Derived(Value::String(json)))
+    }
+}
+
+/// `url_encode value`: the value encoded for use in a query string
+struct UrlEncodeHelper;
+
+impl HelperDef for UrlEncodeHelper {
+    fn call_inner<'reg: 'rc, 'rc>(
+        &self,
+        h: &Helper<'reg, 'rc>,
+        _: &'reg Handlebars<'reg>,
+        _: &'rc Context,
+        _: &mut RenderContext<'reg, 'rc>,
+    ) -> Result<ScopedJson<'reg, 'rc>, RenderError> {
+        let value = match param(h, 0, "url_encode", "the value")? {
+            Value::String(s) => s.clone(),
+            Value::Null => String::new(),
+            v => v.to_string(),
+        };
+        let encoded = url::form_urlencoded::byte_serialize(value.as_bytes()).collect::<String>();
+        Ok(ScopedJson::Derived(Value::String(encoded)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str) -> Result<String, String> {
+        let mut hb = Handlebars::new();
+        register(&mut hb);
+        hb.render_template(template, &serde_json::json!({
+            "access_token": {
+                "realm_access": { "roles": ["user", "wiki_admin"] },
+                "email": "jane@inraweb.local",
+                "exp": 1623045600,
+            },
+            "sites": [{ "name": "Wiki & Docs" }],
+            "updated": "2021-06-07T08:00:00+02:00",
+        })).map_err(|e| e.desc)
+    }
+
+    #[test]
+    fn checks_claims_like_claim_rules() {
+        assert_eq!(render(r#"{{has_claim "realm_access.roles"}} {{has_claim "groups"}}"#).unwrap(), "true false");
+        assert_eq!(render(r#"{{#if (claim_contains "realm_access.roles" "wiki_admin")}}admin{{/if}}"#).unwrap(), "admin");
+        assert_eq!(render(r#"{{claim_contains "realm_access.roles" "admin"}} {{claim_contains "email" "jane"}}"#).unwrap(), "false false");
+        assert_eq!(render(r#"{{claim_matches "email" "@inraweb[.]local$"}} {{claim_matches "realm_access" "."}}"#).unwrap(), "true false");
+        // the root context is used inside blocks, too
+        assert_eq!(render(r#"{{#each sites}}{{claim_contains "realm_access.roles" "user"}}{{/each}}"#).unwrap(), "true");
+        assert!(render(r#"{{claim_matches "email" "("}}"#).unwrap_err().contains("invalid regex"));
+        assert!(render(r#"{{has_claim "realm_access..roles"}}"#).unwrap_err().contains("invalid path"));
+    }
+
+    #[test]
+    fn formats_and_encodes_values() {
+        assert_eq!(render("{{format_date updated}}").unwrap(), "2021-06-07 08:00");
+        assert_eq!(render(r#"{{format_date access_token.exp "%d.%m.%Y %H:%M UTC"}}"#).unwrap(), "07.06.2021 06:00 UTC");
+        assert_eq!(render("{{format_date missing}}").unwrap(), "");
+        assert!(render(r#"{{format_date updated "%Q"}}"#).unwrap_err().contains("invalid format"));
+        assert_eq!(render("{{{json sites}}}").unwrap(), r#"[{"name":"Wiki \u0026 Docs"}]"#);
+        assert_eq!(render("{{url_encode (lookup (lookup sites 0) \"name\")}}").unwrap(), "Wiki+%26+Docs");
+    }
+}
@@ -0,0 +1,86 @@
+// behavior of the dashboard page. Kept out of the page, so the
+// Content-Security-Policy does not need to allow inline scripts.
+
+// replaces site icons that cannot be loaded with a generic one. Icons
+// that failed before this script ran are replaced right away.
+const fallbackIcon = "site-icon.svg";
+document.querySelectorAll(".dashboard-item-link__icon").forEach((icon) => {
+  const useFallback = () => {
+    if (!icon.src.endsWith(fallbackIcon)) {
+      icon.src = fallbackIcon;
+    }
+  };
+  icon.addEventListener("error", useFallback);
+  if (icon.complete && icon.naturalWidth === 0) {
+    useFallback();
+  }
+});
+
+// filters the sites with the server side search, which also knows
+// about descriptions, keywords and categories
+const searchInput = document.getElementById("dashboard-search");
+let searchRequest = 0;
+
+const filterItems = async (query) => {
+  const items = [].slice.call(
+    document.getElementsByClassName("dashboard-item")
+  );
+  const request = ++searchRequest;
+  let matches = null;
+  if (query.trim().length) {
+    const response = await fetch(
+      ".search?q=" + encodeURIComponent(query),
+      { credentials: "same-origin" }
+    );
+    matches = new Set((await response.json()).map((hit) => hit.name));
+  }
+  // a newer search was started while this one was running
+  if (request !== searchRequest) {
+    return;
+  }
+  items.forEach((i) => {
+    // personal links are not known to the server, so filter them here
+    const isMatch = i.dataset.personal
+      ? i.dataset.name.toLowerCase().includes(query.trim().toLowerCase())
+      : matches === null || matches.has(i.dataset.name);
+    i.hidden = !isMatch;
+  });
+};
+
+searchInput.addEventListener("input", (event) =>
+  filterItems(event.target.value)
+);
+
+// on enter, go straight to the best match
+searchInput.form.addEventListener("submit", (event) => {
+  event.preventDefault();
+  window.location =
+    ".search?lucky=true&q=" + encodeURIComponent(searchInput.value);
+});
+
+// dismissed announcements are remembered on the server, per user
+const csrfToken = document.querySelector('meta[name="csrf-token"]');
+document.querySelectorAll(".announcement__dismiss").forEach((button) =>
+  button.addEventListener("click", async () => {
+    const announcement = button.closest(".announcement");
+    const response = await fetch(
+      "/api/v1/announcements/" +
+        encodeURIComponent(announcement.dataset.announcement) +
+        "/dismiss",
+      {
+        method: "POST",
+        credentials: "same-origin",
+        headers: csrfToken ? { "X-CSRF-Token": csrfToken.content } : {},
+      }
+    );
+    if (response.ok) {
+      announcement.remove();
+    }
+  })
+);
+
+const initialQuery = new URLSearchParams(window.location.search).get("q");
+if (initialQuery) {
+  searchInput.value = initialQuery;
+  filterItems(initialQuery);
+}
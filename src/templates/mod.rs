@@ -1,18 +1,274 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr};
 
-const INDEX_HTML_FILENAME: &'static str = "index.html.hbs";
+use handlebars::Handlebars;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// suffix of template files. Templates are registered without it, so
+/// `index.html.hbs` is served as `index.html`
+pub const HBS_SUFFIX: &str = ".hbs";
+
+/// directory of partials, like `partials/head.hbs`, which templates include
+/// with `{{> partials/head}}`
+pub const PARTIALS_DIR: &str = "partials";
+/// directory of layouts, like `layouts/page.hbs`, which pages use as partial
+/// block: `{{#> layouts/page}}...{{/layouts/page}}`
+pub const LAYOUTS_DIR: &str = "layouts";
+
+const INDEX_HTML_FILENAME: &str = "index.html.hbs";
 const INDEX_HTML_CONTENT: &[u8] = std::include_bytes!("index.html.hbs");
 
-const FAVICON_FILENAME: &'static str = "favicon-32x32.png";
+const FAVICON_FILENAME: &str = "favicon-32x32.png";
 const FAVICON_CONTENT: &[u8] = std::include_bytes!("favicon-32x32.png");
 
-const STYLE_CSS_FILENAME: &'static str = "style.css";
+const LAYOUT_PAGE_FILENAME: &str = "layouts/page.hbs";
+const LAYOUT_PAGE_CONTENT: &[u8] = std::include_bytes!("layouts/page.hbs");
+
+const PARTIAL_HEAD_FILENAME: &str = "partials/head.hbs";
+const PARTIAL_HEAD_CONTENT: &[u8] = std::include_bytes!("partials/head.hbs");
+
+const PARTIAL_ANNOUNCEMENTS_FILENAME: &str = "partials/announcements.hbs";
+const PARTIAL_ANNOUNCEMENTS_CONTENT: &[u8] = std::include_bytes!("partials/announcements.hbs");
+
+const PARTIAL_SITE_FILENAME: &str = "partials/site.hbs";
+const PARTIAL_SITE_CONTENT: &[u8] = std::include_bytes!("partials/site.hbs");
+
+/// included by `partials/site.hbs` if the theme has it
+const PARTIAL_SITE_DETAILS_FILENAME: &str = "partials/site-details.hbs";
+
+const STYLE_CSS_FILENAME: &str = "style.css";
 const STYLE_CSS_CONTENT: &[u8] = std::include_bytes!("style.css");
 
-pub fn resources() -> HashMap<&'static str, &'static [u8]> {
+const DASHBOARD_JS_FILENAME: &str = "dashboard.js";
+const DASHBOARD_JS_CONTENT: &[u8] = std::include_bytes!("dashboard.js");
+
+const SITE_ICON_FILENAME: &str = "site-icon.svg";
+const SITE_ICON_CONTENT: &[u8] = std::include_bytes!("site-icon.svg");
+
+const LOCALE_EN_FILENAME: &str = "locales/en.json";
+const LOCALE_EN_CONTENT: &[u8] = std::include_bytes!("locales/en.json");
+
+const LOCALE_DE_FILENAME: &str = "locales/de.json";
+const LOCALE_DE_CONTENT: &[u8] = std::include_bytes!("locales/de.json");
+
+const LOCALE_FR_FILENAME: &str = "locales/fr.json";
+const LOCALE_FR_CONTENT: &[u8] = std::include_bytes!("locales/fr.json");
+
+const THEME_CSS_FILENAME: &str = "theme.css";
+
+const TILES_THEME_CSS_CONTENT: &[u8] = std::include_bytes!("themes/tiles/theme.css");
+
+const LIST_THEME_CSS_CONTENT: &[u8] = std::include_bytes!("themes/list/theme.css");
+const LIST_PARTIAL_SITE_DETAILS_CONTENT: &[u8] = std::include_bytes!("themes/list/partials/site-details.hbs");
+
+const DARK_THEME_CSS_CONTENT: &[u8] = std::include_bytes!("themes/dark/theme.css");
+
+/// The built-in looks of the dashboard. A theme consists of the shared
+/// templates and assets and its own files, which replace shared files of
+/// the same name. Every theme has a `theme.css`.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    /// the sites as tiles in a grid of up to four columns
+    #[default]
+    Tiles,
+    /// the sites as a compact list, one per line with its description
+    List,
+    /// the tiles on a dark background
+    Dark,
+}
+
+impl Theme {
+    pub const NAMES: &'static [&'static str] = &["tiles", "list", "dark"];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Theme::Tiles => "tiles",
+            Theme::List => "list",
+            Theme::Dark => "dark",
+        }
+    }
+
+    /// the files of the theme that are not shared
+    fn files(&self) -> Vec<(&'static str, &'static [u8])> {
+        match self {
+            Theme::Tiles => vec![(THEME_CSS_FILENAME, TILES_THEME_CSS_CONTENT)],
+            Theme::List => vec![
+                (THEME_CSS_FILENAME, LIST_THEME_CSS_CONTENT),
+                (PARTIAL_SITE_DETAILS_FILENAME, LIST_PARTIAL_SITE_DETAILS_CONTENT),
+            ],
+            Theme::Dark => vec![(THEME_CSS_FILENAME, DARK_THEME_CSS_CONTENT)],
+        }
+    }
+}
+
+impl FromStr for Theme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tiles" => Ok(Theme::Tiles),
+            "list" => Ok(Theme::List),
+            "dark" => Ok(Theme::Dark),
+            _ => Err(format!("unknown theme '{}', built-in themes are: {}", s, Theme::NAMES.join(", ")))
+        }
+    }
+}
+
+/// Returns the built-in templates and assets of the theme
+pub fn resources(theme: Theme) -> HashMap<&'static str, &'static [u8]> {
     let mut m = HashMap::new();
     m.insert(INDEX_HTML_FILENAME, INDEX_HTML_CONTENT);
     m.insert(FAVICON_FILENAME, FAVICON_CONTENT);
+    m.insert(LAYOUT_PAGE_FILENAME, LAYOUT_PAGE_CONTENT);
+    m.insert(PARTIAL_HEAD_FILENAME, PARTIAL_HEAD_CONTENT);
+    m.insert(PARTIAL_ANNOUNCEMENTS_FILENAME, PARTIAL_ANNOUNCEMENTS_CONTENT);
+    m.insert(PARTIAL_SITE_FILENAME, PARTIAL_SITE_CONTENT);
     m.insert(STYLE_CSS_FILENAME, STYLE_CSS_CONTENT);
+    m.insert(DASHBOARD_JS_FILENAME, DASHBOARD_JS_CONTENT);
+    m.insert(SITE_ICON_FILENAME, SITE_ICON_CONTENT);
+    m.insert(LOCALE_EN_FILENAME, LOCALE_EN_CONTENT);
+    m.insert(LOCALE_DE_FILENAME, LOCALE_DE_CONTENT);
+    m.insert(LOCALE_FR_FILENAME, LOCALE_FR_CONTENT);
+    m.extend(theme.files());
     m
-}
\ No newline at end of file
+}
+
+/// Returns true if the template with the given name, like `index.html`, can
+/// be requested as a page. Partials and layouts are only used by other
+/// templates.
+pub fn is_page(name: &str) -> bool {
+    ![PARTIALS_DIR, LAYOUTS_DIR].iter()
+        .any(|dir| name.strip_prefix(dir).is_some_and(|rest| rest.starts_with('/')))
+}
+
+/// Returns the content type of a file or rendered template by the extension
+/// of its name, like `text/css` for `style.css`
+pub fn content_type(name: &str) -> &'static str {
+    let name = name.strip_suffix(HBS_SUFFIX).unwrap_or(name);
+    match name.rsplit_once('.') {
+        Some((_, suffix)) => match suffix.to_ascii_lowercase().as_str() {
+            "html" => "text/html; charset=utf-8",
+            "css" => "text/css; charset=utf-8",
+            "js" => "application/javascript; charset=utf-8",
+            "json" => "application/json",
+            "svg" => "image/svg+xml",
+            "png" => "image/png",
+            "ico" => "image/x-icon",
+            "xml" => "application/xml",
+            _ => "text/plain; charset=utf-8",
+        },
+        None => "text/plain; charset=utf-8",
+    }
+}
+
+/// Returns the Content-Security-Policy for pages of the built-in templates.
+/// Nothing but the dashboard's own files may be loaded, except for images
+/// from the origins of the given URLs, which are the sites' icons and the
+/// images of announcements.
+pub fn content_security_policy<'a>(image_urls: impl IntoIterator<Item = &'a str>) -> String {
+    let mut img_src = vec!["'self'".to_string()];
+    for u in image_urls {
+        if let Ok(u) = url::Url::parse(u) {
+            let origin = u.origin();
+            if origin.is_tuple() && !img_src.contains(&origin.ascii_serialization()) {
+                img_src.push(origin.ascii_serialization());
+            }
+        }
+    }
+    format!("default-src 'none'; script-src 'self'; style-src 'self'; img-src {}; connect-src 'self'; form-action 'self'; base-uri 'none'; frame-ancestors 'none'", img_src.join(" "))
+}
+
+/// The favicon at the root of the origin of an http(s) URL, like
+/// `https://wiki.example/favicon.ico` for `https://wiki.example/spaces/it`
+pub fn favicon_url(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return None
+    }
+    url.join("/favicon.ico").ok().map(|u| u.to_string())
+}
+
+/// Registers the built-in templates, partials and layouts of the theme
+pub fn register_builtins(hb: &mut Handlebars, theme: Theme) {
+    for (name, content) in resources(theme) {
+        if let Some(name) = name.strip_suffix(HBS_SUFFIX) {
+            if let Err(e) = hb.register_template_string(name, String::from_utf8_lossy(content)) {
+                panic!("could not parse internal template {}", e)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_pages_are_routable() {
+        assert!(is_page("index.html"));
+        assert!(is_page("partials.html"));
+        assert!(!is_page("partials/head"));
+        assert!(!is_page("layouts/page"));
+    }
+
+    #[test]
+    fn finds_favicons_at_the_origin() {
+        assert_eq!(favicon_url("https://wiki.example"), Some("https://wiki.example/favicon.ico".into()));
+        assert_eq!(favicon_url("https://wiki.example/spaces/it?x=1"), Some("https://wiki.example/favicon.ico".into()));
+        assert_eq!(favicon_url("http://intranet:8080/app/"), Some("http://intranet:8080/favicon.ico".into()));
+        assert_eq!(favicon_url("mailto:a@b.c"), None);
+        assert_eq!(favicon_url("relative/path"), None);
+    }
+
+    #[test]
+    fn allows_images_from_the_given_origins_only() {
+        let csp = content_security_policy(vec!["https://wiki.example/", "https://wiki.example/favicon.ico", "http://intranet:8080/x", "mailto:a@b.c", "relative/path"]);
+        assert!(csp.contains("img-src 'self' https://wiki.example http://intranet:8080;"));
+        assert!(csp.starts_with("default-src 'none'; script-src 'self'; style-src 'self';"));
+    }
+
+    fn render_index(theme: Theme) -> String {
+        let mut hb = Handlebars::new();
+        register_builtins(&mut hb, theme);
+        let catalogs = crate::i18n::Catalogs::load(None, theme, "en").unwrap();
+        hb.register_helper("t", Box::new(crate::i18n::TranslateHelper { catalogs: std::sync::Arc::new(catalogs) }));
+        let site = serde_json::json!({ "name": "Wiki", "url": "https://wiki.example", "display_name": "Wiki", "display_description": "Company wiki" });
+        hb.render("index.html", &serde_json::json!({
+            "locale": "en",
+            "access_token": { "name": "Jane" },
+            "sites": [site],
+            "pinned_sites": [site],
+        })).unwrap()
+    }
+
+    #[test]
+    fn renders_index_with_layout_and_partials() {
+        let page = render_index(Theme::default());
+        assert!(page.starts_with("<!DOCTYPE html>"));
+        assert!(page.contains(r#"<script src="dashboard.js"></script>"#));
+        assert!(page.contains("<title>Dashboard</title>"));
+        assert!(page.contains("Welcome back, Jane!"));
+        assert!(page.contains(r#"id="pinned-Wiki""#));
+        assert!(page.contains(r#"id="Wiki""#));
+        // nothing inline, which the Content-Security-Policy would block
+        assert!(!page.contains("<script>") && !page.contains("onerror="));
+        assert!(page.trim_end().ends_with("</html>"));
+    }
+
+    #[test]
+    fn themes_replace_shared_files() {
+        for name in Theme::NAMES {
+            let theme = name.parse::<Theme>().unwrap();
+            let resources = resources(theme);
+            assert!(resources.contains_key(THEME_CSS_FILENAME), "{} has no theme.css", name);
+            assert!(render_index(theme).contains(r#"href="theme.css""#));
+        }
+        assert_ne!(resources(Theme::Dark)[THEME_CSS_FILENAME], resources(Theme::Tiles)[THEME_CSS_FILENAME]);
+        assert_eq!(resources(Theme::List)[PARTIAL_SITE_FILENAME], resources(Theme::Tiles)[PARTIAL_SITE_FILENAME]);
+        assert!(!resources(Theme::Tiles).contains_key(PARTIAL_SITE_DETAILS_FILENAME));
+        assert!(!render_index(Theme::Tiles).contains("dashboard-item-link__description"));
+        assert!(render_index(Theme::List).contains(r#"<span class="dashboard-item-link__description">Company wiki</span>"#));
+        assert!("sepia".parse::<Theme>().unwrap_err().contains("tiles, list, dark"));
+    }
+}
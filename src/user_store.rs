@@ -0,0 +1,364 @@
+use std::{collections::HashMap, path::PathBuf, sync::RwLock};
+
+use actix_web::{error::BlockingError, web};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::site::Site;
+
+/// How a user wants the dashboard to present their sites. Sites are
+/// referenced by name.
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, Default, PartialEq)]
+#[schemars(rename = "Preferences")]
+pub struct SitePreferences {
+    /// sites to show before all others, in this order
+    #[serde(default)]
+    pub pinned: Vec<String>,
+    /// sites not to show
+    #[serde(default)]
+    pub hidden: Vec<String>,
+    /// order of the sites; sites not listed follow in their default order
+    #[serde(default)]
+    pub order: Vec<String>,
+    /// locale of the dashboard, like `de`. If not set, it is taken from the
+    /// access token or the browser
+    #[serde(default)]
+    pub locale: Option<String>,
+}
+
+/// The visible sites of a user, arranged according to their preferences
+pub struct ArrangedSites<'a> {
+    /// all sites that are neither pinned nor hidden, in the user's order
+    pub sites: Vec<&'a Site>,
+    pub pinned_sites: Vec<&'a Site>,
+    pub hidden_sites: Vec<&'a Site>,
+}
+
+impl SitePreferences {
+    /// Returns preferences that only refer to the given sites, each site
+    /// at most once per list. That way, preferences for sites the user can
+    /// no longer see are ignored.
+    pub fn restricted_to(&self, sites: &[&Site]) -> SitePreferences {
+        let restrict = |names: &[String]| {
+            let mut result: Vec<String> = Vec::new();
+            for name in names {
+                if sites.iter().any(|s| s.name() == name) && !result.contains(name) {
+                    result.push(name.clone())
+                }
+            }
+            result
+        };
+        SitePreferences {
+            pinned: restrict(&self.pinned),
+            hidden: restrict(&self.hidden),
+            order: restrict(&self.order),
+            locale: self.locale.clone(),
+        }
+    }
+
+    pub fn arrange<'a>(&self, sites: Vec<&'a Site>) -> ArrangedSites<'a> {
+        let prefs = self.restricted_to(&sites);
+        let find = |name: &String| *sites.iter().find(|s| s.name() == name).unwrap();
+
+        let mut ordered: Vec<&Site> = prefs.order.iter().map(find).collect();
+        ordered.extend(sites.iter().filter(|s| !prefs.order.iter().any(|n| n == s.name())));
+
+        let is_hidden = |site: &&Site| prefs.hidden.iter().any(|n| n == site.name());
+        let is_pinned = |site: &&Site| prefs.pinned.iter().any(|n| n == site.name());
+        ArrangedSites {
+            pinned_sites: prefs.pinned.iter()
+                .map(find)
+                .filter(|s| !is_hidden(s))
+                .collect(),
+            hidden_sites: ordered.iter().copied().filter(is_hidden).collect(),
+            sites: ordered.into_iter().filter(|s| !is_hidden(s) && !is_pinned(s)).collect(),
+        }
+    }
+}
+
+/// URL schemes personal links may use. Anything else, `javascript:` in
+/// particular, could run code in the dashboard when clicked.
+const LINK_SCHEMES: &[&str] = &["http", "https"];
+/// maximum number of personal links per user
+const MAX_LINKS: usize = 100;
+/// maximum length of each text field of a personal link
+const MAX_LINK_FIELD_LEN: usize = 2048;
+
+/// A bookmark a user added to their own dashboard
+#[derive(Serialize, Deserialize, JsonSchema, Clone, Debug, PartialEq)]
+pub struct PersonalLink {
+    pub name: String,
+    #[schemars(url)]
+    pub url: String,
+    /// URL of an icon to display
+    #[schemars(url)]
+    pub icon: Option<String>,
+    pub category: Option<String>,
+}
+
+impl PersonalLink {
+    pub fn validate(&self) -> Vec<String> {
+        let mut errors = Vec::new();
+        if self.name.trim().is_empty() {
+            errors.push("name must not be empty".to_string())
+        }
+        let fields = [
+            ("name", Some(&self.name)),
+            ("url", Some(&self.url)),
+            ("icon", self.icon.as_ref()),
+            ("category", self.category.as_ref()),
+        ];
+        for (field, value) in fields.iter() {
+            if value.is_some_and(|v| v.len() > MAX_LINK_FIELD_LEN) {
+                errors.push(format!("{} must not be longer than {} bytes", field, MAX_LINK_FIELD_LEN))
+            }
+        }
+        for (field, value) in [("url", Some(&self.url)), ("icon", self.icon.as_ref())].iter() {
+            if let Some(value) = value {
+                match url::Url::parse(value) {
+                    Ok(u) if LINK_SCHEMES.contains(&u.scheme()) => (),
+                    Ok(u) => errors.push(format!("{} has scheme '{}', but only {} are allowed", field, u.scheme(), LINK_SCHEMES.join(", "))),
+                    Err(e) => errors.push(format!("cannot parse {} '{}': {}", field, value, e)),
+                }
+            }
+        }
+        errors
+    }
+}
+
+/// validates a complete list of personal links, as it would be stored
+pub fn validate_links(links: &[PersonalLink]) -> Result<(), String> {
+    if links.len() > MAX_LINKS {
+        return Err(format!("at most {} personal links are allowed", MAX_LINKS))
+    }
+    let errors = links.iter()
+        .enumerate()
+        .flat_map(|(i, l)| l.validate()
+            .into_iter()
+            .map(move |e| format!("link #{}: {}", i + 1, e))
+        )
+        .collect::<Vec<_>>();
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+/// everything stored for a single user
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+struct UserData {
+    #[serde(default)]
+    preferences: SitePreferences,
+    #[serde(default)]
+    links: Vec<PersonalLink>,
+    /// ids of the announcements the user does not want to see again
+    #[serde(default)]
+    dismissed_announcements: Vec<String>,
+}
+
+/// Holds data of individual users, keyed by the `sub` claim of their
+/// access tokens. The data is persisted to a JSON file, if one is
+/// configured, otherwise it is lost on restart.
+pub struct UserStore {
+    store_path: Option<PathBuf>,
+    users: RwLock<HashMap<String, UserData>>,
+    /// serializes updates, so that the file is written in the order the
+    /// changes were made. Held while the file is written, which is why it is
+    /// an async mutex
+    writer: tokio::sync::Mutex<()>,
+}
+
+impl UserStore {
+    /// Creates the store, loading previously stored data from `store_path`
+    /// if that file exists
+    pub fn open(store_path: Option<PathBuf>) -> Result<UserStore, String> {
+        let users = match &store_path {
+            Some(p) if p.exists() => {
+                let content = std::fs::read_to_string(p)
+                    .map_err(|e| format!("cannot read user store {}: {}", p.to_string_lossy(), e))?;
+                serde_json::from_str(&content)
+                    .map_err(|e| format!("cannot parse user store {}: {}", p.to_string_lossy(), e))?
+            },
+            _ => HashMap::new()
+        };
+        Ok(UserStore {
+            store_path,
+            users: RwLock::new(users),
+            writer: tokio::sync::Mutex::new(()),
+        })
+    }
+
+    pub fn preferences(&self, sub: &str) -> SitePreferences {
+        self.users.read().unwrap()
+            .get(sub)
+            .map(|u| u.preferences.clone())
+            .unwrap_or_default()
+    }
+
+    pub async fn set_preferences(&self, sub: &str, preferences: SitePreferences) -> Result<(), String> {
+        self.update(sub, |u| u.preferences = preferences).await
+    }
+
+    pub fn links(&self, sub: &str) -> Vec<PersonalLink> {
+        self.users.read().unwrap()
+            .get(sub)
+            .map(|u| u.links.clone())
+            .unwrap_or_default()
+    }
+
+    /// replaces the personal links of the user, after validating them
+    pub async fn set_links(&self, sub: &str, links: Vec<PersonalLink>) -> Result<(), String> {
+        validate_links(&links)?;
+        self.update(sub, |u| u.links = links).await
+    }
+
+    pub fn dismissed_announcements(&self, sub: &str) -> Vec<String> {
+        self.users.read().unwrap()
+            .get(sub)
+            .map(|u| u.dismissed_announcements.clone())
+            .unwrap_or_default()
+    }
+
+    /// Remembers that the user dismissed the announcement. Ids not in
+    /// `existing` are forgotten, so the list does not grow forever.
+    pub async fn dismiss_announcement(&self, sub: &str, id: &str, existing: &[&str]) -> Result<(), String> {
+        self.update(sub, |u| {
+            u.dismissed_announcements.retain(|d| existing.contains(&d.as_str()) && d != id);
+            u.dismissed_announcements.push(id.to_string());
+        }).await
+    }
+
+    /// Changes the data of one user and persists the store. The lock on the
+    /// users is only held while the user's entry is changed, the file is
+    /// written from a snapshot on the blocking thread pool. If writing
+    /// fails, the user's previous data is restored.
+    async fn update<F: FnOnce(&mut UserData)>(&self, sub: &str, f: F) -> Result<(), String> {
+        let _writer = self.writer.lock().await;
+        let (previous, snapshot) = {
+            let mut users = self.users.write().unwrap();
+            let previous = users.get(sub).cloned();
+            f(users.entry(sub.to_string()).or_default());
+            let snapshot = self.store_path.as_ref().map(|_| users.clone());
+            (previous, snapshot)
+        };
+        let (path, snapshot) = match (self.store_path.clone(), snapshot) {
+            (Some(path), Some(snapshot)) => (path, snapshot),
+            _ => return Ok(()),
+        };
+        let result = web::block(move || crate::store::persist_json(&path, &snapshot, "user store"))
+            .await
+            .map_err(|e| match e {
+                BlockingError::Error(e) => e,
+                BlockingError::Canceled => "writing the user store was canceled".to_string(),
+            });
+        if result.is_err() {
+            let mut users = self.users.write().unwrap();
+            match previous {
+                Some(data) => users.insert(sub.to_string(), data),
+                None => users.remove(sub),
+            };
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sites(names: &[&str]) -> Vec<Site> {
+        names.iter()
+            .map(|n| serde_json::from_value(serde_json::json!({
+                "name": n,
+                "url": format!("https://{}.example", n),
+                "claim_rules": [],
+            })).unwrap())
+            .collect()
+    }
+
+    fn names(sites: &[&Site]) -> Vec<String> {
+        sites.iter().map(|s| s.name().to_string()).collect()
+    }
+
+    fn prefs(pinned: &[&str], hidden: &[&str], order: &[&str]) -> SitePreferences {
+        let owned = |l: &[&str]| l.iter().map(|s| s.to_string()).collect();
+        SitePreferences { pinned: owned(pinned), hidden: owned(hidden), order: owned(order), locale: None }
+    }
+
+    #[test]
+    fn arranges_sites_by_preferences() {
+        let all = sites(&["a", "b", "c", "d"]);
+        let arranged = prefs(&["d"], &["b"], &["c", "b"]).arrange(all.iter().collect());
+        assert_eq!(names(&arranged.sites), vec!["c", "a"]);
+        assert_eq!(names(&arranged.pinned_sites), vec!["d"]);
+        assert_eq!(names(&arranged.hidden_sites), vec!["b"]);
+    }
+
+    fn link(url: &str) -> PersonalLink {
+        PersonalLink { name: "link".into(), url: url.into(), icon: None, category: None }
+    }
+
+    #[test]
+    fn rejects_links_with_unsafe_schemes() {
+        assert!(validate_links(&[link("https://jira.example/board/1")]).is_ok());
+        let err = validate_links(&[link("javascript:alert(1)")]).unwrap_err();
+        assert_eq!(err, "link #1: url has scheme 'javascript', but only http, https are allowed");
+        let icon = PersonalLink { icon: Some("data:image/png;base64,AAAA".into()), ..link("http://a.example") };
+        assert!(validate_links(&[icon]).is_err());
+        assert!(validate_links(&[link("no url")]).is_err());
+    }
+
+    #[test]
+    fn ignores_sites_that_are_not_visible() {
+        let all = sites(&["a", "b"]);
+        let p = prefs(&["x", "a", "a"], &["y"], &["z", "b"]);
+        assert_eq!(p.restricted_to(&all.iter().collect::<Vec<_>>()), prefs(&["a"], &[], &["b"]));
+        let arranged = p.arrange(all.iter().collect());
+        assert_eq!(names(&arranged.sites), vec!["b"]);
+        assert_eq!(names(&arranged.pinned_sites), vec!["a"]);
+        assert!(arranged.hidden_sites.is_empty());
+    }
+
+    #[test]
+    fn forgets_dismissed_announcements_that_no_longer_exist() {
+        actix_web::rt::System::new("test").block_on(async move {
+            let store = UserStore::open(None).unwrap();
+            store.dismiss_announcement("u", "a", &["a", "b"]).await.unwrap();
+            store.dismiss_announcement("u", "b", &["a", "b"]).await.unwrap();
+            store.dismiss_announcement("u", "b", &["a", "b"]).await.unwrap();
+            assert_eq!(store.dismissed_announcements("u"), vec!["a", "b"]);
+            store.dismiss_announcement("u", "c", &["b", "c"]).await.unwrap();
+            assert_eq!(store.dismissed_announcements("u"), vec!["b", "c"]);
+            assert!(store.dismissed_announcements("other").is_empty());
+        });
+    }
+
+    #[test]
+    fn persists_changes_and_keeps_them_on_reopen() {
+        let path = std::env::temp_dir().join(format!("resweb-user-store-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let store_path = path.clone();
+        actix_web::rt::System::new("test").block_on(async move {
+            let store = UserStore::open(Some(store_path)).unwrap();
+            store.set_links("u", vec![link("https://a.example")]).await.unwrap();
+            store.set_preferences("v", prefs(&["a"], &[], &[])).await.unwrap();
+            assert_eq!(store.links("u"), vec![link("https://a.example")]);
+        });
+        let reopened = UserStore::open(Some(path.clone())).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(reopened.links("u"), vec![link("https://a.example")]);
+        assert_eq!(reopened.preferences("v"), prefs(&["a"], &[], &[]));
+    }
+
+    #[test]
+    fn keeps_the_previous_data_if_the_store_cannot_be_written() {
+        // the parent of the store file does not exist
+        let path = std::env::temp_dir().join(format!("resweb-missing-{}", std::process::id())).join("users.json");
+        actix_web::rt::System::new("test").block_on(async move {
+            let store = UserStore::open(Some(path)).unwrap();
+            assert!(store.set_links("u", vec![link("https://a.example")]).await.is_err());
+            assert!(store.links("u").is_empty());
+        });
+    }
+}